@@ -1,30 +1,94 @@
-use std::{
-    cell::{OnceCell, RefCell},
-    collections::HashMap,
-};
+use std::{cell::UnsafeCell, collections::HashMap, sync::OnceLock, thread::ThreadId};
 
-use typed_arena::Arena;
+use crate::{error::LoadError, ResumeKey};
 
-use crate::ResumeKey;
+/// Freed maps leave a `None` behind, so that the indices held by DM stay valid.
+pub static PARSED_MAPS_ARENABASED: MainThreadCell<Vec<Option<ArenaMap>>> =
+    MainThreadCell::new(vec![]);
 
-pub static mut ARENA: OnceCell<Arena<String>> = OnceCell::new();
-pub static mut PARSED_MAPS_ARENABASED: RefCell<Vec<ArenaMap>> = RefCell::new(vec![]);
+/// Global state that must only ever be touched from the BYOND main thread.
+///
+/// We don't lock anything, BYOND only calls us from one thread. Instead, the first thread to
+/// touch the cell claims it, and access from any other thread panics instead of silently
+/// corrupting the parsed maps.
+pub struct MainThreadCell<T> {
+    owner: OnceLock<ThreadId>,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: Every access is checked against the owning thread in [`MainThreadCell::get_mut`]
+unsafe impl<T> Sync for MainThreadCell<T> {}
+
+impl<T> MainThreadCell<T> {
+    pub const fn new(value: T) -> Self {
+        MainThreadCell {
+            owner: OnceLock::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Gives you a mutable reference to the contents, panicking if this isn't the owning thread.
+    ///
+    /// # Safety
+    ///
+    /// The returned reference must not outlive the call that produced it, and you must not
+    /// call this again while it's alive.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get_mut(&self) -> &mut T {
+        let current = std::thread::current().id();
+        let owner = *self.owner.get_or_init(|| current);
+        assert!(
+            owner == current,
+            "bapi-dmm global state accessed from {current:?}, but it belongs to the BYOND main thread {owner:?}"
+        );
+        &mut *self.value.get()
+    }
+}
 
-/// OnceCell helper: Gives you a mutable reference to the string arena.
+/// Looks up a parsed map by its `_internal_index`, erroring if it doesn't exist or was freed.
 ///
 /// # Safety
 ///
-/// This must only be called from the main thread.
-pub unsafe fn get_arena<'s>() -> &'s mut Arena<String> {
-    if let Some(x) = ARENA.get_mut() {
-        x
-    } else {
-        let _ = ARENA.set(Arena::new());
-        ARENA.get_mut().unwrap()
-    }
+/// Same as [`MainThreadCell::get_mut`].
+pub unsafe fn get_parsed_map(id: usize) -> eyre::Result<&'static mut ArenaMap<'static>> {
+    PARSED_MAPS_ARENABASED
+        .get_mut()
+        .get_mut(id)
+        .and_then(Option::as_mut)
+        .ok_or_else(|| LoadError::BadInternalIndex(id).into())
 }
 
 pub struct ArenaMap<'s> {
     pub parsed_data: (dmm_lite::MapInfo, dmm_lite::MapData<'s>),
     pub command_buffers: HashMap<ResumeKey, crate::load::command_buffer::CommandBuffer<'s>>,
+    /// The load that's running commands right now. Its buffer is taken out of `command_buffers`
+    /// while it runs, so that the DM procs it calls can use the arena. This is what stops them
+    /// from freeing the map out from under it, or loading it again, see [`LoadError::Busy`].
+    pub working: Option<ResumeKey>,
+    /// The text of the map file, borrowed from `_source`.
+    pub text: &'s str,
+    /// The map file that everything above borrows from.
+    /// This MUST be declared last, so it is dropped last.
+    _source: Box<str>,
+}
+
+impl ArenaMap<'static> {
+    /// Parses `source`, taking ownership of it so that it lives exactly as long as the parsed data.
+    pub fn new(
+        name: String,
+        source: String,
+        options: dmm_lite::ParseOptions,
+    ) -> Result<Self, dmm_lite::LocatedError> {
+        let source = source.into_boxed_str();
+        // SAFETY: The box's contents never move, and they are dropped after everything borrowing them.
+        let text: &'static str = unsafe { &*(source.as_ref() as *const str) };
+        let parsed_data = dmm_lite::parse_map_with_options(name, text, options)?;
+        Ok(ArenaMap {
+            parsed_data,
+            command_buffers: HashMap::new(),
+            working: None,
+            text,
+            _source: source,
+        })
+    }
 }
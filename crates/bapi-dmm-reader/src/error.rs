@@ -0,0 +1,117 @@
+//! Failures that DM might want to react to, rather than only log.
+//! Everything else is still a plain eyre report. These are carried inside one, and found again
+//! with [`error_code`] when a bind fails, so they can be written to `last_error`.
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum LoadError {
+    /// The datum's `_internal_index` doesn't point at parsed data, it was never parsed or was freed
+    #[error("Bad internal index {0:#?}")]
+    BadInternalIndex(usize),
+    /// No command buffer has this resume key, it already finished or never existed
+    #[error("Bad resume key {0:#?}")]
+    BadResumeKey(usize),
+    #[error("Attempted to get out-of-range tile at coords {0:#?}")]
+    OutOfRange((usize, usize, usize)),
+    #[error("Unable to free parsed map {0:#?} while it is still loading")]
+    StillLoading(usize),
+    #[error("Unable to find {0:#?} on disk")]
+    FileNotFound(String),
+    #[error("Error parsing {path:#?}: {details}")]
+    Parse { path: String, details: String },
+    #[error("Unable to load onto z-level {0:#?}, it doesn't exist")]
+    NoSuchZLevel(usize),
+    #[error("Unknown load_options.{var} {value:#?}")]
+    BadOption { var: &'static str, value: String },
+    /// world.maxx, maxy or maxz is 0, and the world wasn't expanded to fit the map
+    #[error("The world is {0:?}, so there are no turfs to load onto. Set world.maxx, maxy and maxz first, or load without crop_map so that the world is expanded to fit")]
+    EmptyWorld((usize, usize, usize)),
+    /// Something called from inside one of the map's loads tried to load it, or work that load
+    #[error("Parsed map {0:#?} is in the middle of placing commands, it can't be loaded or worked from a proc the load calls")]
+    Busy(usize),
+    /// The map is bigger than load_options.max_tiles or max_atoms allow
+    #[error("Map has {count} {what}, more than the maximum of {max}")]
+    OverBudget {
+        what: &'static str,
+        count: usize,
+        max: usize,
+    },
+}
+
+impl LoadError {
+    /// Mirrors the `BAPI_ERROR_*` defines.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LoadError::BadInternalIndex(_) => "bad_internal_index",
+            LoadError::BadResumeKey(_) => "bad_resume_key",
+            LoadError::OutOfRange(_) => "out_of_range",
+            LoadError::StillLoading(_) => "still_loading",
+            LoadError::FileNotFound(_) => "file_not_found",
+            LoadError::Parse { .. } => "parse",
+            LoadError::NoSuchZLevel(_) => "no_such_z_level",
+            LoadError::BadOption { .. } => "bad_option",
+            LoadError::EmptyWorld(_) => "empty_world",
+            LoadError::OverBudget { .. } => "over_budget",
+            LoadError::Busy(_) => "busy",
+        }
+    }
+}
+
+/// The `BAPI_ERROR_*` code for `error`: the [`LoadError`] behind it if there is one,
+/// otherwise whether it came from BYOND or from us.
+pub fn error_code(error: &eyre::Report) -> &'static str {
+    if let Some(load_error) = error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<LoadError>())
+    {
+        return load_error.code();
+    }
+    if error
+        .chain()
+        .any(|cause| cause.downcast_ref::<byondapi::Error>().is_some())
+    {
+        return "byond";
+    }
+    "other"
+}
+
+/// Errors that will happen again on every command, so there's no point carrying on with the load.
+pub fn is_fatal(error: &eyre::Report) -> bool {
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<byondapi::Error>(),
+            Some(
+                byondapi::Error::InvalidProc(_) | byondapi::Error::NotAvailableForThisByondVersion
+            )
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::WrapErr;
+
+    use super::*;
+
+    #[test]
+    fn test_error_code() {
+        let error: eyre::Report = LoadError::OutOfRange((1, 2, 3)).into();
+        assert_eq!(error_code(&error), "out_of_range");
+
+        // Still found under context
+        let error = Err::<(), _>(LoadError::BadResumeKey(4))
+            .wrap_err("Failed to work")
+            .unwrap_err();
+        assert_eq!(error_code(&error), "bad_resume_key");
+
+        assert_eq!(error_code(&eyre::eyre!("Something else")), "other");
+    }
+
+    #[test]
+    fn test_is_fatal() {
+        let missing_proc = Err::<(), _>(byondapi::Error::InvalidProc(c"_bapi_missing".into()))
+            .wrap_err("Failed to create turf")
+            .unwrap_err();
+        assert!(is_fatal(&missing_proc));
+        assert!(!is_fatal(&LoadError::OutOfRange((1, 2, 3)).into()));
+    }
+}
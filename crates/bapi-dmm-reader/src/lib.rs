@@ -1,24 +1,112 @@
 use byondapi::prelude::*;
 
 use crate::{
-    _compat::setup_panic_handler,
-    arena::{ARENA, PARSED_MAPS_ARENABASED},
+    _compat::setup_panic_handler, arena::PARSED_MAPS_ARENABASED, error::LoadError,
+    load::helpers::ParsedMapTranslationLayer,
 };
 
 type ResumeKey = usize;
 
 pub mod _compat;
 pub mod arena;
+pub mod error;
 pub mod load;
 pub mod parse;
 pub mod random_map;
 
 #[byondapi::bind]
-/// This function empties out the cached map data
+/// This function empties out the cached map data.
+/// Errors without clearing anything if called from a proc one of the loads calls.
 pub fn _bapidmm_clear_map_data() {
     setup_panic_handler();
-    // This must be dropped FIRST or there will be a bunch of invalid refs
-    let _ = unsafe { PARSED_MAPS_ARENABASED.replace(vec![]) };
-    let _ = unsafe { ARENA.take() };
+    let maps = unsafe { PARSED_MAPS_ARENABASED.get_mut() };
+    // Its command buffer borrows the map's text, so it would be left pointing at nothing
+    if let Some(id) = maps
+        .iter()
+        .position(|map| map.as_ref().is_some_and(|map| map.working.is_some()))
+    {
+        return Err(LoadError::Busy(id).into());
+    }
+    let _ = std::mem::take(maps);
     Ok(ByondValue::null())
 }
+
+#[byondapi::bind]
+/// Lists every load that hasn't finished, across all parsed maps, for finding stuck loads.
+/// Each entry is `list("resume_key" = ..., "internal_index" = ..., "remaining" = ..., "paused" = ...)`,
+/// where remaining is how many commands are left to run.
+pub fn _bapidmm_list_command_buffers() {
+    setup_panic_handler();
+    let mut entries = vec![];
+    for (internal_index, map) in unsafe { PARSED_MAPS_ARENABASED.get_mut() }
+        .iter_mut()
+        .enumerate()
+    {
+        let Some(map) = map else {
+            continue;
+        };
+        let mut resume_keys: Vec<usize> = map.command_buffers.keys().copied().collect();
+        resume_keys.sort_unstable();
+        for resume_key in resume_keys {
+            let Some(buffer) = map.command_buffers.get_mut(&resume_key) else {
+                continue;
+            };
+            let remaining = buffer.remaining();
+            let mut entry = ByondValue::new_list()?;
+            entry.write_list_index(
+                ByondValue::new_str("resume_key")?,
+                ByondValue::new_num(resume_key as f32),
+            )?;
+            entry.write_list_index(
+                ByondValue::new_str("internal_index")?,
+                ByondValue::new_num(internal_index as f32),
+            )?;
+            entry.write_list_index(
+                ByondValue::new_str("remaining")?,
+                ByondValue::new_num(remaining as f32),
+            )?;
+            entry.write_list_index(
+                ByondValue::new_str("paused")?,
+                ByondValue::new_num(buffer.paused as u8 as f32),
+            )?;
+            entries.push(entry);
+        }
+    }
+    let list = ByondValue::new_list()?;
+    list.write_list(&entries)?;
+    Ok(list)
+}
+
+#[byondapi::bind]
+/// Frees the parsed data, caches, and file contents behind a single parsed map.
+/// Every copy of the datum shares this data, so they all become unusable.
+/// Errors if the map is still being loaded.
+pub fn _bapidmm_free_parsed_map(parsed_map: ByondValue) {
+    setup_panic_handler();
+    let mut parsed_map = ParsedMapTranslationLayer { parsed_map };
+    let result = free_parsed_map(&mut parsed_map);
+    parsed_map.record_error(result)
+}
+
+fn free_parsed_map(parsed_map: &mut ParsedMapTranslationLayer) -> eyre::Result<ByondValue> {
+    let id = parsed_map.get_internal_index()? as usize;
+
+    let slot = unsafe { PARSED_MAPS_ARENABASED.get_mut() }
+        .get_mut(id)
+        .ok_or(LoadError::BadInternalIndex(id))?;
+
+    // Drained command buffers remove themselves, so anything left over is an in-progress load.
+    // The one running commands is out of the list while it runs.
+    if slot
+        .as_ref()
+        .is_some_and(|map| !map.command_buffers.is_empty() || map.working.is_some())
+    {
+        return Err(LoadError::StillLoading(id).into());
+    }
+
+    // Don't remove it from the list, the indices of every other map would shift
+    *slot = None;
+    parsed_map.set_internal_index(-1.)?;
+
+    Ok(ByondValue::new_num(1.))
+}
@@ -1,5 +1,7 @@
 //! All things to do with loading maps into the game (as opposed to parsing them)
+//! Coordinates are always in BYOND's 1-based space, both internally and in anything we report.
 pub mod command_buffer;
 pub mod helpers;
 pub mod load_buffer;
+pub mod options;
 pub mod smart_byond_value;
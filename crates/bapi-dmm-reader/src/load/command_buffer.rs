@@ -1,34 +1,43 @@
 //! Command buffer which is generated by [`crate::load::load_buffer`]
 //! Allows working piecemeal to actually place down a map
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    panic::AssertUnwindSafe,
+    path::Path,
     rc::Rc,
+    time::{Duration, Instant},
 };
 
 use byondapi::{prelude::*, value::ByondValue};
-use dmm_lite::prefabs::{Literal, Prefab};
-use eyre::eyre;
+use dmm_lite::prefabs::{is_bare_list_key, Literal, Prefab};
 use tracy_full::zone;
 
 use crate::{
     _compat::setup_panic_handler,
+    arena::get_parsed_map,
+    error::{is_fatal, LoadError},
     load::{
         helpers::{
-            ParsedMapTranslationLayer, _bapi_add_turf_to_area, _bapi_apply_preloader,
+            _bapi_add_turf_to_area, _bapi_apply_preloader, _bapi_call_z_loaded_proc,
             _bapi_create_or_get_area, _bapi_create_turf, _bapi_handle_area_contain,
-            _bapi_helper_get_world_bounds, _bapi_helper_text2file, _bapi_helper_text2path,
-            _bapi_helper_tick_check, _bapi_setup_preloader,
+            _bapi_helper_get_content_types, _bapi_helper_get_tile_extent,
+            _bapi_helper_get_world_bounds, _bapi_helper_rand_seed, _bapi_helper_text2file,
+            _bapi_helper_text2path, _bapi_helper_tick_check, _bapi_helper_turf_has_default_area,
+            _bapi_setup_preloader, ParsedMapTranslationLayer, Severity, WarningSink,
         },
+        load_buffer::{CommandStream, Validation, Validator},
+        options::{AreaPolicy, FallbackPolicy, LoadOptions},
         smart_byond_value::{SharedByondValue, SmartByondValue},
     },
-    PARSED_MAPS_ARENABASED,
 };
 
 /// Used by [`CommandBuffer`] to know what it needs to do in a big list.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Command<'s> {
+    /// One per run of tiles in a row that share an area, so that the area is only looked up once
     CreateArea {
-        loc: (usize, usize, usize),
+        locs: Vec<(usize, usize, usize)>,
         prefab: &'s Prefab<'s>,
         new_z: bool,
     },
@@ -41,38 +50,174 @@ pub enum Command<'s> {
     CreateAtom {
         loc: (usize, usize, usize),
         prefab: &'s Prefab<'s>,
+        /// The prefab's `// @note`, if it has one and this type is in [`LoadOptions::note_vars`]
+        note: Option<&'s str>,
     },
 }
 
+impl<'s> Command<'s> {
+    /// The exact coordinate this command places at, or where it starts for [`Command::CreateArea`].
+    pub fn loc(&self) -> (usize, usize, usize) {
+        match self {
+            Command::CreateArea { locs, .. } => locs[0],
+            Command::CreateTurf { loc, .. } | Command::CreateAtom { loc, .. } => *loc,
+        }
+    }
+
+    /// Whether this command places anything at `loc`.
+    pub fn targets(&self, loc: (usize, usize, usize)) -> bool {
+        self.locs().contains(&loc)
+    }
+
+    /// Every tile this command places anything on.
+    pub fn locs(&self) -> &[(usize, usize, usize)] {
+        match self {
+            Command::CreateArea { locs, .. } => locs,
+            Command::CreateTurf { loc, .. } | Command::CreateAtom { loc, .. } => {
+                std::slice::from_ref(loc)
+            }
+        }
+    }
+
+    pub fn prefab(&self) -> &'s Prefab<'s> {
+        match self {
+            Command::CreateArea { prefab, .. }
+            | Command::CreateTurf { prefab, .. }
+            | Command::CreateAtom { prefab, .. } => prefab,
+        }
+    }
+}
+
+/// One line of [`dump_commands`]: where, what kind of command, and the path it places.
+impl fmt::Display for Command<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (x, y, z) = self.loc();
+        let path = self.prefab().0;
+        match self {
+            Command::CreateArea { locs, new_z, .. } => {
+                write!(f, "({x}, {y}, {z}) area {path} over {} tiles", locs.len())?;
+                if *new_z {
+                    write!(f, " new_z")?;
+                }
+            }
+            Command::CreateTurf {
+                no_changeturf,
+                place_on_top,
+                ..
+            } => {
+                write!(f, "({x}, {y}, {z}) turf {path}")?;
+                if *no_changeturf {
+                    write!(f, " no_changeturf")?;
+                }
+                if *place_on_top {
+                    write!(f, " place_on_top")?;
+                }
+            }
+            Command::CreateAtom { note, .. } => {
+                write!(f, "({x}, {y}, {z}) atom {path}")?;
+                if let Some(note) = note {
+                    write!(f, " note {note:?}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// For debugging: Every command the buffer has left to run, one per line in the order they'll run.
+/// This generates all of them, the same as the first call to [`CommandBuffer::remaining`] does.
+#[cfg(feature = "dump")]
+pub fn dump_commands(buffer: &CommandBuffer) -> String {
+    let mut commands = buffer.commands.clone();
+    std::iter::from_fn(|| commands.next_command(&buffer.options))
+        .map(|command| format!("{command}\n"))
+        .collect()
+}
+
 /// Safety: You're fucked honestly
 /// This is extremely dependent on internal BYOND data structures that ~probably~ won't ever change
 /// You'll find out it did when byond starts throwing "BAD REF!" internal debug messages (or segfaults)
-unsafe fn extremely_unsafe_resolve_coord(
-    coord: (usize, usize, usize),
-    world_size: (usize, usize, usize),
-) -> eyre::Result<ByondValue> {
+/// `index` must come from [`turf_ref_index`] with the world bounds BYOND has right now, use [`resolve_turf_ref`]
+unsafe fn extremely_unsafe_resolve_coord(index: u32) -> ByondValue {
     zone!("extremely_unsafe_resolve_coord");
-    let (max_x, max_y, max_z) = world_size;
-    let (x, y, z) = (coord.0 - 1, coord.1 - 1, coord.2 - 1);
-    if (0..max_x).contains(&x) && (0..max_y).contains(&y) && (0..max_z).contains(&z) {
-        Ok(ByondValue::new_ref(
-            ValueType::Turf,
-            (x + y * max_x + z * max_x * max_y) as u32,
-        ))
+    ByondValue::new_ref(ValueType::Turf, index)
+}
+
+/// A reference to a turf that was inside the world bounds it was resolved against.
+/// Only [`resolve_turf_ref`] (and the turf cache) make these, so nothing else builds turf refs by hand.
+#[derive(Debug, Clone, Copy)]
+pub struct TurfRef(ByondValue);
+
+impl TurfRef {
+    pub fn value(self) -> ByondValue {
+        self.0
+    }
+}
+
+/// The safe way into [`extremely_unsafe_resolve_coord`].
+/// `world_bounds` has to be what BYOND has right now, [`CachedTurfs::check_invalidate`] makes sure of that
+/// once per batch of commands, so the cache and the load buffer are the only callers.
+pub fn resolve_turf_ref(
+    coord: (usize, usize, usize),
+    world_bounds: (usize, usize, usize),
+) -> eyre::Result<TurfRef> {
+    let index = turf_ref_index(coord, world_bounds).ok_or(LoadError::OutOfRange(coord))?;
+    Ok(TurfRef(unsafe { extremely_unsafe_resolve_coord(index) }))
+}
+
+/// Turfs are stored in one big array, x first, then y, then z.
+/// Like every coordinate we report, `coord` is 1-based, this is the only place it's made 0-based.
+pub fn turf_ref_index(
+    coord: (usize, usize, usize),
+    (max_x, max_y, max_z): (usize, usize, usize),
+) -> Option<u32> {
+    let (x, y, z) = (
+        coord.0.checked_sub(1)?,
+        coord.1.checked_sub(1)?,
+        coord.2.checked_sub(1)?,
+    );
+    if x < max_x && y < max_y && z < max_z {
+        Some((x + y * max_x + z * max_x * max_y) as u32)
     } else {
-        Err(eyre!(
-            "Attempted to get out-of-range tile at coords {coord:#?}"
-        ))
+        None
     }
 }
 
+/// How [`CachedTurfs`] turns a coordinate into a turf, given the world bounds.
+/// Only tests use anything other than [`resolve_turf_ref`], so they don't need BYOND.
+pub type ResolveTurf = fn((usize, usize, usize), (usize, usize, usize)) -> eyre::Result<TurfRef>;
+
 /// This thing allows us to cache turfs ahead of time in a safe way,
 /// respecting when turf references become invalidated (world.max[x|y|z] changes)
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct CachedTurfs {
     /// Invalidates cache if this changes
     pub world_bounds: (usize, usize, usize),
     pub cached_turfs: HashMap<(usize, usize, usize), SharedByondValue>,
+    pub resolve_turf: ResolveTurf,
+    pub stats: TurfCacheStats,
+}
+
+/// How well [`CachedTurfs`] did over a load, written to the parsed map's `turf_cache_stats` when it finishes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TurfCacheStats {
+    /// Turfs a command found already cached
+    pub hits: usize,
+    /// Turfs a command had to look up, because they weren't cached or the cache was invalidated
+    pub misses: usize,
+    /// Turfs looked up ahead of time with [`CachedTurfs::cache`]
+    pub prewarmed: usize,
+}
+
+impl Default for CachedTurfs {
+    fn default() -> Self {
+        CachedTurfs {
+            world_bounds: Default::default(),
+            cached_turfs: Default::default(),
+            resolve_turf: resolve_turf_ref,
+            stats: Default::default(),
+        }
+    }
 }
 
 impl CachedTurfs {
@@ -91,8 +236,9 @@ impl CachedTurfs {
     /// Caches a turf
     pub fn cache(&mut self, coord: (usize, usize, usize)) -> eyre::Result<()> {
         if let std::collections::hash_map::Entry::Vacant(e) = self.cached_turfs.entry(coord) {
-            let turf = unsafe { extremely_unsafe_resolve_coord(coord, self.world_bounds)? };
-            e.insert(Rc::new(SmartByondValue::from(turf)));
+            let turf = (self.resolve_turf)(coord, self.world_bounds)?;
+            e.insert(Rc::new(SmartByondValue::from(turf.value())));
+            self.stats.prewarmed += 1;
         }
 
         Ok(())
@@ -100,14 +246,17 @@ impl CachedTurfs {
 
     /// Resolves the turf, either by looking it up internally, or failing that, looking it up through byondapi
     /// Will cache byondapi results
-    pub fn resolve_coord(&mut self, coord: (usize, usize, usize)) -> eyre::Result<ByondValue> {
+    pub fn resolve_coord(&mut self, coord: (usize, usize, usize)) -> eyre::Result<TurfRef> {
         if let Some(turf) = self.cached_turfs.get(&coord) {
-            Ok(turf.get_temp_ref())
+            self.stats.hits += 1;
+            // Only resolved turfs are cached, and the cache is cleared whenever the bounds change
+            Ok(TurfRef(turf.get_temp_ref()))
         } else {
-            let turf = unsafe { extremely_unsafe_resolve_coord(coord, self.world_bounds)? };
+            self.stats.misses += 1;
+            let turf = (self.resolve_turf)(coord, self.world_bounds)?;
 
             self.cached_turfs
-                .insert(coord, Rc::new(SmartByondValue::from(turf)));
+                .insert(coord, Rc::new(SmartByondValue::from(turf.value())));
 
             Ok(turf)
         }
@@ -116,16 +265,345 @@ impl CachedTurfs {
 
 /// A single command buffer to place a map down. The coordinates are fixed at this point, this is
 /// only for the purpose of splitting the work up across ticks.
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct CommandBuffer<'s> {
     pub created_areas: HashMap<&'s str, SharedByondValue>,
-    pub known_types: HashMap<&'s str, SharedByondValue>,
+    /// text2path of every path the load has needed, movable types and path values alike.
+    /// Paths that don't exist are null.
+    pub known_types: PathCache<'s>,
     pub cached_turfs: CachedTurfs,
-    pub commands: VecDeque<Command<'s>>,
+    /// Checks the map before anything else is done, then is None. This is done by the work loop,
+    /// pausing the same as it does for commands, so that a huge map doesn't spend its first tick on it.
+    /// Until it's done, none of the fields filled in from its [`Validation`] are either.
+    pub validator: Option<Validator<'s>>,
+    /// Movable paths to look up into `known_types` after validating, reporting the bad ones,
+    /// with how many of them there are
+    pub movable_paths: VecDeque<(&'s str, usize)>,
+    /// Turfs to look up after that, before anything is placed
+    pub prewarm: VecDeque<(usize, usize, usize)>,
+    pub commands: CommandStream<'s>,
+    pub options: LoadOptions,
+    /// Where the map was placed, (minx, miny, minz, maxx, maxy, maxz), for checking big atoms against
+    pub placed_bounds: (usize, usize, usize, usize, usize, usize),
+    /// For additive loads, the last tile atoms were placed on, and the types that were on it before that
+    pub additive_tile: Option<TileContents>,
+    pub timing: LoadTiming,
+    /// For load_options.z_loaded_proc, (last tile, z) of every z-level not finished yet, in the order they finish
+    pub z_last_tiles: VecDeque<(usize, usize)>,
+    /// Set by `_bapidmm_pause_load`. Nothing is run until it's resumed, but the buffer is kept.
+    pub paused: bool,
+    /// For load_options.areas_as_overlay, where each area would have been placed
+    pub area_membership: AreaMembership,
+    /// For load_options.checksum, everything placed so far
+    pub checksum: Option<LoadChecksum>,
+    /// How many commands are left to run. Counted the first time it's asked for, then kept up to date.
+    pub remaining: Option<usize>,
+    /// The same for each tile. Thrown away when a bad path is skipped, as it can't tell which tiles had it.
+    pub remaining_at: Option<HashMap<(usize, usize, usize), usize>>,
+}
+
+/// Area path (after remapping) -> every turf that would have been put in it, in the order they were reached.
+pub type AreaMembership = HashMap<String, Vec<(usize, usize, usize)>>;
+
+/// Path text -> text2path of it, see [`cached_text2path`]
+pub type PathCache<'s> = HashMap<&'s str, SharedByondValue>;
+
+/// How long a [`CommandBuffer`] took, over every call to [`CommandBuffer::work`].
+/// Written to the parsed map's `load_timing` when it finishes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LoadTiming {
+    /// Time spent running commands, not counting the time in between calls
+    pub elapsed: Duration,
+    /// How many times it paused to let the tick carry on
+    pub yields: usize,
+}
+
+/// A rolling hash of the coordinate and path of everything a load placed, in the order it was
+/// placed. Written to the parsed map's `checksum` when it finishes, see [`LoadOptions::checksum`].
+///
+/// This is FNV-1a, so it comes out the same on every machine. It isn't meant to stand up to
+/// someone crafting a map to match, only to notice that a load placed something different.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadChecksum(u64);
+
+impl Default for LoadChecksum {
+    fn default() -> Self {
+        LoadChecksum(0xcbf29ce484222325)
+    }
+}
+
+impl LoadChecksum {
+    /// Adds something placed at `loc`, after everything added before it.
+    pub fn add(&mut self, loc: (usize, usize, usize), path: &str) {
+        let coord_bytes = [loc.0, loc.1, loc.2].map(|coord| (coord as u64).to_le_bytes());
+        // The 0 keeps the end of one path from running into the next coordinate
+        let bytes = coord_bytes
+            .iter()
+            .flatten()
+            .copied()
+            .chain(path.bytes())
+            .chain([0]);
+        for byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+/// As 16 hex digits, since DM numbers can't hold all of it
+impl fmt::Display for LoadChecksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// A tile, and the type of everything on it.
+pub type TileContents = ((usize, usize, usize), HashSet<String>);
+
+/// Where a [`CommandBuffer`] got to in one call to [`CommandBuffer::work`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkStatus {
+    /// There are commands left, call it again to carry on.
+    Paused,
+    /// Everything has been placed, the buffer can be dropped.
+    Finished,
+}
+
+impl<'s> CommandBuffer<'s> {
+    /// How many commands are left to run. Commands are generated as they're run, so the first call
+    /// has to generate (and throw away) all of them, after that it's kept count of. Until the map's
+    /// been validated, this counts movables with bad paths too.
+    pub fn remaining(&mut self) -> usize {
+        let (commands, options) = (&self.commands, &self.options);
+        *self.remaining.get_or_insert_with(|| {
+            let mut commands = commands.clone();
+            std::iter::from_fn(|| commands.next_command(options)).count()
+        })
+    }
+
+    /// How many of the commands left place anything at `loc`. Counted for every tile the first
+    /// time, the same as [`CommandBuffer::remaining`].
+    pub fn remaining_at(&mut self, loc: (usize, usize, usize)) -> usize {
+        let (commands, options) = (&self.commands, &self.options);
+        let remaining_at = self.remaining_at.get_or_insert_with(|| {
+            let mut commands = commands.clone();
+            let mut remaining_at = HashMap::new();
+            while let Some(command) = commands.next_command(options) {
+                for loc in command.locs() {
+                    *remaining_at.entry(*loc).or_insert(0) += 1;
+                }
+            }
+            remaining_at
+        });
+        remaining_at.get(&loc).copied().unwrap_or(0)
+    }
+
+    /// Takes a command that's been handed out off the counts, if they've been counted yet.
+    fn count_run(&mut self, command: &Command) {
+        if let Some(remaining) = &mut self.remaining {
+            *remaining = remaining.saturating_sub(1);
+        }
+        if let Some(remaining_at) = &mut self.remaining_at {
+            for loc in command.locs() {
+                if let Some(count) = remaining_at.get_mut(loc) {
+                    *count = count.saturating_sub(1);
+                }
+            }
+        }
+    }
+
+    /// Runs commands until they're all done, or `should_pause` says to stop for now.
+    ///
+    /// `should_pause` is called after every command with how many have been run in this call.
+    /// This is everything the work bind does besides finding the buffer, so anything that can
+    /// give BYOND calls a [`ParsedMapTranslationLayer`] can drive a load however it likes, such as
+    /// from an async task that yields whenever this pauses.
+    pub fn work(
+        &mut self,
+        parsed_map: &mut ParsedMapTranslationLayer,
+        should_pause: impl FnMut(usize) -> eyre::Result<bool>,
+    ) -> eyre::Result<WorkStatus> {
+        let started = Instant::now();
+        let status = self.run_commands(parsed_map, should_pause);
+        self.timing.elapsed += started.elapsed();
+        if let Ok(WorkStatus::Paused) = status {
+            self.timing.yields += 1;
+        }
+        status
+    }
+
+    fn run_commands(
+        &mut self,
+        parsed_map: &mut ParsedMapTranslationLayer,
+        mut should_pause: impl FnMut(usize) -> eyre::Result<bool>,
+    ) -> eyre::Result<WorkStatus> {
+        zone!("command loop");
+        self.cached_turfs.check_invalidate()?;
+
+        // Validating a tile and the lookups count as commands for pausing
+        let mut commands_run = 0;
+        while let Some(validator) = &mut self.validator {
+            let prefabs = self.commands.prefabs();
+            if validator.step(prefabs, self.commands.settings(), &self.options, parsed_map)? {
+                commands_run += 1;
+                if should_pause(commands_run)? {
+                    return Ok(WorkStatus::Paused);
+                }
+            } else if let Some(validator) = self.validator.take() {
+                let validation = validator.finish(parsed_map)?;
+                self.validated(parsed_map, validation)?;
+            }
+        }
+
+        while let Some((path_text, count)) = self.movable_paths.pop_front() {
+            // Reported once here, the commands placing it are skipped
+            if cached_text2path(&mut self.known_types, path_text)?.is_null() {
+                parsed_map.add_warning_at(Severity::Error, format!("Bad path {path_text:#?}"))?;
+                self.commands.skip_path(path_text);
+                // Nothing has been placed yet, so any count taken still has all of them
+                if let Some(remaining) = &mut self.remaining {
+                    *remaining = remaining.saturating_sub(count);
+                }
+                self.remaining_at = None;
+            }
+            commands_run += 1;
+            if should_pause(commands_run)? {
+                return Ok(WorkStatus::Paused);
+            }
+        }
+
+        while let Some(coord) = self.prewarm.pop_front() {
+            // The world can change size after the load was set up. If the turf can't be found
+            // anymore, the commands placing things on it say so.
+            let _ = self.cached_turfs.cache(coord);
+            commands_run += 1;
+            if should_pause(commands_run)? {
+                return Ok(WorkStatus::Paused);
+            }
+        }
+
+        while let Some(command) = self.commands.next_command(&self.options) {
+            #[cfg(debug_assertions)]
+            if PANIC_ON_NEXT_COMMAND.swap(false, std::sync::atomic::Ordering::Relaxed) {
+                panic!("Panicking on purpose, as requested by _bapidmm_test_panic_on_next_command");
+            }
+
+            self.finish_z_levels(parsed_map, Some(self.commands.row_start()))?;
+            self.count_run(&command);
+
+            let (loc, path) = (command.loc(), command.prefab().0);
+            let result = {
+                let (mut world, mut context) = self.command_parts(parsed_map);
+                run_command(&mut world, &mut context, command)
+            };
+            if let Err(e) = result {
+                if self.options.strict || is_fatal(&e) {
+                    return Err(e);
+                }
+                parsed_map.add_warning_at(
+                    Severity::Error,
+                    format!("Failed to place {path:#?} at {loc:?}: {e:#}"),
+                )?;
+            }
+            commands_run += 1;
+
+            if should_pause(commands_run)? {
+                return Ok(WorkStatus::Paused);
+            }
+        }
+
+        self.finish_z_levels(parsed_map, None)?;
+        Ok(WorkStatus::Finished)
+    }
+
+    /// Splits the buffer into what [`run_command`] needs.
+    fn command_parts<'a>(
+        &'a mut self,
+        parsed_map: &'a mut ParsedMapTranslationLayer,
+    ) -> (ByondWorld<'a, 's>, CommandContext<'a>) {
+        let world = ByondWorld {
+            parsed_map,
+            cached_turfs: &mut self.cached_turfs,
+            known_types: &mut self.known_types,
+            created_areas: &mut self.created_areas,
+        };
+        let context = CommandContext {
+            options: &self.options,
+            placed_bounds: self.placed_bounds,
+            additive_tile: &mut self.additive_tile,
+            area_membership: &mut self.area_membership,
+            checksum: &mut self.checksum,
+        };
+        (world, context)
+    }
+
+    /// Takes on what validating the map found, now that it's done.
+    fn validated(
+        &mut self,
+        parsed_map: &mut ParsedMapTranslationLayer,
+        validation: Validation<'s>,
+    ) -> eyre::Result<()> {
+        parsed_map.set_bounds(validation.bounds)?;
+        self.placed_bounds = validation.bounds;
+        self.z_last_tiles = validation.z_last_tiles;
+        self.movable_paths = validation.movable_paths;
+        self.prewarm = validation.prewarm;
+        // Nothing has been placed yet, so this is before anything that could use it
+        if let Some(seed) = self.options.rand_seed {
+            _bapi_helper_rand_seed(seed)?;
+        }
+        Ok(())
+    }
+
+    /// Calls load_options.z_loaded_proc for each z-level whose last tile is before `done_before`,
+    /// or all of the ones left if None.
+    fn finish_z_levels(
+        &mut self,
+        parsed_map: &mut ParsedMapTranslationLayer,
+        done_before: Option<usize>,
+    ) -> eyre::Result<()> {
+        let Some(proc_name) = &self.options.z_loaded_proc else {
+            return Ok(());
+        };
+        while let Some(&(tile, z)) = self.z_last_tiles.front() {
+            if done_before.is_some_and(|done_before| tile >= done_before) {
+                break;
+            }
+            self.z_last_tiles.pop_front();
+            _bapi_call_z_loaded_proc(proc_name, parsed_map.parsed_map, z)?;
+        }
+        Ok(())
+    }
 }
 
+/// Only check whether we're out of time every this many commands.
 const MIN_PAUSE: usize = 100;
 
+/// Debug builds only: Makes the next command panic, for testing that we recover from it.
+#[cfg(debug_assertions)]
+static PANIC_ON_NEXT_COMMAND: std::sync::atomic::AtomicBool =
+    std::sync::atomic::AtomicBool::new(false);
+
+#[cfg(debug_assertions)]
+#[byondapi::bind]
+pub fn _bapidmm_test_panic_on_next_command() {
+    setup_panic_handler();
+    PANIC_ON_NEXT_COMMAND.store(true, std::sync::atomic::Ordering::Relaxed);
+    Ok(ByondValue::null())
+}
+
+/// Debug builds only: Makes the next command fail, for testing that the rest of the load carries on.
+#[cfg(debug_assertions)]
+static FAIL_NEXT_COMMAND: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+#[cfg(debug_assertions)]
+#[byondapi::bind]
+pub fn _bapidmm_test_fail_next_command() {
+    setup_panic_handler();
+    FAIL_NEXT_COMMAND.store(true, std::sync::atomic::Ordering::Relaxed);
+    Ok(ByondValue::null())
+}
+
 #[byondapi::bind]
 pub fn _bapidmm_work_commandbuffer(parsed_map: ByondValue, resume_key: ByondValue) {
     zone!("_bapidmm_work_commandbuffer");
@@ -134,118 +612,539 @@ pub fn _bapidmm_work_commandbuffer(parsed_map: ByondValue, resume_key: ByondValu
     let id = parsed_map.get_internal_index()? as usize;
     let resume_key = resume_key.get_number()? as usize;
 
-    zone!("borrow parsed_map");
-    let internal_data = unsafe { PARSED_MAPS_ARENABASED.get_mut() }
-        .get_mut(id)
-        .ok_or_else(|| eyre!("Bad internal index {id:#?}"))?;
+    let result = work_commandbuffer(&mut parsed_map, id, resume_key);
+    match parsed_map.record_error(result) {
+        // Already finished or never started, there's nothing left to do. DM can check last_error.
+        // Any other load of the map is still going, so loading is left as it is.
+        Err(e) if matches!(e.downcast_ref(), Some(LoadError::BadResumeKey(_))) => {
+            Ok(ByondValue::new_num(0.))
+        }
+        result => result,
+    }
+}
 
-    zone!("borrow internal_data");
-    let mut minimum_pause_counter = 0;
+#[byondapi::bind]
+/// For finding out why a tile of an unfinished load hasn't appeared yet: How many commands placing
+/// something at (x, y, z) the load with `resume_key` has left to run.
+pub fn _bapidmm_remaining_commands_at(
+    parsed_map: ByondValue,
+    resume_key: ByondValue,
+    x: ByondValue,
+    y: ByondValue,
+    z: ByondValue,
+) {
+    setup_panic_handler();
+    let mut parsed_map = ParsedMapTranslationLayer { parsed_map };
+    let result = parsed_map.get_internal_index().and_then(|id| {
+        let resume_key = resume_key.get_number()? as usize;
+        let loc = (
+            x.get_number()? as usize,
+            y.get_number()? as usize,
+            z.get_number()? as usize,
+        );
+        let Some(buffer) = unsafe { get_parsed_map(id as usize) }?
+            .command_buffers
+            .get_mut(&resume_key)
+        else {
+            return Err(LoadError::BadResumeKey(resume_key).into());
+        };
+        Ok(ByondValue::new_num(buffer.remaining_at(loc) as f32))
+    });
+    parsed_map.record_error(result)
+}
 
-    let command_buffers_map = &mut internal_data.command_buffers;
+#[byondapi::bind]
+/// Stops the load with `resume_key` from doing anything until `_bapidmm_resume_load`.
+/// `_bapidmm_work_commandbuffer` still says there's work left, so whatever is working it keeps
+/// waiting rather than finishing early. The map stays loading, so it can't be freed in the meantime.
+pub fn _bapidmm_pause_load(parsed_map: ByondValue, resume_key: ByondValue) {
+    setup_panic_handler();
+    let mut parsed_map = ParsedMapTranslationLayer { parsed_map };
+    let result = parsed_map
+        .get_internal_index()
+        .and_then(|id| set_paused(id as usize, resume_key.get_number()? as usize, true));
+    parsed_map.record_error(result)
+}
 
-    zone!("lookup our buffer");
-    if let Some(our_command_buffer) = command_buffers_map.get_mut(&resume_key) {
-        zone!("command loop");
-        let cached_turfs = &mut our_command_buffer.cached_turfs;
-        cached_turfs.check_invalidate()?;
-
-        while let Some(command) = our_command_buffer.commands.pop_front() {
-            match command {
-                Command::CreateArea { loc, prefab, new_z } => {
-                    zone!("Commmand::CreateArea");
-
-                    let area =
-                        if let Some(area) = our_command_buffer.created_areas.get_mut(prefab.0) {
-                            area
-                        } else {
-                            zone!("new area creation");
-                            let area = _bapi_create_or_get_area(prefab.0)?;
-                            let area = Rc::new(SmartByondValue::from(area));
-                            our_command_buffer.created_areas.insert(prefab.0, area);
-                            // This can't possibly fail, I hope
-                            our_command_buffer.created_areas.get_mut(prefab.0).unwrap()
-                        };
-
-                    let area_ref = area.get_temp_ref();
-                    let turf_ref = cached_turfs.resolve_coord(loc)?;
-                    if turf_ref.is_null() {
-                        parsed_map.add_warning(format!(
-                            "Unable to create atom at {loc:#?} because coord was null"
-                        ))?;
-                        continue;
-                    }
+#[byondapi::bind]
+/// Lets a load paused by `_bapidmm_pause_load` carry on from where it was.
+pub fn _bapidmm_resume_load(parsed_map: ByondValue, resume_key: ByondValue) {
+    setup_panic_handler();
+    let mut parsed_map = ParsedMapTranslationLayer { parsed_map };
+    let result = parsed_map
+        .get_internal_index()
+        .and_then(|id| set_paused(id as usize, resume_key.get_number()? as usize, false));
+    parsed_map.record_error(result)
+}
 
-                    if !new_z {
-                        _bapi_handle_area_contain(turf_ref, area_ref)?;
-                    }
-                    _bapi_add_turf_to_area(area_ref, turf_ref)?;
+fn set_paused(id: usize, resume_key: usize, paused: bool) -> eyre::Result<ByondValue> {
+    let internal_data = unsafe { get_parsed_map(id) }?;
+    // Out of command_buffers as it runs, from one of the procs it calls
+    if internal_data.working == Some(resume_key) {
+        return Err(LoadError::Busy(id).into());
+    }
+    let Some(buffer) = internal_data.command_buffers.get_mut(&resume_key) else {
+        return Err(LoadError::BadResumeKey(resume_key).into());
+    };
+    buffer.paused = paused;
+    Ok(ByondValue::null())
+}
+
+fn work_commandbuffer(
+    parsed_map: &mut ParsedMapTranslationLayer,
+    id: usize,
+    resume_key: usize,
+) -> eyre::Result<ByondValue> {
+    zone!("take our buffer");
+    let mut our_command_buffer = {
+        let internal_data = unsafe { get_parsed_map(id) }?;
+        if internal_data.working.is_some() {
+            return Err(LoadError::Busy(id).into());
+        }
+        let Some(buffer) = internal_data.command_buffers.remove(&resume_key) else {
+            return Err(LoadError::BadResumeKey(resume_key).into());
+        };
+        if buffer.paused {
+            internal_data.command_buffers.insert(resume_key, buffer);
+            return Ok(ByondValue::new_num(1.));
+        }
+        internal_data.working = Some(resume_key);
+        buffer
+    };
+
+    // Nothing borrowed from the arena is held while the commands run, as they call into DM (turf
+    // hooks, z_loaded_proc, New()), which can call us back. A panic unwinding out of the bind
+    // would abort the entire server, so stop it here.
+    let status = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        our_command_buffer.work(parsed_map, |commands_run| {
+            Ok(commands_run % MIN_PAUSE == 0 && _bapi_helper_tick_check()?)
+        })
+    }));
+
+    let internal_data = unsafe { get_parsed_map(id) }?;
+    internal_data.working = None;
+    let result = match status {
+        Ok(Ok(WorkStatus::Paused)) => {
+            internal_data
+                .command_buffers
+                .insert(resume_key, our_command_buffer);
+            return Ok(ByondValue::new_num(1.));
+        }
+        Ok(Ok(WorkStatus::Finished)) => {
+            finish_load(parsed_map, &our_command_buffer).map(|()| ByondValue::new_num(0.))
+        }
+        // Strict loads and fatal errors end here, the same as if there had been nothing left
+        Ok(Err(e)) => Err(e),
+        // The buffer may be half way through a command, so it can't be resumed
+        Err(_) => parsed_map
+            .add_warning_at(
+                Severity::Error,
+                "Loading was interrupted by a panic, check rust_log.txt",
+            )
+            .map(|()| ByondValue::new_num(0.)),
+    };
+
+    // Clean up after ourselves. Dropping the buffer releases every ref it was holding onto.
+    zone!("cleanup");
+    let manages_loading = !our_command_buffer.options.manual_loading;
+    drop(our_command_buffer);
+    if manages_loading {
+        parsed_map.end_loading()?;
+    }
+    result
+}
+
+/// Writes everything a finished load reports back to the parsed map
+fn finish_load(
+    parsed_map: &mut ParsedMapTranslationLayer,
+    buffer: &CommandBuffer,
+) -> eyre::Result<()> {
+    parsed_map.set_turf_cache_stats(buffer.cached_turfs.stats)?;
+    parsed_map.set_load_timing(buffer.timing)?;
+    if buffer.options.areas_as_overlay {
+        parsed_map.set_area_membership(&buffer.area_membership)?;
+    }
+    if buffer.checksum.is_some() {
+        parsed_map.set_checksum(buffer.checksum)?;
+    }
+    Ok(())
+}
+
+#[byondapi::bind]
+/// For debugging: What the prefab `key` would spawn, without spawning it, as a list of
+/// `list("path" = "/obj/thing", "vars" = list(...))` in the order the map lists them, so the turf and area are last.
+/// vars are converted the same way as when loading with default load options, so they're null if
+/// the prefab doesn't set any. Returns null if the map has no such key.
+pub fn _bapidmm_get_prefab_vars(parsed_map: ByondValue, key: ByondValue) {
+    setup_panic_handler();
+    let mut parsed_map = ParsedMapTranslationLayer { parsed_map };
+    let result = parsed_map.get_internal_index().and_then(|id| {
+        let key = key.get_string()?;
+        get_prefab_vars(&mut parsed_map, id as usize, &key)
+    });
+    parsed_map.record_error(result)
+}
+
+fn get_prefab_vars(
+    parsed_map: &mut ParsedMapTranslationLayer,
+    id: usize,
+    key: &str,
+) -> eyre::Result<ByondValue> {
+    let (_, (prefabs, _)) = &unsafe { get_parsed_map(id) }?.parsed_data;
+    let Some(prefab_list) = prefabs.get(key) else {
+        return Ok(ByondValue::null());
+    };
+
+    let options = LoadOptions::default();
+    let mut path_cache = PathCache::new();
+    let mut list = ByondValue::new_list()?;
+    for (path, vars) in prefab_list {
+        let mut entry = ByondValue::new_list()?;
+        entry.write_list_index(ByondValue::new_str("path")?, ByondValue::new_str(*path)?)?;
+        entry.write_list_index(
+            ByondValue::new_str("vars")?,
+            convert_vars_list_to_byondlist(parsed_map, &options, &mut path_cache, None, vars)?,
+        )?;
+        list.push_list(entry)?;
+    }
+    Ok(list)
+}
+
+/// What commands are carried out against. Loads place into BYOND with [`ByondWorld`], while
+/// [`simulate_load`](crate::load::load_buffer::simulate_load) only keeps count, so that everything
+/// [`run_command`] decides for itself can be run without BYOND.
+pub trait LoadWorld<'s>: WarningSink {
+    type Turf: Copy;
+    type Area: Copy;
+    type Movable: Copy;
+
+    /// The turf at `loc`, or None if there isn't one. Errors if `loc` is outside of the world.
+    fn resolve_turf(&mut self, loc: (usize, usize, usize)) -> eyre::Result<Option<Self::Turf>>;
+
+    /// The area `path` is remapped to `area_path`, made if there isn't one yet.
+    fn area(&mut self, path: &'s str, area_path: &str) -> eyre::Result<Self::Area>;
+
+    /// Whether `turf` is still in the world's default area, for [`AreaPolicy::Merge`].
+    fn has_default_area(&mut self, turf: Self::Turf) -> eyre::Result<bool>;
+
+    /// Puts `turf` in `area`, taking it out of the one it was in first if `contain` is set.
+    fn add_to_area(
+        &mut self,
+        turf: Self::Turf,
+        area: Self::Area,
+        contain: bool,
+    ) -> eyre::Result<()>;
+
+    #[allow(clippy::too_many_arguments)]
+    fn create_turf(
+        &mut self,
+        options: &LoadOptions,
+        turf: Self::Turf,
+        loc: (usize, usize, usize),
+        prefab: &'s Prefab<'s>,
+        place_on_top: bool,
+        no_changeturf: bool,
+    ) -> eyre::Result<()>;
+
+    /// Places a movable on `turf`, or returns None (after warning) if its path doesn't exist.
+    fn create_movable(
+        &mut self,
+        options: &LoadOptions,
+        turf: Self::Turf,
+        loc: (usize, usize, usize),
+        prefab: &'s Prefab<'s>,
+        note: Option<&str>,
+    ) -> eyre::Result<Option<Self::Movable>>;
+
+    /// The types of everything on `turf`, for additive loads.
+    fn content_types(&mut self, turf: Self::Turf) -> eyre::Result<HashSet<String>>;
+
+    /// The (width, height) in tiles that `movable` covers.
+    fn tile_extent(&mut self, movable: Self::Movable) -> eyre::Result<(usize, usize)>;
+}
+
+/// Places into BYOND, through the buffer's caches.
+pub struct ByondWorld<'a, 's> {
+    pub parsed_map: &'a mut ParsedMapTranslationLayer,
+    pub cached_turfs: &'a mut CachedTurfs,
+    pub known_types: &'a mut PathCache<'s>,
+    pub created_areas: &'a mut HashMap<&'s str, SharedByondValue>,
+}
+
+impl WarningSink for ByondWorld<'_, '_> {
+    fn add_warning_at<S: Into<String>>(
+        &mut self,
+        severity: Severity,
+        warning: S,
+    ) -> eyre::Result<()> {
+        self.parsed_map.add_warning_at(severity, warning)
+    }
+}
+
+impl<'s> LoadWorld<'s> for ByondWorld<'_, 's> {
+    type Turf = ByondValue;
+    type Area = ByondValue;
+    type Movable = ByondValue;
+
+    fn resolve_turf(&mut self, loc: (usize, usize, usize)) -> eyre::Result<Option<ByondValue>> {
+        let turf = self.cached_turfs.resolve_coord(loc)?.value();
+        Ok((!turf.is_null()).then_some(turf))
+    }
+
+    fn area(&mut self, path: &'s str, area_path: &str) -> eyre::Result<ByondValue> {
+        if let Some(area) = self.created_areas.get(path) {
+            return Ok(area.get_temp_ref());
+        }
+        zone!("new area creation");
+        let area = _bapi_create_or_get_area(area_path)?;
+        self.created_areas
+            .insert(path, Rc::new(SmartByondValue::from(area)));
+        Ok(area)
+    }
+
+    fn has_default_area(&mut self, turf: ByondValue) -> eyre::Result<bool> {
+        _bapi_helper_turf_has_default_area(turf)
+    }
+
+    fn add_to_area(
+        &mut self,
+        turf: ByondValue,
+        area: ByondValue,
+        contain: bool,
+    ) -> eyre::Result<()> {
+        if contain {
+            _bapi_handle_area_contain(turf, area)?;
+        }
+        _bapi_add_turf_to_area(area, turf)
+    }
+
+    fn create_turf(
+        &mut self,
+        options: &LoadOptions,
+        turf: ByondValue,
+        loc: (usize, usize, usize),
+        prefab: &'s Prefab<'s>,
+        place_on_top: bool,
+        no_changeturf: bool,
+    ) -> eyre::Result<()> {
+        create_turf(
+            self.parsed_map,
+            options,
+            self.known_types,
+            turf,
+            loc,
+            prefab,
+            place_on_top,
+            no_changeturf,
+        )?;
+        Ok(())
+    }
+
+    fn create_movable(
+        &mut self,
+        options: &LoadOptions,
+        turf: ByondValue,
+        loc: (usize, usize, usize),
+        prefab: &'s Prefab<'s>,
+        note: Option<&str>,
+    ) -> eyre::Result<Option<ByondValue>> {
+        create_movable(
+            self.parsed_map,
+            options,
+            self.known_types,
+            turf,
+            loc,
+            prefab,
+            note,
+        )
+    }
+
+    fn content_types(&mut self, turf: ByondValue) -> eyre::Result<HashSet<String>> {
+        _bapi_helper_get_content_types(turf)
+    }
+
+    fn tile_extent(&mut self, movable: ByondValue) -> eyre::Result<(usize, usize)> {
+        _bapi_helper_get_tile_extent(movable)
+    }
+}
+
+/// What [`run_command`] keeps track of besides the world it places into, borrowed from the buffer.
+pub struct CommandContext<'a> {
+    pub options: &'a LoadOptions,
+    /// See [`CommandBuffer::placed_bounds`]
+    pub placed_bounds: (usize, usize, usize, usize, usize, usize),
+    pub additive_tile: &'a mut Option<TileContents>,
+    pub area_membership: &'a mut AreaMembership,
+    pub checksum: &'a mut Option<LoadChecksum>,
+}
+
+/// Carries out a single command. Errors here only affect this command, or the one tile of an area
+/// run, unless the load is strict.
+pub fn run_command<'s, W: LoadWorld<'s>>(
+    world: &mut W,
+    context: &mut CommandContext,
+    command: Command<'s>,
+) -> eyre::Result<()> {
+    #[cfg(debug_assertions)]
+    if FAIL_NEXT_COMMAND.swap(false, std::sync::atomic::Ordering::Relaxed) {
+        return Err(eyre::eyre!(
+            "Failing on purpose, as requested by _bapidmm_test_fail_next_command"
+        ));
+    }
+
+    let options = context.options;
+    match command {
+        Command::CreateArea {
+            locs,
+            prefab,
+            new_z,
+        } => {
+            zone!("Commmand::CreateArea");
+
+            let area_path = options
+                .area_remap
+                .get(prefab.0)
+                .map_or(prefab.0, String::as_str);
+            // Overlay loads only look at turfs, so there's no area to make
+            let area = if options.areas_as_overlay {
+                None
+            } else {
+                Some(world.area(prefab.0, area_path)?)
+            };
+
+            // Each tile is its own, so one that fails doesn't stop the rest of the run
+            let mut place = |world: &mut W, loc| {
+                let Some(turf) = resolve_command_turf(world, loc, options.skip_out_of_bounds)?
+                else {
+                    return eyre::Ok(());
+                };
+
+                // Nothing on a new z-level has an area yet, so there's nothing to merge with
+                if !new_z
+                    && options.area_policy == AreaPolicy::Merge
+                    && !world.has_default_area(turf)?
+                {
+                    return Ok(());
                 }
-                Command::CreateTurf {
-                    loc,
-                    prefab,
-                    no_changeturf,
-                    place_on_top,
-                } => {
-                    zone!("Commmand::CreateTurf");
-                    let turf_ref = cached_turfs.resolve_coord(loc)?;
-                    if turf_ref.is_null() {
-                        parsed_map.add_warning(format!(
-                            "Unable to create atom at {loc:#?} because coord was null"
-                        ))?;
-                        continue;
+                let Some(area) = area else {
+                    match context.area_membership.get_mut(area_path) {
+                        Some(turfs) => turfs.push(loc),
+                        None => {
+                            context
+                                .area_membership
+                                .insert(area_path.to_owned(), vec![loc]);
+                        }
                     }
-
-                    create_turf(
-                        &mut parsed_map,
-                        turf_ref,
-                        prefab,
-                        place_on_top,
-                        no_changeturf,
-                    )?;
+                    return Ok(());
+                };
+                world.add_to_area(turf, area, !new_z || options.contain_new_z)?;
+                if let Some(checksum) = context.checksum {
+                    checksum.add(loc, area_path);
                 }
-                Command::CreateAtom { loc, prefab } => {
-                    zone!("Commmand::CreateAtom");
-                    let turf_ref = cached_turfs.resolve_coord(loc)?;
-                    if turf_ref.is_null() {
-                        parsed_map.add_warning(format!(
-                            "Unable to create atom at {loc:#?} because coord was null"
-                        ))?;
-                        continue;
+                Ok(())
+            };
+            for loc in locs {
+                if let Err(e) = place(world, loc) {
+                    if options.strict || is_fatal(&e) {
+                        return Err(e);
                     }
-                    create_movable(
-                        &mut parsed_map,
-                        &mut our_command_buffer.known_types,
-                        turf_ref,
-                        prefab,
+                    world.add_warning_at(
+                        Severity::Error,
+                        format!("Failed to place {:#?} at {loc:?}: {e:#}", prefab.0),
                     )?;
                 }
             }
-            minimum_pause_counter += 1;
+        }
+        Command::CreateTurf {
+            loc,
+            prefab,
+            no_changeturf,
+            place_on_top,
+        } => {
+            zone!("Commmand::CreateTurf");
+            let Some(turf) = resolve_command_turf(world, loc, options.skip_out_of_bounds)? else {
+                return Ok(());
+            };
 
-            // Yield
-            if (minimum_pause_counter % MIN_PAUSE == 0) && _bapi_helper_tick_check()? {
-                return Ok(ByondValue::new_num(1.));
+            world.create_turf(options, turf, loc, prefab, place_on_top, no_changeturf)?;
+            if let Some(checksum) = context.checksum {
+                checksum.add(loc, prefab.0);
             }
         }
-
-        // Clean up after ourselves
-        if our_command_buffer.commands.is_empty() {
-            zone!("cleanup");
-            command_buffers_map.remove(&resume_key);
+        Command::CreateAtom { loc, prefab, note } => {
+            zone!("Commmand::CreateAtom");
+            let Some(turf) = resolve_command_turf(world, loc, options.skip_out_of_bounds)? else {
+                return Ok(());
+            };
+            if options.additive
+                && existed_before(world, context.additive_tile, turf, loc, prefab.0)?
+            {
+                return Ok(());
+            }
+            let instance = world.create_movable(options, turf, loc, prefab, note)?;
+            if let Some(instance) = instance {
+                if let Some(checksum) = context.checksum {
+                    checksum.add(loc, prefab.0);
+                }
+                if options.big_atom_paths.contains(prefab.0) {
+                    check_big_atom(world, instance, prefab.0, loc, context.placed_bounds)?;
+                }
+            }
         }
     }
+    Ok(())
+}
 
-    zone!("set_loading false and return 0");
-    parsed_map.set_loading(false)?;
+/// For additive loads: Whether `path` was on the tile at `loc` before any of the map's atoms were.
+/// A tile's contents are read the first time one of its atoms is placed, which is why a prefab
+/// with two of the same type still places both.
+fn existed_before<'s, W: LoadWorld<'s>>(
+    world: &mut W,
+    additive_tile: &mut Option<TileContents>,
+    turf: W::Turf,
+    loc: (usize, usize, usize),
+    path: &str,
+) -> eyre::Result<bool> {
+    let types = match additive_tile {
+        Some((tile, types)) if *tile == loc => types,
+        _ => {
+            let types = world.content_types(turf)?;
+            &mut additive_tile.insert((loc, types)).1
+        }
+    };
+    Ok(types.contains(path))
+}
 
-    Ok(ByondValue::new_num(0.))
+/// Gets the turf a command acts on, or None (after warning) if the command should be skipped.
+fn resolve_command_turf<'s, W: LoadWorld<'s>>(
+    world: &mut W,
+    loc: (usize, usize, usize),
+    skip_out_of_bounds: bool,
+) -> eyre::Result<Option<W::Turf>> {
+    let turf = match world.resolve_turf(loc) {
+        Ok(turf) => turf,
+        Err(e) if skip_out_of_bounds => {
+            world.add_warning(format!("Skipped command: {e:#}"))?;
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+    if turf.is_none() {
+        world.add_warning(format!(
+            "Unable to create atom at {loc:#?} because coord was null"
+        ))?;
+    }
+    Ok(turf)
 }
 
-fn create_turf(
-    parsed_map: &mut ParsedMapTranslationLayer,
+#[allow(clippy::too_many_arguments)]
+fn create_turf<'s>(
+    warnings: &mut impl WarningSink,
+    options: &LoadOptions,
+    path_cache: &mut PathCache<'s>,
     turf: ByondValue,
-    prefab_turf: &dmm_lite::prefabs::Prefab,
+    loc: (usize, usize, usize),
+    prefab_turf: &dmm_lite::prefabs::Prefab<'s>,
     place_on_top: bool,
     no_changeturf: bool,
 ) -> eyre::Result<ByondValue> {
@@ -253,81 +1152,232 @@ fn create_turf(
     let (path_text, vars) = prefab_turf;
 
     zone!("creating path string");
-    let vars_list = convert_vars_list_to_byondlist(parsed_map, vars)?;
+    let vars_list = convert_vars_list_to_byondlist(warnings, options, path_cache, Some(loc), vars)?;
 
-    _bapi_create_turf(turf, path_text, vars_list, place_on_top, no_changeturf)
+    _bapi_create_turf(
+        options.create_turf_proc.as_deref(),
+        turf,
+        path_text,
+        vars_list,
+        place_on_top,
+        no_changeturf,
+        // Only placing on top leaves anything under the new turf
+        options.base_turf.as_deref().filter(|_| place_on_top),
+    )
 }
 
 fn create_movable<'s>(
-    parsed_map: &mut ParsedMapTranslationLayer,
-    path_cache: &mut HashMap<&'s str, SharedByondValue>,
+    warnings: &mut impl WarningSink,
+    options: &LoadOptions,
+    path_cache: &mut PathCache<'s>,
     turf: ByondValue,
+    loc: (usize, usize, usize),
     obj: &'s dmm_lite::prefabs::Prefab,
-) -> eyre::Result<()> {
+    note: Option<&str>,
+) -> eyre::Result<Option<ByondValue>> {
     zone!("movable creation");
     let (path_text, vars) = obj;
-    let path = if let Some(path) = path_cache.get(*path_text) {
-        path
-    } else {
-        let path = _bapi_helper_text2path(path_text)?;
-        if path.is_null() {
-            parsed_map.add_warning(format!("Bad path {path_text:#?}"))?;
-            return Ok(());
+    let path = cached_text2path(path_cache, path_text)?;
+    if path.is_null() {
+        warnings.add_warning_at(Severity::Error, format!("Bad path {path_text:#?}"))?;
+        return Ok(None);
+    }
+
+    let mut vars_list =
+        convert_vars_list_to_byondlist(warnings, options, path_cache, Some(loc), vars)?;
+    let pixel_offset = options.pixel_offset;
+    if pixel_offset != (0., 0.) {
+        if vars_list.is_null() {
+            vars_list = ByondValue::new_list()?;
+        }
+        apply_pixel_offset(&mut vars_list, "pixel_x", pixel_offset.0)?;
+        apply_pixel_offset(&mut vars_list, "pixel_y", pixel_offset.1)?;
+    }
+    if let (Some(note), Some(note_var)) = (note, options.note_vars.get(*path_text)) {
+        if vars_list.is_null() {
+            vars_list = ByondValue::new_list()?;
         }
+        vars_list.write_list_index(
+            ByondValue::new_str(note_var.as_str())?,
+            ByondValue::new_str(note)?,
+        )?;
+    }
 
-        let path = Rc::new(SmartByondValue::from(path));
-        path_cache.insert(path_text, path);
-        path_cache.get(path_text).unwrap()
-    };
+    if options.no_preloader {
+        zone!("byond_new");
+        let mut instance = ByondValue::builtin_new(path, &[turf])?;
+        if !vars_list.is_null() {
+            zone!("setting vars");
+            for (var, value) in vars_list.iter()? {
+                instance.write_var(var.get_string()?, &value)?;
+            }
+        }
+        return Ok(Some(instance));
+    }
 
-    if vars.is_some() {
-        let vars_list = convert_vars_list_to_byondlist(parsed_map, vars)?;
-        _bapi_setup_preloader(vars_list, path.get_temp_ref())?;
+    if !vars_list.is_null() {
+        _bapi_setup_preloader(vars_list, path)?;
     }
 
     zone!("byond_new");
-    let instance = ByondValue::builtin_new(path.get_temp_ref(), &[turf])?;
+    let instance = ByondValue::builtin_new(path, &[turf])?;
 
     _bapi_apply_preloader(instance)?;
 
+    Ok(Some(instance))
+}
+
+/// Warns if a movable covering more than one tile reaches past where the map was placed.
+fn check_big_atom<'s, W: LoadWorld<'s>>(
+    world: &mut W,
+    instance: W::Movable,
+    path: &str,
+    loc: (usize, usize, usize),
+    placed_bounds: (usize, usize, usize, usize, usize, usize),
+) -> eyre::Result<()> {
+    zone!("check_big_atom");
+    let (width, height) = world.tile_extent(instance)?;
+    if let Some(far_corner) = big_atom_overhang(loc, (width, height), placed_bounds) {
+        world.add_warning(format!(
+            "{path} at {loc:?} is {width}x{height} tiles, reaching {far_corner:?} which is past the loaded area"
+        ))?;
+    }
     Ok(())
 }
 
-fn convert_vars_list_to_byondlist(
-    parsed_map: &mut ParsedMapTranslationLayer,
-    vars: &Option<Vec<(&str, Literal)>>,
+/// The far corner of an atom placed at `loc` that covers `extent` tiles, if it's outside of `placed_bounds`.
+fn big_atom_overhang(
+    loc: (usize, usize, usize),
+    extent: (usize, usize),
+    placed_bounds: (usize, usize, usize, usize, usize, usize),
+) -> Option<(usize, usize, usize)> {
+    let far_corner = (
+        loc.0 + extent.0.max(1) - 1,
+        loc.1 + extent.1.max(1) - 1,
+        loc.2,
+    );
+    (far_corner.0 > placed_bounds.3 || far_corner.1 > placed_bounds.4).then_some(far_corner)
+}
+
+/// Adds `offset` to a pixel var in the converted `vars_list`, or sets it to `offset` if nothing
+/// sets it. Reading it back from the list takes literal_transform, extra_vars and duplicate vars
+/// into account, the same as it'll be set.
+fn apply_pixel_offset(vars_list: &mut ByondValue, var: &str, offset: f32) -> eyre::Result<()> {
+    if offset == 0. {
+        return Ok(());
+    }
+    let current = vars_list.read_list_index(ByondValue::new_str(var)?)?;
+    let value = if current.is_null() {
+        offset
+    } else if current.is_num() {
+        current.get_number()? + offset
+    } else {
+        // Not a number, so there's nothing sensible we can add to it
+        return Ok(());
+    };
+    vars_list.write_list_index(ByondValue::new_str(var)?, ByondValue::new_num(value))?;
+    Ok(())
+}
+
+fn convert_vars_list_to_byondlist<'s>(
+    warnings: &mut impl WarningSink,
+    options: &LoadOptions,
+    path_cache: &mut PathCache<'s>,
+    loc: Option<(usize, usize, usize)>,
+    vars: &Option<Vec<(&'s str, Literal<'s>)>>,
 ) -> eyre::Result<ByondValue> {
     zone!("convert_vars_list_to_byondlist");
+    if vars.is_none() && options.extra_vars.is_empty() {
+        return Ok(ByondValue::null());
+    }
+    let mut vars_list = ByondValue::new_list()?;
     if let Some(vars) = vars {
-        let mut vars_list = ByondValue::new_list()?;
-        for (key, literal) in vars {
-            let value = convert_literal_to_byondvalue(parsed_map, key, literal)?;
-            vars_list.write_list_index(ByondValue::new_str(*key)?, value)?;
+        for (key, literal) in options.transform_vars(vars) {
+            let fallback = match options.fallback_policy {
+                FallbackPolicy::AsString => None,
+                FallbackPolicy::Skip | FallbackPolicy::Fail => find_fallback(&literal),
+            };
+            if let Some(text) = fallback {
+                if options.fallback_policy == FallbackPolicy::Fail {
+                    return Err(eyre::eyre!(
+                        "Parser failed to parse value for {key:#?}: {text:#?}"
+                    ));
+                }
+                warnings.add_warning(format!(
+                    "Parser failed to parse value for {key:#?}, so it was skipped: {text:#?}"
+                ))?;
+                continue;
+            }
+            let value =
+                convert_literal_to_byondvalue(warnings, options, path_cache, loc, key, &literal)?;
+            vars_list.write_list_index(ByondValue::new_str(key)?, value)?;
         }
-        Ok(vars_list)
-    } else {
-        Ok(ByondValue::null())
     }
+    for (key, value) in &options.extra_vars {
+        // The map's own vars win
+        if vars.iter().flatten().any(|(var, _)| var == key) {
+            continue;
+        }
+        vars_list.write_list_index(ByondValue::new_str(key.as_str())?, value.get_temp_ref())?;
+    }
+    Ok(vars_list)
+}
+
+/// text2path of `path_text`, only asking BYOND the first time each path is seen in a load.
+/// Paths that don't exist are cached too, as null.
+fn cached_text2path<'s>(
+    path_cache: &mut PathCache<'s>,
+    path_text: &'s str,
+) -> eyre::Result<ByondValue> {
+    if let Some(path) = path_cache.get(path_text) {
+        return Ok(path.get_temp_ref());
+    }
+    let path = _bapi_helper_text2path(path_text)?;
+    path_cache.insert(path_text, Rc::new(SmartByondValue::from(path)));
+    Ok(path)
 }
 
 /// This only hard errors when running into an internal BYOND error, such as bad proc, bad value, out of memory, etc
-fn convert_literal_to_byondvalue(
-    parsed_map: &mut ParsedMapTranslationLayer,
+/// `loc` is where the value is being placed, if anywhere, for warnings.
+/// This recurses into lists, which is only ever [`dmm_lite::prefabs::MAX_LIST_DEPTH`] deep, as
+/// that's as far as they're parsed.
+fn convert_literal_to_byondvalue<'s>(
+    warnings: &mut impl WarningSink,
+    options: &LoadOptions,
+    path_cache: &mut PathCache<'s>,
+    loc: Option<(usize, usize, usize)>,
     key: &str,
-    literal: &Literal,
+    literal: &Literal<'s>,
 ) -> eyre::Result<ByondValue> {
     zone!("convert_literal_to_byondvalue");
     Ok(match literal {
         Literal::Number(n) => ByondValue::new_num(*n),
         Literal::String(s) => ByondValue::new_str(*s)?,
-        Literal::Path(p) => _bapi_helper_text2path(p)?,
-        Literal::File(f) => _bapi_helper_text2file(f)?,
+        Literal::Path(p) => cached_text2path(path_cache, p)?,
+        Literal::File(f) => {
+            let file = options.resolve_file(f);
+            if (options.check_files || options.file_base_dir.is_some())
+                && !Path::new(file.as_ref()).exists()
+            {
+                let warning = match loc {
+                    Some(loc) => {
+                        format!("File {file:#?} used by {key:#?} at {loc:?} does not exist")
+                    }
+                    None => format!("File {file:#?} used by {key:#?} does not exist"),
+                };
+                warnings.add_warning(warning)?;
+            }
+            _bapi_helper_text2file(&file)?
+        }
         Literal::Null => ByondValue::null(),
         Literal::Fallback(s) => {
-            parsed_map.add_warning(format!(
-                "Parser failed to parse value for {:#?} and fellback to string: {s:#?}",
-                key
-            ))?;
+            warnings.add_warning_at(
+                Severity::Info,
+                format!(
+                    "Parser failed to parse value for {:#?} and fellback to string: {s:#?}",
+                    key
+                ),
+            )?;
             ByondValue::new_str(*s)?
         }
         Literal::List(l) => {
@@ -335,10 +1385,12 @@ fn convert_literal_to_byondvalue(
             let mut list = ByondValue::new_list()?;
 
             for literal in l {
-                match convert_literal_to_byondvalue(parsed_map, key, literal) {
+                match convert_literal_to_byondvalue(
+                    warnings, options, path_cache, loc, key, literal,
+                ) {
                     Ok(item) => list.push_list(item)?,
                     Err(e) => {
-                        parsed_map.add_warning(format!(
+                        warnings.add_warning(format!(
                             "Inside list inside {:#?}, failed to parse value: {e:#?}",
                             key
                         ))?;
@@ -353,16 +1405,25 @@ fn convert_literal_to_byondvalue(
             let mut list = ByondValue::new_list()?;
 
             for (list_key, list_val) in map.iter() {
-                let key_bv = convert_literal_to_byondvalue(parsed_map, key, list_key);
-                let val_bv = convert_literal_to_byondvalue(parsed_map, key, list_val);
+                if let Some(kind) = suspicious_assoc_key(list_key) {
+                    warnings.add_warning(format!(
+                        "Inside assoc list inside {key:#?}, the key {list_key:?} is {kind}, this is usually a mapping error"
+                    ))?;
+                }
+                let key_bv = convert_literal_to_byondvalue(
+                    warnings, options, path_cache, loc, key, list_key,
+                );
+                let val_bv = convert_literal_to_byondvalue(
+                    warnings, options, path_cache, loc, key, list_val,
+                );
 
                 match (key_bv, val_bv) {
                     (Ok(key), Ok(val)) => list.write_list_index(key, val)?,
-                    (Err(e), _) => parsed_map.add_warning(format!(
+                    (Err(e), _) => warnings.add_warning(format!(
                         "Inside assoc list inside {:#?}, failed to parse assoc list key: {e:#?}",
                         key,
                     ))?,
-                    (_, Err(e)) => parsed_map.add_warning(format!(
+                    (_, Err(e)) => warnings.add_warning(format!(
                         "Inside assoc list inside {:#?}, failed to parse assoc list value: {e:#?}",
                         key
                     ))?,
@@ -373,3 +1434,212 @@ fn convert_literal_to_byondvalue(
         }
     })
 }
+
+/// The first value in `literal` that the parser didn't understand, not counting bare list keys.
+fn find_fallback<'s>(literal: &Literal<'s>) -> Option<&'s str> {
+    match literal {
+        Literal::Fallback(text) => Some(text),
+        Literal::List(list) => list.iter().find_map(find_fallback),
+        Literal::AssocList(list) => list.iter().find_map(|(key, value)| match key {
+            Literal::Fallback(text) if is_bare_list_key(text) => find_fallback(value),
+            _ => find_fallback(key).or_else(|| find_fallback(value)),
+        }),
+        _ => None,
+    }
+}
+
+/// Why `list_key` is an odd thing to have as an assoc list key, if it is.
+fn suspicious_assoc_key(list_key: &Literal) -> Option<&'static str> {
+    match list_key {
+        Literal::List(_) | Literal::AssocList(_) => Some("a list"),
+        Literal::Null => Some("null"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_checksum() {
+        let checksum = |placed: &[((usize, usize, usize), &str)]| {
+            let mut checksum = LoadChecksum::default();
+            for (loc, path) in placed {
+                checksum.add(*loc, path);
+            }
+            checksum
+        };
+        let floor = ((1, 1, 1), "/turf/open/floor");
+        let crowbar = ((1, 1, 1), "/obj/item/crowbar");
+
+        assert_eq!(checksum(&[floor, crowbar]), checksum(&[floor, crowbar]));
+        // Order, place and path all count
+        assert_ne!(checksum(&[floor, crowbar]), checksum(&[crowbar, floor]));
+        assert_ne!(checksum(&[floor]), checksum(&[((2, 1, 1), floor.1)]));
+        assert_ne!(
+            checksum(&[floor]),
+            checksum(&[((1, 1, 1), "/turf/open/floor2")])
+        );
+        // A missing atom at the end is a different load
+        assert_ne!(checksum(&[floor, crowbar]), checksum(&[floor]));
+
+        assert_eq!(LoadChecksum::default().to_string(), "cbf29ce484222325");
+    }
+
+    #[test]
+    fn test_turf_ref_index() {
+        let world_size = (10, 5, 2);
+        // (1, 1, 1) is the first turf
+        assert_eq!(turf_ref_index((1, 1, 1), world_size), Some(0));
+        assert_eq!(turf_ref_index((2, 1, 1), world_size), Some(1));
+        assert_eq!(turf_ref_index((1, 2, 1), world_size), Some(10));
+        assert_eq!(turf_ref_index((10, 5, 2), world_size), Some(99));
+        // 0 is off the map in BYOND, rather than the first tile
+        assert_eq!(turf_ref_index((0, 1, 1), world_size), None);
+        assert_eq!(turf_ref_index((11, 1, 1), world_size), None);
+        assert_eq!(turf_ref_index((1, 1, 3), world_size), None);
+    }
+
+    #[test]
+    fn test_suspicious_assoc_key() {
+        assert_eq!(suspicious_assoc_key(&Literal::String("a")), None);
+        assert_eq!(suspicious_assoc_key(&Literal::Path("/obj")), None);
+        assert_eq!(suspicious_assoc_key(&Literal::Null), Some("null"));
+        assert_eq!(
+            suspicious_assoc_key(&Literal::List(vec![Literal::Number(1.)])),
+            Some("a list")
+        );
+    }
+
+    #[test]
+    fn test_find_fallback() {
+        assert_eq!(find_fallback(&Literal::Fallback("gmddmf")), Some("gmddmf"));
+        assert_eq!(find_fallback(&Literal::String("gmddmf")), None);
+        assert_eq!(
+            find_fallback(&Literal::List(vec![
+                Literal::Number(1.),
+                Literal::Fallback("1 + 1")
+            ])),
+            Some("1 + 1")
+        );
+        // Bare keys are fine, their values aren't
+        let bare_key = |value| Literal::AssocList(vec![(Literal::Fallback("aaa"), value)]);
+        assert_eq!(find_fallback(&bare_key(Literal::Number(2.))), None);
+        assert_eq!(
+            find_fallback(&bare_key(Literal::Fallback("x y"))),
+            Some("x y")
+        );
+    }
+
+    #[test]
+    fn test_big_atom_overhang() {
+        let placed_bounds = (1, 1, 1, 10, 10, 1);
+        assert_eq!(big_atom_overhang((5, 5, 1), (2, 2), placed_bounds), None);
+        assert_eq!(big_atom_overhang((9, 10, 1), (2, 1), placed_bounds), None);
+        assert_eq!(
+            big_atom_overhang((10, 5, 1), (2, 1), placed_bounds),
+            Some((11, 5, 1))
+        );
+        assert_eq!(
+            big_atom_overhang((5, 10, 1), (1, 3), placed_bounds),
+            Some((5, 12, 1))
+        );
+        // Zero sized bounds still cover the tile they're on
+        assert_eq!(big_atom_overhang((10, 10, 1), (0, 0), placed_bounds), None);
+    }
+
+    #[test]
+    fn test_resolve_turf_ref_out_of_range() {
+        let error = resolve_turf_ref((11, 1, 1), (10, 5, 2)).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<LoadError>(),
+            Some(&LoadError::OutOfRange((11, 1, 1)))
+        );
+        assert!(resolve_turf_ref((0, 1, 1), (10, 5, 2)).is_err());
+    }
+
+    #[test]
+    fn test_command_targets() {
+        let prefab = ("/area/station", None);
+        let area = Command::CreateArea {
+            locs: vec![(1, 1, 1), (2, 1, 1)],
+            prefab: &prefab,
+            new_z: false,
+        };
+        assert!(area.targets((2, 1, 1)));
+        assert!(!area.targets((3, 1, 1)));
+
+        let atom = Command::CreateAtom {
+            loc: (3, 1, 1),
+            prefab: &prefab,
+            note: None,
+        };
+        assert!(atom.targets((3, 1, 1)));
+        assert!(!atom.targets((1, 1, 1)));
+    }
+
+    #[test]
+    fn test_command_display() {
+        let area_prefab = ("/area/station", None);
+        let area = Command::CreateArea {
+            locs: vec![(1, 1, 1), (2, 1, 1)],
+            prefab: &area_prefab,
+            new_z: false,
+        };
+        assert_eq!(
+            area.to_string(),
+            "(1, 1, 1) area /area/station over 2 tiles"
+        );
+
+        let turf_prefab = ("/turf/open/floor", None);
+        let turf = Command::CreateTurf {
+            loc: (2, 1, 1),
+            prefab: &turf_prefab,
+            no_changeturf: true,
+            place_on_top: false,
+        };
+        assert_eq!(
+            turf.to_string(),
+            "(2, 1, 1) turf /turf/open/floor no_changeturf"
+        );
+
+        let atom_prefab = ("/obj/item", None);
+        let atom = Command::CreateAtom {
+            loc: (3, 1, 1),
+            prefab: &atom_prefab,
+            note: Some("Hi"),
+        };
+        assert_eq!(atom.to_string(), "(3, 1, 1) atom /obj/item note \"Hi\"");
+    }
+
+    #[test]
+    fn test_cached_turfs_resolver() {
+        fn refuse(
+            coord: (usize, usize, usize),
+            world_bounds: (usize, usize, usize),
+        ) -> eyre::Result<TurfRef> {
+            Err(eyre::eyre!("Refused {coord:?} in {world_bounds:?}"))
+        }
+
+        let mut cached_turfs = CachedTurfs {
+            world_bounds: (10, 5, 2),
+            resolve_turf: refuse,
+            ..Default::default()
+        };
+        let error = cached_turfs.resolve_coord((1, 2, 3)).unwrap_err();
+        assert_eq!(error.to_string(), "Refused (1, 2, 3) in (10, 5, 2)");
+        assert!(cached_turfs.cache((4, 5, 6)).is_err());
+        // Failures are never cached
+        assert!(cached_turfs.cached_turfs.is_empty());
+        // But still count as having missed
+        assert_eq!(
+            cached_turfs.stats,
+            TurfCacheStats {
+                hits: 0,
+                misses: 1,
+                prewarmed: 0
+            }
+        );
+    }
+}
@@ -1,10 +1,18 @@
 //! This file is home to wrappers for BYOND-VM global procs we use to do our work.
 //! This allows for strongly typed arguments, which I found out the hard way we really need.
 
+use std::collections::HashSet;
+
 use byondapi::{global_call::call_global, value::ByondValue};
 use eyre::{Context, Result};
 use tracy_full::zone;
 
+use crate::{
+    arena::get_parsed_map,
+    error::error_code,
+    load::command_buffer::{AreaMembership, LoadChecksum, LoadTiming, TurfCacheStats},
+};
+
 /// Gets the current world.maxx, world.maxy, and world.maxz
 pub fn _bapi_helper_get_world_bounds() -> Result<(usize, usize, usize)> {
     zone!("_bapi_helper_get_world_bounds");
@@ -32,6 +40,12 @@ pub fn _bapi_helper_get_world_type_area() -> Result<String> {
         .context("Unable to get world.area")
 }
 
+/// Returns true if the turf is still in an area of type world.area, meaning nothing has assigned it one.
+pub fn _bapi_helper_turf_has_default_area(turf: ByondValue) -> Result<bool> {
+    zone!("_bapi_helper_turf_has_default_area");
+    Ok(call_global("_bapi_helper_turf_has_default_area", &[turf])?.is_true())
+}
+
 /// Tries to find an existing area instance of type `path`, and creates it if it doesn't exist.
 pub fn _bapi_create_or_get_area(path: &str) -> Result<ByondValue> {
     zone!("_bapi_create_or_get_area");
@@ -83,27 +97,68 @@ pub fn _bapi_apply_preloader(instance: ByondValue) -> Result<()> {
 }
 
 /// "Creates" a turf, really just changes whatever is at the given turf ref to what the map needs to be there.
+/// If `base_turf` is set, a turf placed on top has only that under it, instead of the turf that was there.
 pub fn _bapi_create_turf(
+    create_turf_proc: Option<&str>,
     turf: ByondValue,
     path_text: &str,
     vars_list: ByondValue,
     place_on_top: bool,
     no_changeturf: bool,
+    base_turf: Option<&str>,
 ) -> Result<ByondValue> {
     zone!("_bapi_create_turf");
     call_global(
-        "_bapi_create_turf",
+        create_turf_proc.unwrap_or("_bapi_create_turf"),
         &[
             turf,
             ByondValue::new_str(path_text)?,
             vars_list,
             ByondValue::new_num(if place_on_top { 1. } else { 0. }),
             ByondValue::new_num(if no_changeturf { 1. } else { 0. }),
+            match base_turf {
+                Some(base_turf) => ByondValue::new_str(base_turf)?,
+                None => ByondValue::null(),
+            },
         ],
     )
     .context("Failed to call bapi_create_turf")
 }
 
+/// Calls load_options.z_loaded_proc with the parsed map, once world z-level `z` is done
+pub fn _bapi_call_z_loaded_proc(proc_name: &str, parsed_map: ByondValue, z: usize) -> Result<()> {
+    zone!("_bapi_call_z_loaded_proc");
+    call_global(proc_name, &[parsed_map, ByondValue::new_num(z as f32)])
+        .with_context(|| format!("Failed to call z_loaded_proc {proc_name:#?} for z {z}"))?;
+    Ok(())
+}
+
+/// Gets how many tiles a movable's bounds cover, as (width, height)
+pub fn _bapi_helper_get_tile_extent(instance: ByondValue) -> Result<(usize, usize)> {
+    zone!("_bapi_helper_get_tile_extent");
+    let extent = call_global("_bapi_helper_get_tile_extent", &[instance])?;
+    Ok((
+        extent.read_list_index(1.)?.get_number()? as usize,
+        extent.read_list_index(2.)?.get_number()? as usize,
+    ))
+}
+
+/// The type of everything on `turf`, as text like "/obj/thing".
+pub fn _bapi_helper_get_content_types(turf: ByondValue) -> Result<HashSet<String>> {
+    zone!("_bapi_helper_get_content_types");
+    Ok(call_global("_bapi_helper_get_content_types", &[turf])?
+        .values()?
+        .map(|path| path.get_string())
+        .collect::<Result<_, _>>()?)
+}
+
+/// Calls rand_seed(), so that anything random after this repeats for the same seed
+pub fn _bapi_helper_rand_seed(seed: f32) -> Result<()> {
+    zone!("_bapi_helper_rand_seed");
+    call_global("_bapi_helper_rand_seed", &[ByondValue::new_num(seed)])?;
+    Ok(())
+}
+
 /// Calls TICK_CHECK - basically checking if the server is overrunning or about to overrun it's tick.
 pub fn _bapi_helper_tick_check() -> Result<bool> {
     zone!("_bapi_helper_tick_check");
@@ -115,18 +170,66 @@ pub fn _bapi_helper_tick_check() -> Result<bool> {
     }
 }
 
+/// How bad a warning is. Mirrors the `BAPI_SEVERITY_*` defines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Something was handled, but maybe not how the mapper intended
+    Info = 1,
+    Warning = 2,
+    /// Something in the map was not loaded
+    Error = 3,
+}
+
 /// Used to wrap calls and variable access on our /datum/bapi_parsed_map datum
 pub struct ParsedMapTranslationLayer {
     pub parsed_map: ByondValue,
 }
 
-impl ParsedMapTranslationLayer {
-    /// Add a warning for DM to see.
-    pub fn add_warning<S: Into<Vec<u8>>>(&mut self, warning: S) -> Result<()> {
-        self.parsed_map
-            .call("_bapi_add_warning", &[ByondValue::new_str(warning)?])?;
+/// Somewhere warnings about a map go. The loader only writes to this, so the parts of it that
+/// don't need BYOND can be run with a `Vec` instead.
+pub trait WarningSink {
+    /// Add a warning at `severity`.
+    fn add_warning_at<S: Into<String>>(&mut self, severity: Severity, warning: S) -> Result<()>;
+
+    /// Add a warning at [`Severity::Warning`].
+    fn add_warning<S: Into<String>>(&mut self, warning: S) -> Result<()> {
+        self.add_warning_at(Severity::Warning, warning)
+    }
+}
+
+/// Keeps every warning, in order. For headless tests.
+impl WarningSink for Vec<(Severity, String)> {
+    fn add_warning_at<S: Into<String>>(&mut self, severity: Severity, warning: S) -> Result<()> {
+        self.push((severity, warning.into()));
         Ok(())
     }
+}
+
+/// Warnings for DM to see. DM drops any below the map's warning_threshold.
+impl WarningSink for ParsedMapTranslationLayer {
+    fn add_warning_at<S: Into<String>>(&mut self, severity: Severity, warning: S) -> Result<()> {
+        self.parsed_map.call(
+            "_bapi_add_warning",
+            &[
+                ByondValue::new_str(warning.into())?,
+                ByondValue::new_num(severity as u8 as f32),
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+impl ParsedMapTranslationLayer {
+    /// Sets `last_error` to the `BAPI_ERROR_*` code of `result`'s error, or clears it if it succeeded.
+    /// Failing to write the var is ignored, so that the original error isn't lost.
+    pub fn record_error<T>(&mut self, result: Result<T>) -> Result<T> {
+        let code = match &result {
+            Ok(_) => ByondValue::null(),
+            Err(e) => ByondValue::new_str(error_code(e)).unwrap_or_default(),
+        };
+        let _ = self.parsed_map.write_var("last_error", &code);
+        result
+    }
 
     /// Expand the BYOND map to the new bounds. new_z and z_offset control whether or not it handles area contents for us.
     pub fn expand_map(
@@ -156,6 +259,20 @@ impl ParsedMapTranslationLayer {
             .context("Unable to read /datum/bapi_parsed_map/_internal_index")
     }
 
+    /// Set the _internal_index, -1 meaning there is no parsed data behind this datum.
+    pub fn set_internal_index(&mut self, index: f32) -> Result<()> {
+        self.parsed_map
+            .write_var("_internal_index", &ByondValue::new_num(index))?;
+        Ok(())
+    }
+
+    /// Whether the parsed data behind this datum was detected as TGM when it was parsed.
+    /// Unlike the map_format var, this can't have been changed from DM.
+    pub fn is_tgm(&self) -> Result<bool> {
+        let id = self.get_internal_index()? as usize;
+        Ok(unsafe { get_parsed_map(id) }?.parsed_data.0.is_tgm)
+    }
+
     /// Get the earlier-calculated key length without having to check again.
     pub fn get_key_len(&self) -> Result<f32> {
         self.parsed_map
@@ -177,14 +294,34 @@ impl ParsedMapTranslationLayer {
     }
 
     /// Set the loading var so byond knows if it can fuck with us or not. Probably irrelevant since we take control the whole time.
-    pub fn set_loading(&mut self, loading: bool) -> Result<()> {
-        self.parsed_map.write_var(
-            "loading",
-            &ByondValue::new_num(if loading { 1. } else { 0. }),
-        )?;
+    /// Counts a load as started, see [`ParsedMapTranslationLayer::end_loading`].
+    pub fn begin_loading(&mut self) -> Result<()> {
+        let loading = self.get_loading()?;
+        self.parsed_map
+            .write_var("loading", &ByondValue::new_num(loading + 1.))?;
+        Ok(())
+    }
+
+    /// Counts a load as finished. `loading` is how many loads of this datum are unfinished, so
+    /// that it only goes false once the last of them is done.
+    pub fn end_loading(&mut self) -> Result<()> {
+        let loading = self.get_loading()?;
+        self.parsed_map
+            .write_var("loading", &ByondValue::new_num((loading - 1.).max(0.)))?;
         Ok(())
     }
 
+    fn get_loading(&self) -> Result<f32> {
+        let loading = self
+            .parsed_map
+            .read_var("loading")
+            .context("Unable to read /datum/bapi_parsed_map/loading")?;
+        // Anything DM set it to that isn't a count is treated as one load
+        Ok(loading
+            .get_number()
+            .unwrap_or(if loading.is_true() { 1. } else { 0. }))
+    }
+
     /// Set the bounds list with the actual extent of the map (that is to say, shit that isn't space turfs.)
     pub fn set_bounds(&mut self, bounds: (usize, usize, usize, usize, usize, usize)) -> Result<()> {
         let new_list = ByondValue::new_list()?;
@@ -199,4 +336,64 @@ impl ParsedMapTranslationLayer {
         self.parsed_map.write_var("bounds", &new_list)?;
         Ok(())
     }
+
+    /// Set the turf_cache_stats list, see [`TurfCacheStats`].
+    pub fn set_turf_cache_stats(&mut self, stats: TurfCacheStats) -> Result<()> {
+        let mut new_list = ByondValue::new_list()?;
+        new_list.write_list_index(
+            ByondValue::new_str("hits")?,
+            ByondValue::new_num(stats.hits as f32),
+        )?;
+        new_list.write_list_index(
+            ByondValue::new_str("misses")?,
+            ByondValue::new_num(stats.misses as f32),
+        )?;
+        new_list.write_list_index(
+            ByondValue::new_str("prewarmed")?,
+            ByondValue::new_num(stats.prewarmed as f32),
+        )?;
+        self.parsed_map.write_var("turf_cache_stats", &new_list)?;
+        Ok(())
+    }
+
+    /// Set the load_timing list, once the load is done
+    pub fn set_load_timing(&mut self, timing: LoadTiming) -> Result<()> {
+        let mut new_list = ByondValue::new_list()?;
+        new_list.write_list_index(
+            ByondValue::new_str("elapsed_ms")?,
+            ByondValue::new_num(timing.elapsed.as_secs_f32() * 1000.),
+        )?;
+        new_list.write_list_index(
+            ByondValue::new_str("yields")?,
+            ByondValue::new_num(timing.yields as f32),
+        )?;
+        self.parsed_map.write_var("load_timing", &new_list)?;
+        Ok(())
+    }
+
+    /// Set the checksum once a load with load_options.checksum is done, or clear it as a load starts
+    pub fn set_checksum(&mut self, checksum: Option<LoadChecksum>) -> Result<()> {
+        let checksum = match checksum {
+            Some(checksum) => ByondValue::new_str(checksum.to_string())?,
+            None => ByondValue::null(),
+        };
+        self.parsed_map.write_var("checksum", &checksum)?;
+        Ok(())
+    }
+
+    /// Set the area_membership list, once an overlay load is done
+    pub fn set_area_membership(&mut self, membership: &AreaMembership) -> Result<()> {
+        let mut new_list = ByondValue::new_list()?;
+        for (area_path, turfs) in membership {
+            let mut turf_list = ByondValue::new_list()?;
+            for &(x, y, z) in turfs {
+                let coord = ByondValue::new_list()?;
+                coord.write_list(&[x, y, z].map(|coord| ByondValue::new_num(coord as f32)))?;
+                turf_list.push_list(coord)?;
+            }
+            new_list.write_list_index(ByondValue::new_str(area_path.as_str())?, turf_list)?;
+        }
+        self.parsed_map.write_var("area_membership", &new_list)?;
+        Ok(())
+    }
 }
@@ -1,24 +1,48 @@
 #![allow(clippy::too_many_arguments)]
 //! This is a variant of bapidmm loading where the maploading generates a list of commands,
 //! to execute separately from doing expensive operations.
+//!
+//! Setting up a load only does what has to happen before the world is touched, like expanding it.
+//! Everything per tile is left for the work loop, so that it can pause: first the map is checked
+//! (see [`Validator`]) so that problems are reported before anything is placed, then the paths and
+//! turfs it needs are looked up, and only then are the commands generated, as they're executed.
+//! See [`CommandStream`].
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    iter::{Enumerate, Peekable},
+};
 
 use byondapi::prelude::*;
-use eyre::eyre;
+use dmm_lite::{
+    block::{block_overlaps, Block},
+    diff::{map_diff, TileChange},
+    prefabs::{duplicate_vars, prefab_notes, Prefab, Prefabs},
+    stats::map_statistics,
+};
 use tracy_full::{frame, zone};
 
 use crate::{
     _compat::setup_panic_handler,
-    arena::ArenaMap,
+    arena::{get_parsed_map, ArenaMap, PARSED_MAPS_ARENABASED},
+    error::{is_fatal, LoadError},
     load::{
-        command_buffer::{Command, CommandBuffer},
+        command_buffer::{
+            run_command, turf_ref_index, AreaMembership, CachedTurfs, Command, CommandBuffer,
+            CommandContext, LoadChecksum, LoadWorld,
+        },
         helpers::{
-            ParsedMapTranslationLayer, _bapi_helper_get_world_bounds,
-            _bapi_helper_get_world_type_area, _bapi_helper_get_world_type_turf,
+            _bapi_helper_get_world_bounds, _bapi_helper_get_world_type_area,
+            _bapi_helper_get_world_type_turf, ParsedMapTranslationLayer, Severity, WarningSink,
         },
+        options::{LoadOptions, Rotation},
     },
-    PARSED_MAPS_ARENABASED,
+    ResumeKey,
 };
 
+/// Sets up a load of `parsed_map` and returns the resume key to work it with, see
+/// `_bapidmm_work_commandbuffer`. The map itself isn't checked until the first work call, so a
+/// map that can't be loaded still gets a resume key here, and it's that call that fails.
 #[byondapi::bind]
 pub fn _bapidmm_load_map_buffered(
     parsed_map: ByondValue,
@@ -35,6 +59,7 @@ pub fn _bapidmm_load_map_buffered(
     z_upper: ByondValue,
     place_on_top: ByondValue,
     new_z: ByondValue,
+    load_options: ByondValue,
 ) {
     setup_panic_handler();
     let mut parsed_map = ParsedMapTranslationLayer { parsed_map };
@@ -53,11 +78,29 @@ pub fn _bapidmm_load_map_buffered(
     let place_on_top = place_on_top.get_bool()?;
     let new_z = new_z.get_bool()?;
 
-    let internal_data = unsafe { PARSED_MAPS_ARENABASED.get_mut() }
-        .get_mut(id as usize)
-        .ok_or_else(|| eyre!("Bad internal index {id:#?}"))?;
+    let setup = LoadOptions::from_byond(load_options).and_then(|load_options| {
+        // Before the parsed map is borrowed, as this needs both versions of it at once
+        let diff = match load_options.diff_against {
+            Some(old) => Some(unsafe { diff_tiles(old, id as usize) }?),
+            None => None,
+        };
+        let internal_data = unsafe { get_parsed_map(id as usize) }?;
+        if internal_data.working.is_some() {
+            return Err(LoadError::Busy(id as usize).into());
+        }
+        Ok((load_options, diff, internal_data))
+    });
+    let (load_options, diff, internal_data) = match setup {
+        Ok(setup) => setup,
+        Err(e) => return parsed_map.record_error(Err(e)),
+    };
 
-    parsed_map.set_loading(true)?;
+    let manages_loading = !load_options.manual_loading;
+    if manages_loading {
+        parsed_map.begin_loading()?;
+    }
+    // Whatever an earlier load left there isn't this load's
+    parsed_map.set_checksum(None)?;
 
     // Load map
     let ret = match generate_command_buffer(
@@ -70,20 +113,47 @@ pub fn _bapidmm_load_map_buffered(
         (x_upper, y_upper, z_upper),
         place_on_top,
         new_z,
+        load_options,
+        diff,
     ) {
         Ok(val) => Ok(val),
         Err(e) => {
-            parsed_map.add_warning(format!("Loading failed due to error: {e:#}"))?;
+            parsed_map.add_warning_at(
+                Severity::Error,
+                format!("Loading failed due to error: {e:#}"),
+            )?;
+            // There's no command buffer to finish it off
+            if manages_loading {
+                parsed_map.end_loading()?;
+            }
             Err(e)
         }
     };
 
     frame!();
-    ret
+    parsed_map.record_error(ret)
 }
 
-/// if you generate usize::MAX command buffers in one round I can't help you I'm sorry
-static mut COMMAND_BUFFER_ID: usize = 0;
+/// The last resume key handed out, see [`next_resume_key`]
+static mut COMMAND_BUFFER_ID: ResumeKey = 0;
+
+/// Resume keys go to DM as numbers, so they have to stay exactly representable as one
+const MAX_RESUME_KEY: ResumeKey = 1 << 24;
+
+/// The key after `last` that none of `command_buffers` are using.
+///
+/// Keys count up from 1, since DM treats 0 as the load failing, and wrap around after
+/// [`MAX_RESUME_KEY`]. A key that's still taken by an unfinished load is skipped rather than
+/// replacing its command buffer, so a stale key can never resume someone else's load.
+fn next_resume_key<T>(last: ResumeKey, command_buffers: &HashMap<ResumeKey, T>) -> ResumeKey {
+    let mut key = last;
+    loop {
+        key = if key >= MAX_RESUME_KEY { 1 } else { key + 1 };
+        if !command_buffers.contains_key(&key) {
+            return key;
+        }
+    }
+}
 
 fn generate_command_buffer<'a>(
     parsed_map: &mut ParsedMapTranslationLayer,
@@ -96,60 +166,110 @@ fn generate_command_buffer<'a>(
     upper_bounds: (f32, f32, f32),
     place_on_top: bool,
     new_z: bool,
+    load_options: LoadOptions,
+    diff: Option<TileDiff>,
 ) -> eyre::Result<ByondValue> {
-    // Safety: only ever called on main thread by BYOND
-    unsafe { COMMAND_BUFFER_ID += 1 };
     zone!("generate_command_buffer");
 
     let (_metadata, (prefabs, blocks)) = &internal_data.parsed_data;
+    let notes = if load_options.note_vars.is_empty() {
+        HashMap::new()
+    } else {
+        prefab_notes(internal_data.text)
+    };
     let command_buffers = &mut internal_data.command_buffers;
-    let resume_key = unsafe { COMMAND_BUFFER_ID };
-
-    let mut our_command_buffer = CommandBuffer::default();
+    // Safety: only ever called on main thread by BYOND
+    let resume_key = next_resume_key(unsafe { COMMAND_BUFFER_ID }, command_buffers);
+    unsafe { COMMAND_BUFFER_ID = resume_key };
 
     let key_len = parsed_map.get_key_len()?;
     let parsed_bounds = parsed_map.get_parsed_bounds()?;
+    // What the offset places, which is a different shape once a quarter turn swaps the sides
+    let turned_bounds = load_options.rotation.rotate_bounds(parsed_bounds);
+    let mut offset = load_options
+        .centered_offset(turned_bounds)?
+        .unwrap_or(offset);
     let world_bounds = _bapi_helper_get_world_bounds()?;
-    our_command_buffer.cached_turfs.world_bounds = world_bounds;
+    if let Some(clamped) = load_options.clamped_offset(offset, turned_bounds, world_bounds) {
+        parsed_map.add_warning(format!(
+            "Moved the map from {offset:?} to {clamped:?} to fit it in the world"
+        ))?;
+        offset = clamped;
+    }
+    if load_options.clamp_to_world
+        && (turned_bounds.3 > world_bounds.0 || turned_bounds.4 > world_bounds.1)
+    {
+        parsed_map.add_warning(format!(
+            "The map is bigger than the world, so nothing past ({}, {}) is placed",
+            world_bounds.0, world_bounds.1
+        ))?;
+    }
+    let mut cached_turfs = CachedTurfs {
+        world_bounds,
+        ..Default::default()
+    };
+
+    load_options.check_z_levels(parsed_bounds.2..=parsed_bounds.5, world_bounds.2)?;
+    if load_options.max_tiles.is_some() || load_options.max_atoms.is_some() {
+        // Before the world is touched, so that an oversized map doesn't even expand it
+        let counts = map_statistics(prefabs, blocks, &[]);
+        load_options.check_budget(counts.tiles, counts.atoms)?;
+    }
+    // Remapped z-levels are checked to already exist, so they never expand the world
+    let max_z = match load_options.z_levels {
+        Some(_) => world_bounds.2,
+        None => offset.2 as usize + parsed_bounds.5 - 1,
+    };
 
     // Expand map if necessary
     if !crop_map {
-        let max_extent_offset = (
-            offset.0 as usize + parsed_bounds.3 - 1,
-            offset.1 as usize + parsed_bounds.4 - 1,
-            offset.2 as usize + parsed_bounds.5 - 1,
+        let mut max_extent_offset = (
+            offset.0 as usize + turned_bounds.3 - 1,
+            offset.1 as usize + turned_bounds.4 - 1,
+            max_z,
         );
+        // Only z-levels are added, what doesn't fit in x and y is cropped instead
+        if load_options.clamp_to_world {
+            max_extent_offset.0 = max_extent_offset.0.min(world_bounds.0);
+            max_extent_offset.1 = max_extent_offset.1.min(world_bounds.1);
+        }
         if exceeds_upper_bounds(max_extent_offset, world_bounds) && !crop_map {
             parsed_map.expand_map(max_extent_offset, new_z, offset.2)?;
-            our_command_buffer.cached_turfs.world_bounds = max_extent_offset;
+            cached_turfs.world_bounds = max_extent_offset;
         }
     }
 
+    check_world_bounds(cached_turfs.world_bounds)?;
+
     let world_turf = _bapi_helper_get_world_type_turf()?;
     let world_area = _bapi_helper_get_world_type_area()?;
 
     let space_key: Option<&str> = if no_changeturf {
-        prefabs.iter().find_map(|(key, prefab_list)| {
-            if prefab_list.len() != 2 {
-                return None;
-            }
-            match prefab_list[0] {
-                (turf, None) if turf == world_turf => {}
-                _ => return None,
-            }
-            match prefab_list[1] {
-                (area, None) if area == world_area => {}
-                _ => return None,
-            }
-            Some(*key)
-        })
+        find_space_key(prefabs, &world_turf, &world_area)
     } else {
         None
     };
 
+    let blank_row_key = if blocks.iter().any(|(_, lines)| lines.contains(&"")) {
+        let key = find_space_key(prefabs, &world_turf, &world_area);
+        if key.is_none() {
+            parsed_map.add_warning("The map has blank rows, but no prefab of only world.turf and world.area to fill them with, so they were left empty")?;
+        }
+        key
+    } else {
+        None
+    };
+
+    let only_tiles = diff.map(|(changed, removed)| {
+        if removed > 0 {
+            parsed_map.add_warning(format!("{removed} tiles of the earlier version of the map aren't in this one, and were left as they were"))?;
+        }
+        eyre::Ok(changed)
+    }).transpose()?;
+
     // We know bounds ahead of time so we
     let mut no_afterchange = no_changeturf;
-    if parsed_bounds.5 + (offset.2 as usize) - 1 > world_bounds.2 {
+    if max_z > world_bounds.2 {
         // z expansion
         if !no_changeturf {
             parsed_map.add_warning("Z-level expansion occurred without no_changeturf set, this may cause problems when /turf/AfterChange is called, and therefore ChangeTurf will NOT be called")?;
@@ -157,155 +277,925 @@ fn generate_command_buffer<'a>(
         }
     }
 
+    let settings = TileSettings {
+        key_len: key_len as usize,
+        offset,
+        crop_map: crop_map || load_options.clamp_to_world,
+        lower_bounds,
+        upper_bounds,
+        world_bounds: cached_turfs.world_bounds,
+        space_key,
+        blank_row_key,
+        no_afterchange,
+        place_on_top,
+        new_z,
+        notes,
+        only_tiles,
+        parsed_bounds,
+        flip_y: load_options.flip_y,
+        rotation: load_options.rotation,
+    };
+
+    let our_command_buffer = CommandBuffer {
+        created_areas: HashMap::new(),
+        known_types: HashMap::new(),
+        cached_turfs,
+        validator: Some(Validator::new(blocks, &settings)),
+        movable_paths: VecDeque::new(),
+        prewarm: VecDeque::new(),
+        commands: CommandStream::new(prefabs, blocks, settings, HashSet::new()),
+        checksum: load_options.checksum.then(LoadChecksum::default),
+        remaining: None,
+        remaining_at: None,
+        // Some options are still needed while executing the commands
+        options: load_options,
+        placed_bounds: Default::default(),
+        additive_tile: None,
+        timing: Default::default(),
+        z_last_tiles: VecDeque::new(),
+        paused: false,
+        area_membership: AreaMembership::new(),
+    };
+
+    #[cfg(feature = "dump")]
+    let _ = std::fs::write(
+        format!("data/mapdump_{}_{}", _metadata.name, resume_key),
+        format!("{:#?}", &our_command_buffer),
+    );
+    #[cfg(feature = "dump")]
+    let _ = std::fs::write(
+        format!("data/mapdump_{}_{}_commands", _metadata.name, resume_key),
+        crate::load::command_buffer::dump_commands(&our_command_buffer),
+    );
+    let replaced = command_buffers.insert(resume_key, our_command_buffer);
+    debug_assert!(replaced.is_none(), "resume key {resume_key} was in use");
+
+    Ok(ByondValue::new_num(resume_key as f32))
+}
+
+/// What the validation pass found out about a map, see [`Validator`]
+#[derive(Debug)]
+pub struct Validation<'s> {
+    /// (minx, miny, minz, maxx, maxy, maxz) of every tile something is placed on, starting at (1, 1, 1)
+    pub bounds: (usize, usize, usize, usize, usize, usize),
+    /// Every tile something is placed on, for the work loop to look up first
+    pub prewarm: VecDeque<(usize, usize, usize)>,
+    /// (last tile, z) of every z-level, in the order they finish loading
+    pub z_last_tiles: VecDeque<(usize, usize)>,
+    /// Every movable path placed and how many times, in the order they're first placed
+    pub movable_paths: VecDeque<(&'s str, usize)>,
+}
+
+/// (prefab key, path, var) -> (first coord it was placed at, number of times placed)
+type UnknownVars<'s> = BTreeMap<(&'s str, &'s str, &'s str), ((usize, usize, usize), usize)>;
+
+/// Plans every tile of the map once, reporting anything wrong with it to `warnings`, without
+/// touching BYOND. The commands are thrown away, [`CommandStream`] generates them again later.
+///
+/// This goes a tile at a time with [`Validator::step`], so that the work loop can pause partway
+/// through a huge map, the same as it does while placing it.
+#[derive(Debug)]
+pub struct Validator<'s> {
+    blocks: &'s [Block<'s>],
+    tiles: Enumerate<Tiles<'s>>,
+    /// Block overlaps are reported by the first step
+    started: bool,
     // (minx, miny, minz, maxx, maxy, maxz)
     // starts at (1, 1, 1)
-    let mut bounds = (usize::MAX, usize::MAX, usize::MAX, 1, 1, 1);
-
-    for (bottom_left, block) in blocks {
-        // We have to reverse and THEN enumerate this to translate from
-        // origin TOP left to origin BOTTOM left
-        // and then reverse it again to do the correct iteration order
-        for (map_y_offset, line) in block.iter().rev().enumerate().rev() {
-            let turfs = separate_turfs(line, key_len as usize);
-            for (map_x_offset, prefab_key) in turfs.enumerate() {
-                let relative_coord = (
-                    bottom_left.0 + map_x_offset,
-                    bottom_left.1 + map_y_offset,
-                    bottom_left.2,
-                );
+    bounds: (usize, usize, usize, usize, usize, usize),
+    /// Collected so that a common prefab doesn't produce a warning for every tile it's on
+    unknown_vars: UnknownVars<'s>,
+    movable_paths: VecDeque<(&'s str, usize)>,
+    /// Path -> where it is in `movable_paths`
+    seen_movable_paths: HashMap<&'s str, usize>,
+    /// Prefabs already checked for duplicate vars, so that each is only reported once
+    checked_prefabs: HashSet<&'s str>,
+    /// The commands planned for the current tile, only kept around to reuse the allocation
+    tile_commands: VecDeque<Command<'s>>,
+    prewarm: VecDeque<(usize, usize, usize)>,
+    /// World z -> the last tile placed on it, counting tiles in load order
+    z_last_tiles: HashMap<usize, usize>,
+}
 
-                // Skip anything outside of our relative bounds
-                if float_exceeds_upper_bounds(relative_coord, upper_bounds) {
-                    continue;
-                }
-                // for some reason, negative bounds are permitted?
-                if float_exceeds_lower_bounds(relative_coord, lower_bounds) {
-                    continue;
-                }
+impl<'s> Validator<'s> {
+    pub fn new(blocks: &'s [Block<'s>], settings: &TileSettings<'s>) -> Self {
+        Validator {
+            blocks,
+            tiles: Tiles::new(blocks, settings.key_len)
+                .fill_blank_rows(settings.blank_row_key)
+                .enumerate(),
+            started: false,
+            bounds: (usize::MAX, usize::MAX, usize::MAX, 1, 1, 1),
+            unknown_vars: BTreeMap::new(),
+            movable_paths: VecDeque::new(),
+            seen_movable_paths: HashMap::new(),
+            checked_prefabs: HashSet::new(),
+            tile_commands: VecDeque::new(),
+            prewarm: VecDeque::new(),
+            z_last_tiles: HashMap::new(),
+        }
+    }
 
-                // Calculate absolute position
-                // This is offset - 1 because (1,1,1) actually goes *at* offset
-                let exact_coord = (
-                    relative_coord.0 + offset.0 as usize - 1,
-                    relative_coord.1 + offset.1 as usize - 1,
-                    relative_coord.2 + offset.2 as usize - 1,
-                );
+    /// Validates the next tile. Returns false once there are none left, and it's time to [`Validator::finish`].
+    pub fn step(
+        &mut self,
+        prefabs: &'s Prefabs<'s>,
+        settings: &TileSettings<'s>,
+        load_options: &LoadOptions,
+        warnings: &mut impl WarningSink,
+    ) -> eyre::Result<bool> {
+        if !self.started {
+            self.started = true;
+            for (first, second, (min, max)) in block_overlaps(self.blocks, settings.key_len) {
+                warnings.add_warning(format!(
+                    "Blocks at {:?} and {:?} overlap from {min:?} to {max:?}, the later one is loaded there",
+                    self.blocks[first].0, self.blocks[second].0
+                ))?;
+            }
+        }
 
-                // This will just guaranteed fail to locate a turf
-                if exceeds_lower_bounds(exact_coord, (1, 1, 1)) {
-                    parsed_map.add_warning(format!(
-                        "Bad map coord (tries to spawn in negative space): {exact_coord:#?}"
+        let Some((tile, (relative_coord, prefab_key))) = self.tiles.next() else {
+            return Ok(false);
+        };
+        let placed = settings.plan_tile(
+            prefabs,
+            load_options,
+            relative_coord,
+            prefab_key,
+            &mut self.tile_commands,
+            warnings,
+        );
+        let Some(exact_coord) = placed? else {
+            self.tile_commands.clear();
+            return Ok(true);
+        };
+
+        self.prewarm.push_back(exact_coord);
+        if load_options.z_loaded_proc.is_some() {
+            self.z_last_tiles.insert(exact_coord.2, tile);
+        }
+
+        // This is the point where we are committed, we are GOING to put something at this coord
+        // Accordingly, this is where we calculate bounds
+        let bounds = &mut self.bounds;
+        bounds.0 = bounds.0.min(exact_coord.0);
+        bounds.1 = bounds.1.min(exact_coord.1);
+        bounds.2 = bounds.2.min(exact_coord.2);
+        bounds.3 = bounds.3.max(exact_coord.0);
+        bounds.4 = bounds.4.max(exact_coord.1);
+        bounds.5 = bounds.5.max(exact_coord.2);
+
+        if self.checked_prefabs.insert(prefab_key) {
+            for (path, vars) in &prefabs[prefab_key] {
+                for var in duplicate_vars(vars.as_deref().unwrap_or_default()) {
+                    warnings.add_warning(format!(
+                        "Prefab {prefab_key:#?} sets {var:#?} on {path} more than once, first placed at {exact_coord:?}. The last one is used"
                     ))?;
-                    continue;
                 }
+            }
+        }
 
-                // Avoid generating OOB commands
-                if exceeds_upper_bounds(exact_coord, world_bounds) && crop_map {
-                    continue;
+        if let Some(whitelist) = &load_options.var_whitelist {
+            for (path, vars) in &prefabs[prefab_key] {
+                for (var, _) in vars.iter().flatten() {
+                    if !whitelist.allows(path, var) {
+                        self.unknown_vars
+                            .entry((prefab_key, *path, *var))
+                            .or_insert((exact_coord, 0))
+                            .1 += 1;
+                    }
                 }
+            }
+        }
 
-                if Some(prefab_key) == space_key && no_afterchange {
-                    continue;
+        for command in self.tile_commands.drain(..) {
+            if let Command::CreateAtom { prefab, .. } = command {
+                let index = *self.seen_movable_paths.entry(prefab.0).or_insert_with(|| {
+                    self.movable_paths.push_back((prefab.0, 0));
+                    self.movable_paths.len() - 1
+                });
+                self.movable_paths[index].1 += 1;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reports what's only known once every tile has been seen.
+    pub fn finish(self, warnings: &mut impl WarningSink) -> eyre::Result<Validation<'s>> {
+        for ((prefab_key, path, var), (coord, count)) in self.unknown_vars {
+            let others = match count {
+                1 => String::new(),
+                count => format!(" and {} other tiles", count - 1),
+            };
+            warnings.add_warning(format!(
+                "Prefab {prefab_key:#?} sets unknown var {var:#?} on {path}, at {coord:?}{others}"
+            ))?;
+        }
+
+        Ok(Validation {
+            bounds: self.bounds,
+            prewarm: self.prewarm,
+            z_last_tiles: sorted_z_last_tiles(self.z_last_tiles),
+            movable_paths: self.movable_paths,
+        })
+    }
+}
+
+/// Runs a [`Validator`] over the whole map at once.
+fn validate_tiles<'s>(
+    prefabs: &'s Prefabs<'s>,
+    blocks: &'s [Block<'s>],
+    settings: &TileSettings<'s>,
+    load_options: &LoadOptions,
+    warnings: &mut impl WarningSink,
+) -> eyre::Result<Validation<'s>> {
+    zone!("validate_tiles");
+    let mut validator = Validator::new(blocks, settings);
+    while validator.step(prefabs, settings, load_options, warnings)? {}
+    validator.finish(warnings)
+}
+
+/// What loading a map would do, see [`simulate_load`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoadReport {
+    /// Tiles given an area
+    pub areas: usize,
+    pub turfs: usize,
+    pub atoms: usize,
+    /// (minx, miny, minz, maxx, maxy, maxz) of every tile something is placed on, or None if
+    /// nothing is placed at all
+    pub bounds: Option<(usize, usize, usize, usize, usize, usize)>,
+    /// Everything that loading would report, in order
+    pub warnings: Vec<(Severity, String)>,
+}
+
+/// Runs everything that loading a map does without BYOND: validating the map, then running every
+/// command the work loop would against a [`SimulatedWorld`], which counts what they place.
+///
+/// `settings` stands in for the arguments of the load proc and the world it loads into, and
+/// `path_exists` for looking up movable types, which skips the same movables a real load would.
+pub fn simulate_load<'s>(
+    prefabs: &'s Prefabs<'s>,
+    blocks: &'s [Block<'s>],
+    settings: TileSettings<'s>,
+    load_options: &LoadOptions,
+    path_exists: impl Fn(&str) -> bool,
+) -> eyre::Result<LoadReport> {
+    let mut world = SimulatedWorld {
+        world_bounds: settings.world_bounds,
+        ..Default::default()
+    };
+    let validation = validate_tiles(prefabs, blocks, &settings, load_options, &mut world)?;
+
+    let mut bad_paths = HashSet::new();
+    for (path_text, _) in validation.movable_paths {
+        if !path_exists(path_text) {
+            world.add_warning_at(Severity::Error, format!("Bad path {path_text:#?}"))?;
+            bad_paths.insert(path_text);
+        }
+    }
+
+    let mut context = CommandContext {
+        options: load_options,
+        placed_bounds: validation.bounds,
+        additive_tile: &mut None,
+        area_membership: &mut AreaMembership::new(),
+        checksum: &mut load_options.checksum.then(LoadChecksum::default),
+    };
+    let mut commands = CommandStream::new(prefabs, blocks, settings, bad_paths);
+    while let Some(command) = commands.next_command(load_options) {
+        let (loc, path) = (command.loc(), command.prefab().0);
+        if let Err(e) = run_command(&mut world, &mut context, command) {
+            if load_options.strict || is_fatal(&e) {
+                return Err(e);
+            }
+            world.add_warning_at(
+                Severity::Error,
+                format!("Failed to place {path:#?} at {loc:?}: {e:#}"),
+            )?;
+        }
+    }
+
+    Ok(LoadReport {
+        areas: world.areas.len(),
+        turfs: world.turfs.len(),
+        atoms: world.atoms,
+        bounds: (!validation.prewarm.is_empty()).then_some(validation.bounds),
+        warnings: world.warnings,
+    })
+}
+
+/// Stands in for BYOND in [`simulate_load`]: An empty world that only keeps count of what's
+/// placed in it. Turfs are their coordinates.
+#[derive(Debug, Default)]
+pub struct SimulatedWorld {
+    pub world_bounds: (usize, usize, usize),
+    /// Every tile given an area
+    pub areas: HashSet<(usize, usize, usize)>,
+    /// Every tile given a turf
+    pub turfs: HashSet<(usize, usize, usize)>,
+    pub atoms: usize,
+    pub warnings: Vec<(Severity, String)>,
+}
+
+impl WarningSink for SimulatedWorld {
+    fn add_warning_at<S: Into<String>>(
+        &mut self,
+        severity: Severity,
+        warning: S,
+    ) -> eyre::Result<()> {
+        self.warnings.add_warning_at(severity, warning)
+    }
+}
+
+impl<'s> LoadWorld<'s> for SimulatedWorld {
+    type Turf = (usize, usize, usize);
+    type Area = ();
+    type Movable = ();
+
+    fn resolve_turf(
+        &mut self,
+        loc: (usize, usize, usize),
+    ) -> eyre::Result<Option<(usize, usize, usize)>> {
+        turf_ref_index(loc, self.world_bounds).ok_or(LoadError::OutOfRange(loc))?;
+        Ok(Some(loc))
+    }
+
+    fn area(&mut self, _path: &'s str, _area_path: &str) -> eyre::Result<()> {
+        Ok(())
+    }
+
+    fn has_default_area(&mut self, turf: (usize, usize, usize)) -> eyre::Result<bool> {
+        Ok(!self.areas.contains(&turf))
+    }
+
+    fn add_to_area(
+        &mut self,
+        turf: (usize, usize, usize),
+        _area: (),
+        _contain: bool,
+    ) -> eyre::Result<()> {
+        self.areas.insert(turf);
+        Ok(())
+    }
+
+    fn create_turf(
+        &mut self,
+        _options: &LoadOptions,
+        turf: (usize, usize, usize),
+        _loc: (usize, usize, usize),
+        _prefab: &'s Prefab<'s>,
+        _place_on_top: bool,
+        _no_changeturf: bool,
+    ) -> eyre::Result<()> {
+        self.turfs.insert(turf);
+        Ok(())
+    }
+
+    fn create_movable(
+        &mut self,
+        _options: &LoadOptions,
+        _turf: (usize, usize, usize),
+        _loc: (usize, usize, usize),
+        _prefab: &'s Prefab<'s>,
+        _note: Option<&str>,
+    ) -> eyre::Result<Option<()>> {
+        self.atoms += 1;
+        Ok(Some(()))
+    }
+
+    fn content_types(&mut self, _turf: (usize, usize, usize)) -> eyre::Result<HashSet<String>> {
+        Ok(HashSet::new())
+    }
+
+    /// There's no BYOND to ask, so everything is one tile
+    fn tile_extent(&mut self, _movable: ()) -> eyre::Result<(usize, usize)> {
+        Ok((1, 1))
+    }
+}
+
+/// Tiles to load and how many were removed, see [`diff_tiles`]
+type TileDiff = (HashSet<(usize, usize, usize)>, usize);
+
+/// The coordinates (relative to the map) of every tile of the parsed map `new` that differs from
+/// the parsed map `old`, and how many tiles only `old` has. See [`LoadOptions::diff_against`].
+///
+/// # Safety
+///
+/// Same as [`get_parsed_map`].
+unsafe fn diff_tiles(old: usize, new: usize) -> eyre::Result<TileDiff> {
+    let maps = &*PARSED_MAPS_ARENABASED.get_mut();
+    let map_data = |id: usize| {
+        maps.get(id)
+            .and_then(Option::as_ref)
+            .map(|map| &map.parsed_data.1)
+            .ok_or(LoadError::BadInternalIndex(id))
+    };
+    let (old, new) = (map_data(old)?, map_data(new)?);
+
+    let mut changed = HashSet::new();
+    let mut removed = 0;
+    for (coord, change) in map_diff(old, new) {
+        match change {
+            TileChange::Removed(_) => removed += 1,
+            TileChange::Added(_) | TileChange::Changed(_) => {
+                changed.insert(coord);
+            }
+        }
+    }
+    Ok((changed, removed))
+}
+
+/// Errors if the world has no turfs at all, since every tile would fail to resolve.
+fn check_world_bounds(world_bounds: (usize, usize, usize)) -> eyre::Result<()> {
+    if world_bounds.0 == 0 || world_bounds.1 == 0 || world_bounds.2 == 0 {
+        return Err(LoadError::EmptyWorld(world_bounds).into());
+    }
+    Ok(())
+}
+
+/// (tile, z) for each z-level, in the order they finish loading.
+fn sorted_z_last_tiles(z_last_tiles: HashMap<usize, usize>) -> VecDeque<(usize, usize)> {
+    let mut sorted: Vec<_> = z_last_tiles
+        .into_iter()
+        .map(|(z, tile)| (tile, z))
+        .collect();
+    sorted.sort_unstable();
+    sorted.into()
+}
+
+/// The prefab that's only world.turf and world.area, with no vars, which is what empty space is.
+fn find_space_key<'s>(
+    prefabs: &'s Prefabs<'s>,
+    world_turf: &str,
+    world_area: &str,
+) -> Option<&'s str> {
+    prefabs.iter().find_map(|(key, prefab_list)| {
+        if prefab_list.len() != 2 {
+            return None;
+        }
+        match prefab_list[0] {
+            (turf, None) if turf == world_turf => {}
+            _ => return None,
+        }
+        match prefab_list[1] {
+            (area, None) if area == world_area => {}
+            _ => return None,
+        }
+        Some(*key)
+    })
+}
+
+/// Every tile of a map in load order: blocks in order, each one top to bottom, left to right.
+/// Yields the tile's coordinate relative to the map (starting at (1, 1, 1)) and its prefab key.
+#[derive(Debug, Clone)]
+pub struct Tiles<'s> {
+    blocks: &'s [Block<'s>],
+    key_len: usize,
+    block: usize,
+    /// Counted from the top of the block
+    row: usize,
+    column: usize,
+    /// Empty rows are yielded as a full row of this key, see [`Tiles::fill_blank_rows`]
+    blank_row_key: Option<&'s str>,
+}
+
+impl<'s> Tiles<'s> {
+    pub fn new(blocks: &'s [Block<'s>], key_len: usize) -> Self {
+        Tiles {
+            blocks,
+            key_len,
+            block: 0,
+            row: 0,
+            column: 0,
+            blank_row_key: None,
+        }
+    }
+
+    /// Blocks only have empty rows if the map was parsed with `blank_rows`. Without a key to fill
+    /// them with, nothing is placed there.
+    pub fn fill_blank_rows(mut self, key: Option<&'s str>) -> Self {
+        self.blank_row_key = key;
+        self
+    }
+}
+
+impl<'s> Iterator for Tiles<'s> {
+    type Item = ((usize, usize, usize), &'s str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.key_len == 0 {
+            return None;
+        }
+        loop {
+            let (bottom_left, lines) = self.blocks.get(self.block)?;
+            let Some(line) = lines.get(self.row) else {
+                self.block += 1;
+                self.row = 0;
+                continue;
+            };
+            let start = self.column * self.key_len;
+            let prefab_key = match self.blank_row_key {
+                // As wide as the widest row of the block
+                Some(key) if line.is_empty() => {
+                    let width = lines.iter().map(|line| line.len() / self.key_len).max();
+                    (Some(self.column) < width).then_some(key)
                 }
+                // A short key at the end of a line is yielded as is, and reported as an invalid key
+                _ => (start < line.len())
+                    .then(|| &line[start..(start + self.key_len).min(line.len())]),
+            };
+            let Some(prefab_key) = prefab_key else {
+                self.row += 1;
+                self.column = 0;
+                continue;
+            };
+            let relative_coord = (
+                bottom_left.0 + self.column,
+                // Rows are listed top to bottom, so the last row is at the block's y
+                bottom_left.1 + (lines.len() - 1 - self.row),
+                bottom_left.2,
+            );
+            self.column += 1;
+            return Some((relative_coord, prefab_key));
+        }
+    }
+}
 
-                if let Some(prefab) = prefabs.get(prefab_key) {
-                    // DMM prefab require that all prefab lists end with one /turf, and then one /area.
-                    if prefab.len() < 2 {
-                        parsed_map.add_warning(format!(
-                                "Prefab {prefab_key:#?} is too short, violating requirement for /turf and /area!"
-                            ))?;
-                        continue;
-                    }
+/// Everything fixed at the start of loading that decides what a tile turns into.
+#[derive(Debug, Clone)]
+pub struct TileSettings<'s> {
+    pub key_len: usize,
+    pub offset: (f32, f32, f32),
+    pub crop_map: bool,
+    // These MUST be f32 because they can be INFINITY
+    pub lower_bounds: (f32, f32, f32),
+    pub upper_bounds: (f32, f32, f32),
+    /// The size of the world, after expanding it to fit the map
+    pub world_bounds: (usize, usize, usize),
+    /// Tiles with this key are skipped, see `no_changeturf`
+    pub space_key: Option<&'s str>,
+    /// Blank rows, from parsing with `blank_rows_as_space`, are filled with this key
+    pub blank_row_key: Option<&'s str>,
+    pub no_afterchange: bool,
+    pub place_on_top: bool,
+    pub new_z: bool,
+    /// Prefab key -> `// @note`, only found if [`LoadOptions::note_vars`] is set
+    pub notes: HashMap<&'s str, &'s str>,
+    /// If set, tiles at any other coordinate (relative to the map) are skipped, see [`LoadOptions::diff_against`]
+    pub only_tiles: Option<HashSet<(usize, usize, usize)>>,
+    /// (minx, miny, minz, maxx, maxy, maxz) of the map, which is what it's flipped and rotated inside of
+    pub parsed_bounds: (usize, usize, usize, usize, usize, usize),
+    /// See [`LoadOptions::flip_y`]
+    pub flip_y: bool,
+    pub rotation: Rotation,
+}
 
-                    // This is the point where we are committed, we are GOING to put something at this coord
-                    // Accordingly, this is where we calculate bounds
-                    bounds.0 = bounds.0.min(exact_coord.0);
-                    bounds.1 = bounds.1.min(exact_coord.1);
-                    bounds.2 = bounds.2.min(exact_coord.2);
-                    bounds.3 = bounds.3.max(exact_coord.0);
-                    bounds.4 = bounds.4.max(exact_coord.1);
-                    bounds.5 = bounds.5.max(exact_coord.2);
-
-                    our_command_buffer.cached_turfs.cache(exact_coord)?;
-
-                    let mut prefab_list = prefab.iter().rev();
-                    // Above check ensures that these cannot panic
-                    let prefab_area = prefab_list.next().unwrap();
-                    if !prefab_area.0.starts_with("/area") {
-                        parsed_map.add_warning(format!(
-                            "Prefab {prefab_key:#?} does not end in an area, instead ending in {prefab_area:#?}!"
-                        ))?;
-                        continue;
-                    }
-                    if !prefab_area.0.starts_with("/area/template_noop") {
-                        zone!("generating CreateArea");
-                        our_command_buffer.commands.push_back(Command::CreateArea {
-                            loc: exact_coord,
-                            prefab: prefab_area,
-                            new_z,
-                        });
-                    }
+impl<'s> TileSettings<'s> {
+    /// Where a tile of the map goes once it's been flipped and rotated, still relative to the map.
+    /// See [`Rotation`] for the order everything is done in. None if it can't be turned, see
+    /// [`Rotation::rotate`].
+    pub fn turn(&self, relative_coord: (usize, usize, usize)) -> Option<(usize, usize, usize)> {
+        let bounds = self.parsed_bounds;
+        let flipped = if self.flip_y {
+            // Mirrored in place, as the lowest and highest rows swap
+            (
+                relative_coord.0,
+                (bounds.1 + bounds.4).checked_sub(relative_coord.1)?,
+                relative_coord.2,
+            )
+        } else {
+            relative_coord
+        };
+        self.rotation.rotate(flipped, bounds)
+    }
 
-                    let prefab_turf = prefab_list.next().unwrap();
-                    if !prefab_turf.0.starts_with("/turf") {
-                        parsed_map.add_warning(format!(
-                            "Prefab {prefab_key:#?} does not second-end in a turf, instead ending in {prefab_turf:#?}!"
-                        ))?;
-                        continue;
-                    }
-                    if !prefab_turf.0.starts_with("/turf/template_noop") {
-                        zone!("generating CreateTurf");
-                        our_command_buffer.commands.push_back(Command::CreateTurf {
-                            loc: exact_coord,
-                            prefab: prefab_turf,
-                            no_changeturf: no_afterchange,
-                            place_on_top,
-                        })
-                    }
+    /// Adds the commands for one tile to `commands`, and any problems with it to `warnings`.
+    /// Returns where the tile goes, or None if nothing at all is placed there.
+    pub fn plan_tile(
+        &self,
+        prefabs: &'s Prefabs<'s>,
+        load_options: &LoadOptions,
+        relative_coord: (usize, usize, usize),
+        prefab_key: &'s str,
+        commands: &mut VecDeque<Command<'s>>,
+        warnings: &mut impl WarningSink,
+    ) -> eyre::Result<Option<(usize, usize, usize)>> {
+        let offset = self.offset;
 
-                    // We reverse it again after doing the turf and area
-                    for instance in prefab_list.rev() {
-                        // We allow these but warn about them
-                        if !instance.0.starts_with("/obj") && !instance.0.starts_with("/mob") {
-                            if instance.0.starts_with("/turf") {
-                                parsed_map.add_warning(
-                                    format!(
-                                        "Prefab {prefab_key:#?} had a secondary turf that we aren't going to deal with: {instance:#?}"
-                                    ))?;
-                                continue;
-                            } else {
-                                parsed_map.add_warning(
-                                        format!(
-                                            "Prefab {prefab_key:#?} has a strange element that we'll treat as a movable: {instance:#?}"
-                                        ))?;
-                            }
-                        }
-                        zone!("generating CreateAtom");
-                        // Movables are easy
-                        our_command_buffer.commands.push_back(Command::CreateAtom {
-                            loc: exact_coord,
-                            prefab: instance,
-                        });
-                    }
+        if self
+            .only_tiles
+            .as_ref()
+            .is_some_and(|only_tiles| !only_tiles.contains(&relative_coord))
+        {
+            return Ok(None);
+        }
+
+        // After only_tiles, as the diff is of the map as written
+        let Some(relative_coord) = self.turn(relative_coord) else {
+            warnings.add_warning_at(
+                Severity::Warning,
+                format!("Skipped {prefab_key:#?} at {relative_coord:?}, it's outside of the map's bounds so it can't be turned"),
+            )?;
+            return Ok(None);
+        };
+
+        // Skip anything outside of our relative bounds
+        if float_exceeds_upper_bounds(relative_coord, self.upper_bounds) {
+            return Ok(None);
+        }
+        // for some reason, negative bounds are permitted?
+        if float_exceeds_lower_bounds(relative_coord, self.lower_bounds) {
+            return Ok(None);
+        }
+
+        // Calculate absolute position
+        // This is offset - 1 because (1,1,1) actually goes *at* offset
+        let exact_coord = (
+            relative_coord.0 + offset.0 as usize - 1,
+            relative_coord.1 + offset.1 as usize - 1,
+            load_options.target_z(relative_coord.2, offset.2),
+        );
+
+        // This will just guaranteed fail to locate a turf
+        if exceeds_lower_bounds(exact_coord, (1, 1, 1)) {
+            warnings.add_warning_at(
+                Severity::Error,
+                format!("Bad map coord (tries to spawn in negative space): {exact_coord:#?}"),
+            )?;
+            return Ok(None);
+        }
+
+        // Avoid generating OOB commands
+        if exceeds_upper_bounds(exact_coord, self.world_bounds) && self.crop_map {
+            return Ok(None);
+        }
+
+        if Some(prefab_key) == self.space_key && self.no_afterchange {
+            return Ok(None);
+        }
+
+        let Some(prefab) = prefabs.get(prefab_key) else {
+            // Note: Cannot hard error or map will fail to finish loading
+            // This is necessarily just a warning, if an error-level one
+            warnings.add_warning_at(
+                Severity::Error,
+                format!("Invalid prefab key: {prefab_key:#?}"),
+            )?;
+            return Ok(None);
+        };
+
+        // DMM prefab require that all prefab lists end with one /turf, and then one /area.
+        if prefab.len() < 2 {
+            warnings.add_warning_at(
+                Severity::Error,
+                format!(
+                "Prefab {prefab_key:#?} is too short, violating requirement for /turf and /area!"
+            ),
+            )?;
+            return Ok(None);
+        }
+
+        if turf_ref_index(exact_coord, self.world_bounds).is_none() {
+            let error: eyre::Report = LoadError::OutOfRange(exact_coord).into();
+            if !load_options.skip_out_of_bounds {
+                return Err(error);
+            }
+            warnings.add_warning_at(Severity::Warning, format!("Skipped tile: {error:#}"))?;
+            return Ok(None);
+        }
+
+        let mut prefab_list = prefab.iter().rev();
+        // Above check ensures that these cannot panic
+        let prefab_area = prefab_list.next().unwrap();
+        if !prefab_area.0.starts_with("/area") {
+            warnings.add_warning_at(Severity::Error, format!(
+                "Prefab {prefab_key:#?} does not end in an area, instead ending in {prefab_area:#?}!"
+            ))?;
+            return Ok(Some(exact_coord));
+        }
+        if load_options.mode.places_areas()
+            && !load_options.skip_areas
+            && !prefab_area.0.starts_with("/area/template_noop")
+            && load_options.should_load(prefab_area)
+        {
+            commands.push_back(Command::CreateArea {
+                locs: vec![exact_coord],
+                prefab: prefab_area,
+                new_z: self.new_z,
+            });
+        }
+
+        let prefab_turf = prefab_list.next().unwrap();
+        if !prefab_turf.0.starts_with("/turf") {
+            warnings.add_warning_at(Severity::Error, format!(
+                "Prefab {prefab_key:#?} does not second-end in a turf, instead ending in {prefab_turf:#?}!"
+            ))?;
+            return Ok(Some(exact_coord));
+        }
+        if load_options.mode.places_turfs()
+            && !prefab_turf.0.starts_with("/turf/template_noop")
+            && !load_options.transparent_turf_paths.contains(prefab_turf.0)
+            && load_options.should_load(prefab_turf)
+        {
+            commands.push_back(Command::CreateTurf {
+                loc: exact_coord,
+                prefab: prefab_turf,
+                no_changeturf: self.no_afterchange
+                    || load_options.no_changeturf_paths.contains(prefab_turf.0),
+                place_on_top: self.place_on_top,
+            })
+        }
+
+        if !load_options.mode.places_atoms() {
+            return Ok(Some(exact_coord));
+        }
+
+        // How many movables the tile has, counting any past max_atoms_per_tile
+        let mut atoms = 0;
+        // We reverse it again after doing the turf and area
+        for instance in prefab_list.rev() {
+            if !load_options.should_load(instance) {
+                continue;
+            }
+            // We allow these but warn about them
+            if !instance.0.starts_with("/obj") && !instance.0.starts_with("/mob") {
+                if instance.0.starts_with("/turf") {
+                    warnings.add_warning_at(Severity::Warning, format!(
+                        "Prefab {prefab_key:#?} had a secondary turf that we aren't going to deal with: {instance:#?}"
+                    ))?;
+                    continue;
                 } else {
-                    // Note: Cannot hard error or map will fail to finish loading
-                    // This is necessarily just a warning
-                    parsed_map.add_warning(format!("Invalid prefab key: {prefab_key:#?}"))?;
+                    warnings.add_warning_at(Severity::Info, format!(
+                        "Prefab {prefab_key:#?} has a strange element that we'll treat as a movable: {instance:#?}"
+                    ))?;
                 }
             }
+            atoms += 1;
+            if load_options
+                .max_atoms_per_tile
+                .is_some_and(|max_atoms| atoms > max_atoms)
+            {
+                continue;
+            }
+            // Movables are easy
+            let note = if load_options.note_vars.contains_key(instance.0) {
+                self.notes.get(prefab_key).copied()
+            } else {
+                None
+            };
+            commands.push_back(Command::CreateAtom {
+                loc: exact_coord,
+                prefab: instance,
+                note,
+            });
+        }
+        if let Some(max_atoms) = load_options.max_atoms_per_tile.filter(|max| atoms > *max) {
+            warnings.add_warning_at(
+                Severity::Warning,
+                format!(
+                    "Tile at {exact_coord:?} has {atoms} atoms, only the first {max_atoms} were placed"
+                ),
+            )?;
         }
+
+        Ok(Some(exact_coord))
     }
+}
 
-    parsed_map.set_bounds(bounds)?;
+/// Produces the commands to load a map one tile at a time, as the command loop asks for them,
+/// so that a huge map never has to be turned into commands all at once.
+#[derive(Debug, Clone)]
+pub struct CommandStream<'s> {
+    prefabs: &'s Prefabs<'s>,
+    settings: TileSettings<'s>,
+    tiles: Peekable<Tiles<'s>>,
+    /// Commands for the current row that haven't been handed out yet
+    pending: VecDeque<Command<'s>>,
+    /// How many tiles have been taken from `tiles`
+    tiles_taken: usize,
+    /// The tile the current row started at, every tile before it has had all its commands handed out
+    row_start: usize,
+    /// Movables with these paths are skipped, they were reported while validating
+    bad_paths: HashSet<&'s str>,
+}
 
-    #[cfg(feature = "dump")]
-    let _ = std::fs::write(
-        format!("data/mapdump_{}_{}", _metadata.name, resume_key),
-        format!("{:#?}", &our_command_buffer),
-    );
-    command_buffers.insert(resume_key, our_command_buffer);
+impl<'s> CommandStream<'s> {
+    pub fn new(
+        prefabs: &'s Prefabs<'s>,
+        blocks: &'s [Block<'s>],
+        settings: TileSettings<'s>,
+        bad_paths: HashSet<&'s str>,
+    ) -> Self {
+        CommandStream {
+            prefabs,
+            tiles: Tiles::new(blocks, settings.key_len)
+                .fill_blank_rows(settings.blank_row_key)
+                .peekable(),
+            settings,
+            pending: VecDeque::new(),
+            tiles_taken: 0,
+            row_start: 0,
+            bad_paths,
+        }
+    }
 
-    Ok(ByondValue::new_num(resume_key as f32))
+    pub fn prefabs(&self) -> &'s Prefabs<'s> {
+        self.prefabs
+    }
+
+    pub fn settings(&self) -> &TileSettings<'s> {
+        &self.settings
+    }
+
+    /// Movables with this path are skipped from now on.
+    pub fn skip_path(&mut self, path: &'s str) {
+        self.bad_paths.insert(path);
+    }
+
+    /// Every tile before this one (in load order) has had all of its commands handed out already,
+    /// not counting the command that was just handed out.
+    pub fn row_start(&self) -> usize {
+        self.row_start
+    }
+
+    /// The next command to run, or None once the whole map has been handed out.
+    pub fn next_command(&mut self, load_options: &LoadOptions) -> Option<Command<'s>> {
+        loop {
+            if let Some(command) = self.pending.pop_front() {
+                if matches!(command, Command::CreateAtom { prefab, .. } if self.bad_paths.contains(prefab.0))
+                {
+                    continue;
+                }
+                return Some(command);
+            }
+
+            zone!("generating row commands");
+            let mut row = VecDeque::new();
+            let (mut relative_coord, mut prefab_key) = self.tiles.next()?;
+            self.row_start = self.tiles_taken;
+            self.tiles_taken += 1;
+            loop {
+                // Anything wrong with the tile was already reported while validating
+                let _ = self.settings.plan_tile(
+                    self.prefabs,
+                    load_options,
+                    relative_coord,
+                    prefab_key,
+                    &mut row,
+                    &mut Vec::new(),
+                );
+                let next_in_row = |&(next, _): &((usize, usize, usize), &str)| {
+                    next == (relative_coord.0 + 1, relative_coord.1, relative_coord.2)
+                };
+                match self.tiles.next_if(next_in_row) {
+                    Some(next) => {
+                        (relative_coord, prefab_key) = next;
+                        self.tiles_taken += 1;
+                    }
+                    None => break,
+                }
+            }
+            self.pending = merge_area_runs(row);
+        }
+    }
+}
+
+/// Merges the `CreateArea` commands of neighbouring tiles that share an area, and moves them in
+/// front of everything else, so that a row of one area is a single command.
+/// Areas are still placed before the turfs and movables on them.
+fn merge_area_runs(row: VecDeque<Command>) -> VecDeque<Command> {
+    let mut areas = VecDeque::new();
+    let mut others = VecDeque::new();
+    for command in row {
+        let Command::CreateArea {
+            locs,
+            prefab,
+            new_z,
+        } = command
+        else {
+            others.push_back(command);
+            continue;
+        };
+        match areas.back_mut() {
+            Some(Command::CreateArea {
+                locs: run_locs,
+                prefab: run_prefab,
+                ..
+            }) if *run_prefab == prefab
+                && run_locs.last().map(|last| last.0 + 1) == Some(locs[0].0) =>
+            {
+                run_locs.extend(locs)
+            }
+            _ => areas.push_back(Command::CreateArea {
+                locs,
+                prefab,
+                new_z,
+            }),
+        }
+    }
+    areas.extend(others);
+    areas
 }
 
 // Helpers
@@ -325,20 +1215,463 @@ fn float_exceeds_lower_bounds(check: (usize, usize, usize), bounds: (f32, f32, f
     (check.0 as f32) < bounds.0 || (check.1 as f32) < bounds.1 || (check.2 as f32) < bounds.2
 }
 
-fn separate_turfs(mut s: &str, n: usize) -> impl Iterator<Item = &'_ str> {
-    assert_ne!(n, 0);
-    std::iter::from_fn(move || {
-        let index = s
-            .char_indices()
-            .nth(n)
-            .map(|(index, _)| index)
-            .unwrap_or(s.len());
-        let (item, rest) = s.split_at(index);
-        if item.is_empty() {
-            None
-        } else {
-            s = rest;
-            Some(item)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load::options::VarWhitelist;
+
+    #[test]
+    fn test_check_world_bounds() {
+        assert!(check_world_bounds((255, 255, 1)).is_ok());
+        // A fresh world before anything has set maxz
+        let error = check_world_bounds((255, 255, 0)).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<LoadError>(),
+            Some(&LoadError::EmptyWorld((255, 255, 0)))
+        );
+        assert!(error
+            .to_string()
+            .contains("Set world.maxx, maxy and maxz first"));
+        assert!(check_world_bounds((0, 0, 0)).is_err());
+    }
+
+    #[test]
+    fn test_next_resume_key() {
+        let mut in_use = HashMap::new();
+        assert_eq!(next_resume_key(0, &in_use), 1);
+
+        // Taken keys are skipped, never replaced
+        in_use.insert(5, ());
+        in_use.insert(6, ());
+        assert_eq!(next_resume_key(4, &in_use), 7);
+
+        // Wraps around, and still skips
+        in_use.insert(1, ());
+        assert_eq!(next_resume_key(MAX_RESUME_KEY, &in_use), 2);
+    }
+
+    #[test]
+    fn test_tiles_load_order() {
+        let blocks = vec![((1, 1, 1), vec!["aaab", "acad"]), ((5, 1, 2), vec!["aeb"])];
+        assert_eq!(
+            Tiles::new(&blocks, 2).collect::<Vec<_>>(),
+            vec![
+                ((1, 2, 1), "aa"),
+                ((2, 2, 1), "ab"),
+                ((1, 1, 1), "ac"),
+                ((2, 1, 1), "ad"),
+                ((5, 1, 2), "ae"),
+                // Left over at the end of the line
+                ((6, 1, 2), "b"),
+            ]
+        );
+        assert_eq!(Tiles::new(&blocks, 0).next(), None);
+    }
+
+    #[test]
+    fn test_tiles_fill_blank_rows() {
+        let blocks = vec![((1, 1, 1), vec!["ab", "", "a"])];
+        assert_eq!(
+            Tiles::new(&blocks, 1)
+                .fill_blank_rows(Some("s"))
+                .collect::<Vec<_>>(),
+            vec![
+                ((1, 3, 1), "a"),
+                ((2, 3, 1), "b"),
+                ((1, 2, 1), "s"),
+                ((2, 2, 1), "s"),
+                ((1, 1, 1), "a"),
+            ]
+        );
+        // Left empty without a key
+        assert_eq!(Tiles::new(&blocks, 1).count(), 3);
+    }
+
+    fn test_settings<'s>() -> TileSettings<'s> {
+        TileSettings {
+            key_len: 1,
+            offset: (1., 1., 1.),
+            crop_map: false,
+            lower_bounds: (f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+            upper_bounds: (f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            world_bounds: (255, 255, 1),
+            space_key: None,
+            blank_row_key: None,
+            no_afterchange: false,
+            place_on_top: false,
+            new_z: false,
+            notes: HashMap::new(),
+            only_tiles: None,
+            parsed_bounds: (1, 1, 1, 255, 255, 1),
+            flip_y: false,
+            rotation: Rotation::Cw0,
         }
-    })
+    }
+
+    fn stream_commands<'s>(prefabs: &'s Prefabs<'s>, blocks: &'s [Block<'s>]) -> Vec<Command<'s>> {
+        stream_commands_with(prefabs, blocks, test_settings(), &LoadOptions::default())
+    }
+
+    fn stream_commands_with<'s>(
+        prefabs: &'s Prefabs<'s>,
+        blocks: &'s [Block<'s>],
+        settings: TileSettings<'s>,
+        options: &LoadOptions,
+    ) -> Vec<Command<'s>> {
+        let mut stream = CommandStream::new(prefabs, blocks, settings, HashSet::new());
+        std::iter::from_fn(|| stream.next_command(options)).collect()
+    }
+
+    /// Where each turf in `commands` goes, and its path
+    fn created_turfs<'s>(commands: &[Command<'s>]) -> Vec<((usize, usize, usize), &'s str)> {
+        commands
+            .iter()
+            .filter_map(|command| match command {
+                Command::CreateTurf { loc, prefab, .. } => Some((*loc, prefab.0)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_area_runs_merged() {
+        let row = "a".repeat(10);
+        let map = format!(
+            "\"a\" = (/turf/open/floor,/area/station)\n\n(1,1,1) = {{\"\n{}\n\"}}\n",
+            vec![row; 10].join("\n")
+        );
+        let (_info, (prefabs, blocks)) =
+            dmm_lite::parse_map_multithreaded("test".to_owned(), &map).unwrap();
+        let commands = stream_commands(&prefabs, &blocks);
+
+        // One area command per row instead of per tile, so 110 commands rather than 200
+        assert_eq!(commands.len(), 110);
+        let area_locs: Vec<_> = commands
+            .iter()
+            .filter_map(|command| match command {
+                Command::CreateArea { locs, .. } => Some(locs.len()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(area_locs, vec![10; 10]);
+    }
+
+    #[test]
+    fn test_command_stream_row_start() {
+        let map = "\"a\" = (/turf/open/floor,/area/station)\n\n(1,1,1) = {\"\naa\naa\n\"}\n";
+        let (_info, (prefabs, blocks)) =
+            dmm_lite::parse_map_multithreaded("test".to_owned(), map).unwrap();
+        let options = LoadOptions::default();
+        let mut stream = CommandStream::new(&prefabs, &blocks, test_settings(), HashSet::new());
+
+        // One area and two turfs per row
+        let mut row_starts = vec![];
+        while stream.next_command(&options).is_some() {
+            row_starts.push(stream.row_start());
+        }
+        assert_eq!(row_starts, vec![0, 0, 0, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_skip_areas() {
+        let map = "\"a\" = (/obj/item,/turf/open/floor,/area/station)\n\n(1,1,1) = {\"\naa\n\"}\n";
+        let (_info, (prefabs, blocks)) =
+            dmm_lite::parse_map_multithreaded("test".to_owned(), map).unwrap();
+        let options = LoadOptions {
+            skip_areas: true,
+            ..Default::default()
+        };
+        let commands = stream_commands_with(&prefabs, &blocks, test_settings(), &options);
+
+        assert!(!commands
+            .iter()
+            .any(|command| matches!(command, Command::CreateArea { .. })));
+        // Turfs and movables are still placed
+        assert_eq!(commands.len(), 4);
+    }
+
+    #[test]
+    fn test_only_tiles() {
+        let map =
+            "\"a\" = (/obj/item,/turf/open/floor,/area/station)\n\n(1,1,1) = {\"\naa\naa\n\"}\n";
+        let (_info, (prefabs, blocks)) =
+            dmm_lite::parse_map_multithreaded("test".to_owned(), map).unwrap();
+        let settings = TileSettings {
+            only_tiles: Some(HashSet::from([(2, 1, 1)])),
+            ..test_settings()
+        };
+        let commands = stream_commands_with(&prefabs, &blocks, settings, &LoadOptions::default());
+
+        // The area, turf and item of the one tile
+        assert_eq!(commands.len(), 3);
+    }
+
+    #[test]
+    fn test_flip_y() {
+        let map = r#""a" = (/turf/open/floor,/area/station)
+"b" = (/turf/open/wall,/area/station)
+
+(1,1,1) = {"
+ab
+aa
+aa
+"}
+"#;
+        let (_info, (prefabs, blocks)) =
+            dmm_lite::parse_map_multithreaded("test".to_owned(), map).unwrap();
+        let settings = TileSettings {
+            offset: (1., 3., 1.),
+            // Cropped to the lower two rows, once they're flipped
+            upper_bounds: (f32::INFINITY, 2., f32::INFINITY),
+            parsed_bounds: (1, 1, 1, 2, 3, 1),
+            flip_y: true,
+            ..test_settings()
+        };
+        let turfs = created_turfs(&stream_commands_with(
+            &prefabs,
+            &blocks,
+            settings,
+            &LoadOptions::default(),
+        ));
+
+        // The top row is at the bottom, and the offset still applies
+        assert_eq!(
+            turfs,
+            vec![
+                ((1, 3, 1), "/turf/open/floor"),
+                ((2, 3, 1), "/turf/open/wall"),
+                ((1, 4, 1), "/turf/open/floor"),
+                ((2, 4, 1), "/turf/open/floor"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rotated_and_offset() {
+        // 3 wide and 2 tall, with the only wall at the top left
+        let map = r#""a" = (/turf/open/floor,/area/station)
+"b" = (/turf/open/wall,/area/station)
+
+(1,1,1) = {"
+baa
+aaa
+"}
+"#;
+        let (_info, (prefabs, blocks)) =
+            dmm_lite::parse_map_multithreaded("test".to_owned(), map).unwrap();
+        let settings = TileSettings {
+            offset: (10., 10., 1.),
+            parsed_bounds: (1, 1, 1, 3, 2, 1),
+            rotation: Rotation::Cw90,
+            ..test_settings()
+        };
+        let mut turfs = created_turfs(&stream_commands_with(
+            &prefabs,
+            &blocks,
+            settings,
+            &LoadOptions::default(),
+        ));
+        turfs.sort_unstable();
+
+        // Turned around the map's own corner into 2 wide and 3 tall, then moved to (10, 10), so the
+        // wall goes from the top left to the top right
+        let floor = "/turf/open/floor";
+        assert_eq!(
+            turfs,
+            vec![
+                ((10, 10, 1), floor),
+                ((10, 11, 1), floor),
+                ((10, 12, 1), floor),
+                ((11, 10, 1), floor),
+                ((11, 11, 1), floor),
+                ((11, 12, 1), "/turf/open/wall"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_atoms_per_tile() {
+        let map = "\"a\" = (/obj/item,/obj/item,/obj/item,/turf/open/floor,/area/station)\n\"b\" = (/obj/item,/turf/open/floor,/area/station)\n\n(1,1,1) = {\"\nab\n\"}\n";
+        let (_info, (prefabs, _blocks)) =
+            dmm_lite::parse_map_multithreaded("test".to_owned(), map).unwrap();
+        let options = LoadOptions {
+            max_atoms_per_tile: Some(2),
+            ..Default::default()
+        };
+        let settings = test_settings();
+        let mut commands = VecDeque::new();
+        let mut warnings: Vec<(Severity, String)> = vec![];
+        for (coord, key) in [((1, 1, 1), "a"), ((2, 1, 1), "b")] {
+            settings
+                .plan_tile(&prefabs, &options, coord, key, &mut commands, &mut warnings)
+                .unwrap();
+        }
+
+        let atoms: Vec<_> = commands
+            .iter()
+            .filter_map(|command| match command {
+                Command::CreateAtom { loc, .. } => Some(*loc),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(atoms, vec![(1, 1, 1), (1, 1, 1), (2, 1, 1)]);
+        assert_eq!(
+            warnings,
+            vec![(
+                Severity::Warning,
+                "Tile at (1, 1, 1) has 3 atoms, only the first 2 were placed".to_owned()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_rotated_short_key() {
+        // A short key left over at the end of the row, past the map's bounds
+        let map = r#""aa" = (/turf/open/floor,/area/station)
+
+(1,1,1) = {"
+aaaab
+"}
+"#;
+        let (_info, (prefabs, blocks)) =
+            dmm_lite::parse_map_multithreaded("test".to_owned(), map).unwrap();
+        let settings = TileSettings {
+            key_len: 2,
+            parsed_bounds: (1, 1, 1, 2, 1, 1),
+            rotation: Rotation::Cw90,
+            ..test_settings()
+        };
+        let report = simulate_load(&prefabs, &blocks, settings, &LoadOptions::default(), |_| {
+            true
+        })
+        .unwrap();
+
+        // Skipped, rather than turned to somewhere that doesn't exist
+        assert_eq!(report.turfs, 2);
+        assert_eq!(
+            report.warnings,
+            vec![(
+                Severity::Warning,
+                "Skipped \"b\" at (3, 1, 1), it's outside of the map's bounds so it can't be turned"
+                    .to_owned()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_simulate_load() {
+        let map = r#""a" = (/turf/open/floor,/area/station)
+"b" = (/obj/item,/obj/fake,/turf/open/floor,/area/station)
+
+(1,1,1) = {"
+ab
+ac
+"}
+(1,1,1) = {"
+a
+"}
+"#;
+        let (_info, (prefabs, blocks)) =
+            dmm_lite::parse_map_multithreaded("test".to_owned(), map).unwrap();
+        let report = simulate_load(
+            &prefabs,
+            &blocks,
+            test_settings(),
+            &LoadOptions::default(),
+            |path| path != "/obj/fake",
+        )
+        .unwrap();
+
+        assert_eq!(
+            report,
+            LoadReport {
+                // Overlapping blocks place on (1, 1, 1) twice, and "c" places nothing
+                areas: 3,
+                turfs: 3,
+                // The fake one is skipped
+                atoms: 1,
+                bounds: Some((1, 1, 1, 2, 2, 1)),
+                warnings: vec![
+                    (
+                        Severity::Warning,
+                        "Blocks at (1, 1, 1) and (1, 1, 1) overlap from (1, 1, 1) to (1, 1, 1), the later one is loaded there".to_owned()
+                    ),
+                    (Severity::Error, "Invalid prefab key: \"c\"".to_owned()),
+                    (Severity::Error, "Bad path \"/obj/fake\"".to_owned()),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_unknown_var_warnings() {
+        let map = r#""a" = (/obj/item{name = "a"},/turf/open/floor,/area/station)
+"b" = (/obj/item{desc = "b"},/turf/open/floor,/area/station)
+
+(1,1,1) = {"
+aab
+"}
+"#;
+        let (_info, (prefabs, blocks)) =
+            dmm_lite::parse_map_multithreaded("test".to_owned(), map).unwrap();
+        let load_options = LoadOptions {
+            var_whitelist: Some(VarWhitelist(HashMap::from([(
+                "/obj/item".to_owned(),
+                HashSet::new(),
+            )]))),
+            ..Default::default()
+        };
+        let report =
+            simulate_load(&prefabs, &blocks, test_settings(), &load_options, |_| true).unwrap();
+
+        assert_eq!(
+            report.warnings,
+            vec![
+                (
+                    Severity::Warning,
+                    "Prefab \"a\" sets unknown var \"name\" on /obj/item, at (1, 1, 1) and 1 other tiles".to_owned()
+                ),
+                (
+                    Severity::Warning,
+                    "Prefab \"b\" sets unknown var \"desc\" on /obj/item, at (3, 1, 1)".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_area_runs_split() {
+        let map = r#""a" = (/turf/open/floor,/area/station)
+"b" = (/turf/open/floor,/area/hallway)
+
+(1,1,1) = {"
+aaba
+"}
+"#;
+        let (_info, (prefabs, blocks)) =
+            dmm_lite::parse_map_multithreaded("test".to_owned(), map).unwrap();
+        let commands = stream_commands(&prefabs, &blocks);
+
+        let areas: Vec<_> = commands
+            .iter()
+            .filter_map(|command| match command {
+                Command::CreateArea { locs, prefab, .. } => Some((prefab.0, locs.clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            areas,
+            vec![
+                ("/area/station", vec![(1, 1, 1), (2, 1, 1)]),
+                ("/area/hallway", vec![(3, 1, 1)]),
+                ("/area/station", vec![(4, 1, 1)]),
+            ]
+        );
+        // Areas go first, then the turfs in order
+        assert!(matches!(
+            commands[3],
+            Command::CreateTurf { loc: (1, 1, 1), .. }
+        ));
+        assert_eq!(commands.len(), 7);
+    }
 }
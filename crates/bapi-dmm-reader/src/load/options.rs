@@ -0,0 +1,1193 @@
+//! Optional settings for loading a map, read from a `/datum/bapi_load_options`.
+//! These are things that not every caller needs, so they don't get their own bind argument.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    ops::RangeInclusive,
+    path::Path,
+    rc::Rc,
+};
+
+use byondapi::value::ByondValue;
+use dmm_lite::{
+    prefabs::{Literal, Prefab},
+    writer::format_number,
+};
+use eyre::{Context, Result};
+
+use crate::{
+    error::LoadError,
+    load::{
+        helpers::_bapi_helper_text2path,
+        smart_byond_value::{SharedByondValue, SmartByondValue},
+    },
+};
+
+#[derive(Debug, Default)]
+pub struct LoadOptions {
+    /// Which kinds of things get placed.
+    pub mode: LoadMode,
+    /// If set, vars that aren't valid for their type are reported as warnings.
+    pub var_whitelist: Option<VarWhitelist>,
+    /// If set, anything tagged as a variant that isn't allowed is not loaded.
+    pub variant_filter: Option<VariantFilter>,
+    /// Added to the pixel_x and pixel_y of every movable.
+    pub pixel_offset: (f32, f32),
+    /// Skip tiles that are off the map with a warning, instead of failing the whole load.
+    pub skip_out_of_bounds: bool,
+    /// Turn explicit `dir` vars along with the map for rotated loads, see [`rotate_dir`].
+    pub rotate_dirs: bool,
+    /// Move the map to fit in the world instead of expanding the world for it, see
+    /// [`LoadOptions::clamped_offset`].
+    pub clamp_to_world: bool,
+    /// Mirror the map top to bottom, for maps from editors that count y from the top.
+    pub flip_y: bool,
+    /// Turn the map clockwise, see [`Rotation`].
+    pub rotation: Rotation,
+    /// Relative file literals ('icons/thing.dmi') are resolved relative to this, if set.
+    pub file_base_dir: Option<String>,
+    /// Warn about file literals that point to files that don't exist, even without a `file_base_dir`.
+    pub check_files: bool,
+    /// Don't place areas at all, whatever the mode, so turfs stay in whatever area they're in.
+    pub skip_areas: bool,
+    /// What happens to turfs that are already in an area.
+    pub area_policy: AreaPolicy,
+    /// Run area containment handling on new z-levels too. This is the slow part of placing areas.
+    pub contain_new_z: bool,
+    /// Don't put turfs in the map's areas, only record which area each would have gone in.
+    /// See [`crate::load::command_buffer::AreaMembership`].
+    pub areas_as_overlay: bool,
+    /// Stop the whole load on the first command that fails, instead of warning and carrying on.
+    pub strict: bool,
+    /// The world z-level each of the map's z-levels is loaded onto, the first entry being the map's z 1.
+    /// This replaces the z offset.
+    pub z_levels: Option<Vec<usize>>,
+    /// Rewrites each var (by name) before it's set, or drops it by returning None.
+    /// There's no way to set this from DM, it's for building on the loader from Rust.
+    pub literal_transform: Option<LiteralTransform>,
+    /// Turfs of exactly these types never go through ChangeTurf, whatever no_changeturf is.
+    pub no_changeturf_paths: HashSet<String>,
+    /// Turfs of exactly these types aren't placed, leaving whatever turf was there. Anything else on
+    /// the tile still is.
+    pub transparent_turf_paths: HashSet<String>,
+    /// Movables of exactly these types are checked for reaching past the loaded area once they're
+    /// created, going by their bound_width and bound_height.
+    pub big_atom_paths: HashSet<String>,
+    /// Set the vars of movables after creating them, instead of going through the preloader.
+    /// Turfs still use the preloader.
+    pub no_preloader: bool,
+    /// Global proc that turfs are created with instead of `_bapi_create_turf`. It's given the same arguments.
+    pub create_turf_proc: Option<String>,
+    /// For place_on_top loads, the turf type put under each placed turf, instead of whatever was there.
+    pub base_turf: Option<String>,
+    /// Global proc called with the parsed map and a world z-level as soon as everything on that
+    /// z-level has been placed, before anything on the next one is.
+    pub z_loaded_proc: Option<String>,
+    /// Don't create movables whose exact type is already on the tile, so a map can be loaded
+    /// over itself without doubling up.
+    pub additive: bool,
+    /// What happens to vars with values that the parser didn't understand.
+    pub fallback_policy: FallbackPolicy,
+    /// Where the middle of the map goes, replacing the offset. See [`LoadOptions::centered_offset`].
+    pub center_on: Option<(usize, usize, usize)>,
+    /// Typepath -> var name. Movables of exactly these types get the `// @note` of their prefab
+    /// set as that var, if it has one.
+    pub note_vars: HashMap<String, String>,
+    /// Passed to `rand_seed()` before anything is placed, so that randomness while loading repeats.
+    pub rand_seed: Option<f32>,
+    /// Maps with more tiles than this aren't loaded at all.
+    pub max_tiles: Option<usize>,
+    /// Maps with more movables than this aren't loaded at all.
+    pub max_atoms: Option<usize>,
+    /// Movables past this many on one tile are skipped with a warning, rather than the whole map.
+    pub max_atoms_per_tile: Option<usize>,
+    /// Area typepath -> the area typepath placed instead. Turfs and movables aren't affected.
+    pub area_remap: HashMap<String, String>,
+    /// Set on every turf and movable, unless the map sets the same var on it.
+    pub extra_vars: Vec<(String, SharedByondValue)>,
+    /// The `_internal_index` of an earlier version of the map. Only tiles that differ from it are
+    /// loaded, see [`dmm_lite::diff::map_diff`].
+    pub diff_against: Option<usize>,
+    /// Hash everything placed, see [`crate::load::command_buffer::LoadChecksum`].
+    pub checksum: bool,
+    /// Leave the parsed map's `loading` alone, for callers that set it themselves.
+    pub manual_loading: bool,
+}
+
+/// See [`LoadOptions::literal_transform`]. Only top level vars are passed in, so a transform that
+/// cares about what's inside of lists has to look inside of them itself.
+pub type LiteralTransform = for<'a> fn(&str, &Literal<'a>) -> Option<Literal<'a>>;
+
+/// Turns a `dir` value `quarter_turns` times clockwise, so that something facing `NORTH` faces
+/// `EAST` after one turn. Diagonals turn with their cardinals.
+///
+/// This is for the `dir` var of atoms in rotated loads. Anything that isn't one of the eight
+/// directions, such as 0, `UP` or a number that isn't whole, is returned as it is.
+pub fn rotate_dir(dir: f32, quarter_turns: u32) -> f32 {
+    const NORTH: u8 = 1;
+    const SOUTH: u8 = 2;
+    const EAST: u8 = 4;
+    const WEST: u8 = 8;
+
+    if dir.fract() != 0. || !(1. ..=15.).contains(&dir) {
+        return dir;
+    }
+    let dir = dir as u8;
+    if dir & (NORTH | SOUTH) == NORTH | SOUTH || dir & (EAST | WEST) == EAST | WEST {
+        return dir as f32;
+    }
+
+    let mut rotated = dir;
+    for _ in 0..quarter_turns % 4 {
+        let mut turned = 0;
+        for (from, to) in [(NORTH, EAST), (EAST, SOUTH), (SOUTH, WEST), (WEST, NORTH)] {
+            if rotated & from != 0 {
+                turned |= to;
+            }
+        }
+        rotated = turned;
+    }
+    rotated as f32
+}
+
+impl LoadOptions {
+    /// Reads the options off of a `/datum/bapi_load_options`, or uses the defaults if it's null.
+    pub fn from_byond(options: ByondValue) -> Result<Self> {
+        if options.is_null() {
+            return Ok(Self::default());
+        }
+
+        let mode = options
+            .read_var("load_mode")
+            .context("Failed to read load_options.load_mode")?;
+        let mode = if mode.is_null() {
+            LoadMode::default()
+        } else {
+            LoadMode::from_define(&mode.get_string()?)?
+        };
+
+        let var_whitelist = options
+            .read_var("var_whitelist")
+            .context("Failed to read load_options.var_whitelist")?;
+        let var_whitelist = if var_whitelist.is_null() {
+            None
+        } else {
+            Some(VarWhitelist::from_byond(var_whitelist)?)
+        };
+
+        let variant_var = options
+            .read_var("variant_var")
+            .context("Failed to read load_options.variant_var")?;
+        let variant_filter = if variant_var.is_null() {
+            None
+        } else {
+            Some(VariantFilter::from_byond(
+                variant_var.get_string()?,
+                options
+                    .read_var("allowed_variants")
+                    .context("Failed to read load_options.allowed_variants")?,
+            )?)
+        };
+
+        let pixel_offset = (
+            read_number_or_zero(options, "pixel_offset_x")?,
+            read_number_or_zero(options, "pixel_offset_y")?,
+        );
+
+        let skip_out_of_bounds = options
+            .read_var("skip_out_of_bounds")
+            .context("Failed to read load_options.skip_out_of_bounds")?
+            .is_true();
+
+        let rotate_dirs = options
+            .read_var("rotate_dirs")
+            .context("Failed to read load_options.rotate_dirs")?
+            .is_true();
+
+        let flip_y = options
+            .read_var("flip_y")
+            .context("Failed to read load_options.flip_y")?
+            .is_true();
+
+        let rotation = Rotation::from_degrees(read_number_or_zero(options, "rotation")?)?;
+
+        let clamp_to_world = options
+            .read_var("clamp_to_world")
+            .context("Failed to read load_options.clamp_to_world")?
+            .is_true();
+
+        let file_base_dir = options
+            .read_var("file_base_dir")
+            .context("Failed to read load_options.file_base_dir")?;
+        let file_base_dir = if file_base_dir.is_null() {
+            None
+        } else {
+            Some(file_base_dir.get_string()?)
+        };
+
+        let check_files = options
+            .read_var("check_files")
+            .context("Failed to read load_options.check_files")?
+            .is_true();
+
+        let skip_areas = options
+            .read_var("skip_areas")
+            .context("Failed to read load_options.skip_areas")?
+            .is_true();
+
+        let area_policy = options
+            .read_var("area_policy")
+            .context("Failed to read load_options.area_policy")?;
+        let area_policy = if area_policy.is_null() {
+            AreaPolicy::default()
+        } else {
+            AreaPolicy::from_define(&area_policy.get_string()?)?
+        };
+
+        let areas_as_overlay = options
+            .read_var("areas_as_overlay")
+            .context("Failed to read load_options.areas_as_overlay")?
+            .is_true();
+
+        let contain_new_z = options
+            .read_var("contain_new_z")
+            .context("Failed to read load_options.contain_new_z")?
+            .is_true();
+
+        let strict = options
+            .read_var("strict")
+            .context("Failed to read load_options.strict")?
+            .is_true();
+
+        let z_levels = options
+            .read_var("z_levels")
+            .context("Failed to read load_options.z_levels")?;
+        let z_levels = if z_levels.is_null() {
+            None
+        } else {
+            Some(
+                z_levels
+                    .values()
+                    .context("load_options.z_levels is not a list")?
+                    .map(|z| z.get_number().map(|z| z as usize))
+                    .collect::<Result<_, _>>()
+                    .context("load_options.z_levels must only contain numbers")?,
+            )
+        };
+
+        let no_changeturf_paths = options
+            .read_var("no_changeturf_paths")
+            .context("Failed to read load_options.no_changeturf_paths")?;
+        let no_changeturf_paths = if no_changeturf_paths.is_null() {
+            HashSet::new()
+        } else {
+            no_changeturf_paths
+                .values()
+                .context("load_options.no_changeturf_paths is not a list")?
+                .map(|path| path.get_string())
+                .collect::<Result<_, _>>()?
+        };
+
+        let transparent_turf_paths = options
+            .read_var("transparent_turf_paths")
+            .context("Failed to read load_options.transparent_turf_paths")?;
+        let transparent_turf_paths = if transparent_turf_paths.is_null() {
+            HashSet::new()
+        } else {
+            transparent_turf_paths
+                .values()
+                .context("load_options.transparent_turf_paths is not a list")?
+                .map(|path| path.get_string())
+                .collect::<Result<_, _>>()?
+        };
+
+        let big_atom_paths = options
+            .read_var("big_atom_paths")
+            .context("Failed to read load_options.big_atom_paths")?;
+        let big_atom_paths = if big_atom_paths.is_null() {
+            HashSet::new()
+        } else {
+            big_atom_paths
+                .values()
+                .context("load_options.big_atom_paths is not a list")?
+                .map(|path| path.get_string())
+                .collect::<Result<_, _>>()?
+        };
+
+        let no_preloader = options
+            .read_var("no_preloader")
+            .context("Failed to read load_options.no_preloader")?
+            .is_true();
+
+        let create_turf_proc = options
+            .read_var("create_turf_proc")
+            .context("Failed to read load_options.create_turf_proc")?;
+        let create_turf_proc = if create_turf_proc.is_null() {
+            None
+        } else {
+            Some(create_turf_proc.get_string()?)
+        };
+
+        let base_turf = options
+            .read_var("base_turf")
+            .context("Failed to read load_options.base_turf")?;
+        let base_turf = if base_turf.is_null() {
+            None
+        } else {
+            let base_turf = base_turf.get_string()?;
+            if !base_turf.starts_with("/turf") || _bapi_helper_text2path(&base_turf)?.is_null() {
+                return Err(LoadError::BadOption {
+                    var: "base_turf",
+                    value: base_turf,
+                }
+                .into());
+            }
+            Some(base_turf)
+        };
+
+        let z_loaded_proc = options
+            .read_var("z_loaded_proc")
+            .context("Failed to read load_options.z_loaded_proc")?;
+        let z_loaded_proc = if z_loaded_proc.is_null() {
+            None
+        } else {
+            Some(z_loaded_proc.get_string()?)
+        };
+
+        let additive = options
+            .read_var("additive")
+            .context("Failed to read load_options.additive")?
+            .is_true();
+
+        let fallback_policy = options
+            .read_var("fallback_policy")
+            .context("Failed to read load_options.fallback_policy")?;
+        let fallback_policy = if fallback_policy.is_null() {
+            FallbackPolicy::default()
+        } else {
+            FallbackPolicy::from_define(&fallback_policy.get_string()?)?
+        };
+
+        let center_on = options
+            .read_var("center_on")
+            .context("Failed to read load_options.center_on")?;
+        let center_on = if center_on.is_null() {
+            None
+        } else {
+            let coords = center_on
+                .values()
+                .context("load_options.center_on is not a list")?
+                .map(|coord| coord.get_number().map(|coord| coord as usize))
+                .collect::<Result<Vec<_>, _>>()
+                .context("load_options.center_on must only contain numbers")?;
+            let [x, y, z] = coords[..] else {
+                return Err(LoadError::BadOption {
+                    var: "center_on",
+                    value: format!("{coords:?}"),
+                })
+                .wrap_err("load_options.center_on must be list(x, y, z)");
+            };
+            Some((x, y, z))
+        };
+
+        let note_vars = options
+            .read_var("note_vars")
+            .context("Failed to read load_options.note_vars")?;
+        let note_vars = if note_vars.is_null() {
+            HashMap::new()
+        } else {
+            note_vars
+                .iter()
+                .context("load_options.note_vars is not a list")?
+                .map(|(path, var)| Ok((path.get_string()?, var.get_string()?)))
+                .collect::<Result<_>>()
+                .context("load_options.note_vars must be typepath = var name")?
+        };
+
+        let rand_seed = options
+            .read_var("rand_seed")
+            .context("Failed to read load_options.rand_seed")?;
+        let rand_seed = if rand_seed.is_null() {
+            None
+        } else {
+            Some(
+                rand_seed
+                    .get_number()
+                    .context("load_options.rand_seed is not a number")?,
+            )
+        };
+
+        let max_tiles = read_optional_count(options, "max_tiles")?;
+        let max_atoms = read_optional_count(options, "max_atoms")?;
+        let max_atoms_per_tile = read_optional_count(options, "max_atoms_per_tile")?;
+
+        let area_remap = options
+            .read_var("area_remap")
+            .context("Failed to read load_options.area_remap")?;
+        let area_remap = if area_remap.is_null() {
+            HashMap::new()
+        } else {
+            area_remap
+                .iter()
+                .context("load_options.area_remap is not a list")?
+                .map(|(from, to)| Ok((from.get_string()?, to.get_string()?)))
+                .collect::<Result<_>>()
+                .context("load_options.area_remap must be typepath = typepath")?
+        };
+
+        let extra_vars = options
+            .read_var("extra_vars")
+            .context("Failed to read load_options.extra_vars")?;
+        let extra_vars = if extra_vars.is_null() {
+            vec![]
+        } else {
+            extra_vars
+                .iter()
+                .context("load_options.extra_vars is not a list")?
+                .map(|(var, value)| Ok((var.get_string()?, Rc::new(SmartByondValue::from(value)))))
+                .collect::<Result<_>>()
+                .context("load_options.extra_vars must be var name = value")?
+        };
+
+        let diff_against = options
+            .read_var("diff_against")
+            .context("Failed to read load_options.diff_against")?;
+        let diff_against = if diff_against.is_null() {
+            None
+        } else {
+            let index = diff_against
+                .read_number("_internal_index")
+                .context("load_options.diff_against is not a /datum/bapi_parsed_map")?;
+            if index < 0. {
+                return Err(LoadError::BadOption {
+                    var: "diff_against",
+                    value: format!("{index}"),
+                })
+                .wrap_err("load_options.diff_against has been freed");
+            }
+            Some(index as usize)
+        };
+
+        let checksum = options
+            .read_var("checksum")
+            .context("Failed to read load_options.checksum")?
+            .is_true();
+
+        let manual_loading = options
+            .read_var("manual_loading")
+            .context("Failed to read load_options.manual_loading")?
+            .is_true();
+
+        Ok(LoadOptions {
+            mode,
+            var_whitelist,
+            variant_filter,
+            pixel_offset,
+            skip_out_of_bounds,
+            rotate_dirs,
+            clamp_to_world,
+            flip_y,
+            rotation,
+            file_base_dir,
+            check_files,
+            skip_areas,
+            area_policy,
+            contain_new_z,
+            areas_as_overlay,
+            strict,
+            z_levels,
+            literal_transform: None,
+            no_changeturf_paths,
+            transparent_turf_paths,
+            big_atom_paths,
+            no_preloader,
+            create_turf_proc,
+            base_turf,
+            z_loaded_proc,
+            additive,
+            fallback_policy,
+            center_on,
+            note_vars,
+            rand_seed,
+            max_tiles,
+            max_atoms,
+            max_atoms_per_tile,
+            area_remap,
+            extra_vars,
+            diff_against,
+            checksum,
+            manual_loading,
+        })
+    }
+
+    /// The offset that moves the map as little as possible to fit it in x and y of `world_bounds`, if
+    /// [`LoadOptions::clamp_to_world`] is set and it doesn't fit at `offset`. Z-levels are left alone.
+    ///
+    /// A map that's bigger than the world is put against its bottom left, and whatever still doesn't
+    /// fit is cropped.
+    pub fn clamped_offset(
+        &self,
+        offset: (f32, f32, f32),
+        parsed_bounds: (usize, usize, usize, usize, usize, usize),
+        world_bounds: (usize, usize, usize),
+    ) -> Option<(f32, f32, f32)> {
+        if !self.clamp_to_world {
+            return None;
+        }
+        // (1, 1, 1) goes at the offset, so the far edge is at offset + max - 1
+        let clamp = |offset: f32, max: usize, world: usize| {
+            let highest = (world + 1).saturating_sub(max).max(1) as f32;
+            offset.clamp(1., highest)
+        };
+        let clamped = (
+            clamp(offset.0, parsed_bounds.3, world_bounds.0),
+            clamp(offset.1, parsed_bounds.4, world_bounds.1),
+            offset.2,
+        );
+        (clamped != offset).then_some(clamped)
+    }
+
+    /// Checks that each of `map_z_levels` has an existing z-level to go onto, if
+    /// [`LoadOptions::z_levels`] is set. Nothing is created for them.
+    pub fn check_z_levels(
+        &self,
+        map_z_levels: RangeInclusive<usize>,
+        world_max_z: usize,
+    ) -> Result<()> {
+        let Some(z_levels) = &self.z_levels else {
+            return Ok(());
+        };
+        for map_z in map_z_levels {
+            let Some(&target_z) = z_levels.get(map_z - 1) else {
+                return Err(LoadError::BadOption {
+                    var: "z_levels",
+                    value: format!("{z_levels:?}"),
+                })
+                .wrap_err(format!("No z-level given for the map's z-level {map_z}"));
+            };
+            if target_z == 0 || target_z > world_max_z {
+                return Err(LoadError::NoSuchZLevel(target_z).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The offset that puts the middle tile of the map on [`LoadOptions::center_on`], if it's set.
+    /// `parsed_bounds` is (minx, miny, minz, maxx, maxy, maxz) of the map.
+    ///
+    /// With an even size there are two middle tiles, and the lower one is used, so a 4 wide map
+    /// centered on x 10 covers x 9 to 12.
+    pub fn centered_offset(
+        &self,
+        parsed_bounds: (usize, usize, usize, usize, usize, usize),
+    ) -> Result<Option<(f32, f32, f32)>> {
+        let Some(center_on) = self.center_on else {
+            return Ok(None);
+        };
+        let middle = (
+            (parsed_bounds.0 + parsed_bounds.3) / 2,
+            (parsed_bounds.1 + parsed_bounds.4) / 2,
+            (parsed_bounds.2 + parsed_bounds.5) / 2,
+        );
+        // (1, 1, 1) goes at the offset, so the middle goes at offset + middle - 1
+        let offset = |center: usize, middle: usize| (center + 1).checked_sub(middle);
+        match (
+            offset(center_on.0, middle.0),
+            offset(center_on.1, middle.1),
+            offset(center_on.2, middle.2),
+        ) {
+            (Some(x), Some(y), Some(z)) if x > 0 && y > 0 && z > 0 => {
+                Ok(Some((x as f32, y as f32, z as f32)))
+            }
+            _ => Err(LoadError::BadOption {
+                var: "center_on",
+                value: format!("{center_on:?}"),
+            })
+            .wrap_err(format!(
+                "The map's middle is at {middle:?}, so centering it there would put it off the world"
+            )),
+        }
+    }
+
+    /// Errors if the map has more tiles or movables than [`LoadOptions::max_tiles`] and
+    /// [`LoadOptions::max_atoms`] allow. These are counted before cropping or filtering.
+    pub fn check_budget(&self, tiles: usize, atoms: usize) -> Result<()> {
+        for (what, count, max) in [
+            ("tiles", tiles, self.max_tiles),
+            ("atoms", atoms, self.max_atoms),
+        ] {
+            if let Some(max) = max.filter(|&max| count > max) {
+                return Err(LoadError::OverBudget { what, count, max }.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The world z-level that the map's `map_z` goes onto.
+    pub fn target_z(&self, map_z: usize, z_offset: f32) -> usize {
+        match &self.z_levels {
+            // Anything without an entry was caught by check_z_levels, 0 is never placed
+            Some(z_levels) => map_z
+                .checked_sub(1)
+                .and_then(|index| z_levels.get(index))
+                .copied()
+                .unwrap_or(0),
+            None => map_z + z_offset as usize - 1,
+        }
+    }
+
+    /// Where a file literal points to, taking [`LoadOptions::file_base_dir`] into account.
+    pub fn resolve_file<'a>(&self, file: &'a str) -> Cow<'a, str> {
+        match &self.file_base_dir {
+            Some(base_dir) if !Path::new(file).is_absolute() => {
+                Cow::Owned(format!("{}/{file}", base_dir.trim_end_matches(['/', '\\'])))
+            }
+            _ => Cow::Borrowed(file),
+        }
+    }
+
+    /// The vars to set, after [`LoadOptions::literal_transform`] has been applied to them.
+    pub fn transform_vars<'v, 's>(
+        &self,
+        vars: &'v [(&'s str, Literal<'s>)],
+    ) -> Vec<(&'s str, Cow<'v, Literal<'s>>)> {
+        let quarter_turns = if self.rotate_dirs {
+            self.rotation.quarter_turns()
+        } else {
+            0
+        };
+        vars.iter()
+            .filter_map(|(key, literal)| match self.literal_transform {
+                Some(transform) => {
+                    transform(key, literal).map(|literal| (*key, Cow::Owned(literal)))
+                }
+                None => Some((*key, Cow::Borrowed(literal))),
+            })
+            // Anything that faces a direction turns along with the map, if asked to
+            .map(|(key, literal)| match *literal {
+                Literal::Number(dir) if key == "dir" && quarter_turns > 0 => (
+                    key,
+                    Cow::Owned(Literal::Number(rotate_dir(dir, quarter_turns))),
+                ),
+                _ => (key, literal),
+            })
+            .collect()
+    }
+
+    /// Whether `instance` should be loaded at all.
+    pub fn should_load(&self, instance: &Prefab) -> bool {
+        match &self.variant_filter {
+            Some(filter) => filter.allows(instance),
+            None => true,
+        }
+    }
+}
+
+fn read_number_or_zero(options: ByondValue, var: &str) -> Result<f32> {
+    let value = options
+        .read_var(var)
+        .with_context(|| format!("Failed to read load_options.{var}"))?;
+    if value.is_null() {
+        return Ok(0.);
+    }
+    value
+        .get_number()
+        .with_context(|| format!("load_options.{var} is not a number"))
+}
+
+fn read_optional_count(options: ByondValue, var: &str) -> Result<Option<usize>> {
+    let value = options
+        .read_var(var)
+        .with_context(|| format!("Failed to read load_options.{var}"))?;
+    if value.is_null() {
+        return Ok(None);
+    }
+    value
+        .get_number()
+        .map(|count| Some(count as usize))
+        .with_context(|| format!("load_options.{var} is not a number"))
+}
+
+/// Mirrors the `BAPI_LOAD_MODE_*` defines.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LoadMode {
+    #[default]
+    Full,
+    TurfsAndAreas,
+    AreasOnly,
+    AtomsOnly,
+}
+
+impl LoadMode {
+    fn from_define(mode: &str) -> Result<Self> {
+        match mode {
+            "full" => Ok(LoadMode::Full),
+            "turfs_and_areas" => Ok(LoadMode::TurfsAndAreas),
+            "areas_only" => Ok(LoadMode::AreasOnly),
+            "atoms_only" => Ok(LoadMode::AtomsOnly),
+            _ => Err(LoadError::BadOption {
+                var: "load_mode",
+                value: mode.to_owned(),
+            }
+            .into()),
+        }
+    }
+
+    pub fn places_areas(self) -> bool {
+        matches!(
+            self,
+            LoadMode::Full | LoadMode::TurfsAndAreas | LoadMode::AreasOnly
+        )
+    }
+
+    pub fn places_turfs(self) -> bool {
+        matches!(self, LoadMode::Full | LoadMode::TurfsAndAreas)
+    }
+
+    pub fn places_atoms(self) -> bool {
+        matches!(self, LoadMode::Full | LoadMode::AtomsOnly)
+    }
+}
+
+/// How far a map is turned clockwise while it loads, from `load_options.rotation` in degrees.
+///
+/// Tiles are turned around the map's own bounds, so the rotated map has the same bottom left corner
+/// it had before, and the offset puts that corner in the world the same as it would unrotated.
+/// In full, a tile's coordinate in the map becomes one in the world by:
+/// 1. Mirroring it top to bottom, for [`LoadOptions::flip_y`].
+/// 2. Rotating it, around the map's own bounds.
+/// 3. Checking it against the load's bounds, which are in the same coordinates as the map.
+/// 4. Adding the offset.
+///
+/// With [`LoadOptions::rotate_dirs`], explicit `dir` vars are turned along with it, see [`rotate_dir`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    Cw0,
+    Cw90,
+    Cw180,
+    Cw270,
+}
+
+impl Rotation {
+    fn from_degrees(degrees: f32) -> Result<Self> {
+        match degrees {
+            0. => Ok(Rotation::Cw0),
+            90. => Ok(Rotation::Cw90),
+            180. => Ok(Rotation::Cw180),
+            270. => Ok(Rotation::Cw270),
+            _ => Err(LoadError::BadOption {
+                var: "rotation",
+                value: format_number(degrees),
+            })
+            .wrap_err("load_options.rotation must be 0, 90, 180 or 270"),
+        }
+    }
+
+    pub fn quarter_turns(self) -> u32 {
+        self as u32
+    }
+
+    /// Where `coord` ends up, with the map turned inside of `bounds`, (minx, miny, minz, maxx, maxy, maxz).
+    ///
+    /// Returns None for a turned map if `coord` is outside of `bounds`, as there's nowhere for it to
+    /// go. A short key left over at the end of a row is one of those.
+    pub fn rotate(
+        self,
+        coord: (usize, usize, usize),
+        bounds: (usize, usize, usize, usize, usize, usize),
+    ) -> Option<(usize, usize, usize)> {
+        if self == Rotation::Cw0 {
+            return Some(coord);
+        }
+        if !(bounds.0..=bounds.3).contains(&coord.0) || !(bounds.1..=bounds.4).contains(&coord.1) {
+            return None;
+        }
+        // From the bottom left corner, and the size before turning
+        let (x, y) = (coord.0 - bounds.0, coord.1 - bounds.1);
+        let (width, height) = (bounds.3 - bounds.0 + 1, bounds.4 - bounds.1 + 1);
+        let (x, y) = match self {
+            Rotation::Cw0 => (x, y),
+            Rotation::Cw90 => (y, width - 1 - x),
+            Rotation::Cw180 => (width - 1 - x, height - 1 - y),
+            Rotation::Cw270 => (height - 1 - y, x),
+        };
+        Some((bounds.0 + x, bounds.1 + y, coord.2))
+    }
+
+    /// The bounds of the map once it's turned, the same corner with the sides swapped for quarter turns.
+    pub fn rotate_bounds(
+        self,
+        bounds: (usize, usize, usize, usize, usize, usize),
+    ) -> (usize, usize, usize, usize, usize, usize) {
+        if self.quarter_turns() % 2 == 0 {
+            return bounds;
+        }
+        let (width, height) = (bounds.3 - bounds.0 + 1, bounds.4 - bounds.1 + 1);
+        (
+            bounds.0,
+            bounds.1,
+            bounds.2,
+            bounds.0 + height - 1,
+            bounds.1 + width - 1,
+            bounds.5,
+        )
+    }
+}
+
+/// Mirrors the `BAPI_AREA_POLICY_*` defines.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AreaPolicy {
+    /// Every tile goes into the map's area, whatever it was in before.
+    #[default]
+    Replace,
+    /// Only tiles still in the default `world.area` are moved into the map's area.
+    Merge,
+}
+
+impl AreaPolicy {
+    fn from_define(policy: &str) -> Result<Self> {
+        match policy {
+            "replace" => Ok(AreaPolicy::Replace),
+            "merge" => Ok(AreaPolicy::Merge),
+            _ => Err(LoadError::BadOption {
+                var: "area_policy",
+                value: policy.to_owned(),
+            }
+            .into()),
+        }
+    }
+}
+
+/// Mirrors the `BAPI_FALLBACK_*` defines.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FallbackPolicy {
+    /// The value is set as a string of whatever was written in the map.
+    #[default]
+    AsString,
+    /// The var isn't set, with a warning.
+    Skip,
+    /// The atom fails to be created.
+    Fail,
+}
+
+impl FallbackPolicy {
+    fn from_define(policy: &str) -> Result<Self> {
+        match policy {
+            "as_string" => Ok(FallbackPolicy::AsString),
+            "skip" => Ok(FallbackPolicy::Skip),
+            "fail" => Ok(FallbackPolicy::Fail),
+            _ => Err(LoadError::BadOption {
+                var: "fallback_policy",
+                value: policy.to_owned(),
+            }
+            .into()),
+        }
+    }
+}
+
+/// Typepath -> var names that are valid on that type.
+#[derive(Debug, Default)]
+pub struct VarWhitelist(pub HashMap<String, HashSet<String>>);
+
+impl VarWhitelist {
+    /// Reads an assoc list of `typepath = list("var", "names")`.
+    fn from_byond(list: ByondValue) -> Result<Self> {
+        let mut whitelist = HashMap::new();
+        for (path, vars) in list
+            .iter()
+            .context("load_options.var_whitelist is not a list")?
+        {
+            let path = path.get_string()?;
+            let vars = vars
+                .values()
+                .with_context(|| format!("load_options.var_whitelist[{path}] is not a list"))?
+                .map(|var| var.get_string())
+                .collect::<Result<HashSet<_>, _>>()?;
+            whitelist.insert(path, vars);
+        }
+        Ok(VarWhitelist(whitelist))
+    }
+
+    /// Returns true if `var` is valid on `path` or any of its parent types.
+    /// Types that neither they nor their parents have an entry for can't be checked, so are always allowed.
+    pub fn allows(&self, path: &str, var: &str) -> bool {
+        let mut known_type = false;
+        let mut current = Some(path);
+        while let Some(type_path) = current {
+            if let Some(vars) = self.0.get(type_path) {
+                if vars.contains(var) {
+                    return true;
+                }
+                known_type = true;
+            }
+            current = parent_type(type_path);
+        }
+        !known_type
+    }
+}
+
+/// The parent of a typepath, including the implicit parents of the builtin types.
+fn parent_type(path: &str) -> Option<&str> {
+    match path {
+        "/datum" => None,
+        "/atom" => Some("/datum"),
+        "/atom/movable" | "/turf" | "/area" => Some("/atom"),
+        "/obj" | "/mob" => Some("/atom/movable"),
+        _ => match path.rfind('/') {
+            Some(0) | None => Some("/datum"),
+            Some(index) => Some(&path[..index]),
+        },
+    }
+}
+
+/// Only loads instances whose `var` is one of `allowed`, for having several variants of a map in one file.
+#[derive(Debug, Default)]
+pub struct VariantFilter {
+    pub var: String,
+    pub allowed: HashSet<String>,
+}
+
+impl VariantFilter {
+    fn from_byond(var: String, allowed: ByondValue) -> Result<Self> {
+        // No list means no variants are allowed, only untagged instances load
+        let allowed = if allowed.is_null() {
+            HashSet::new()
+        } else {
+            allowed
+                .values()
+                .context("load_options.allowed_variants is not a list")?
+                .map(|variant| variant.get_string())
+                .collect::<Result<_, _>>()?
+        };
+        Ok(VariantFilter { var, allowed })
+    }
+
+    /// Instances without the tag are always allowed.
+    pub fn allows(&self, instance: &Prefab) -> bool {
+        let Some((_, value)) = instance
+            .1
+            .iter()
+            .flatten()
+            .find(|(var, _)| *var == self.var)
+        else {
+            return true;
+        };
+        match value {
+            Literal::String(variant) => self.allowed.contains(*variant),
+            Literal::Number(variant) => self.allowed.contains(&format_number(*variant)),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transform_vars() {
+        fn rename_enum<'a>(key: &str, literal: &Literal<'a>) -> Option<Literal<'a>> {
+            match (key, literal) {
+                ("dir_name", Literal::String("north")) => Some(Literal::String("NORTH")),
+                ("obsolete", _) => None,
+                _ => Some(literal.clone()),
+            }
+        }
+
+        let vars = vec![
+            ("dir_name", Literal::String("north")),
+            ("obsolete", Literal::Number(1.)),
+            ("name", Literal::String("north")),
+        ];
+        let options = LoadOptions {
+            literal_transform: Some(rename_enum),
+            ..Default::default()
+        };
+        assert_eq!(
+            options
+                .transform_vars(&vars)
+                .into_iter()
+                .map(|(key, literal)| (key, literal.into_owned()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("dir_name", Literal::String("NORTH")),
+                ("name", Literal::String("north")),
+            ]
+        );
+
+        // Untouched without one
+        assert_eq!(LoadOptions::default().transform_vars(&vars).len(), 3);
+    }
+
+    #[test]
+    fn test_rotation() {
+        // 3 wide and 2 tall, away from the origin
+        let bounds = (2, 5, 1, 4, 6, 1);
+        let top_left = (2, 6, 1);
+
+        assert_eq!(Rotation::Cw0.rotate(top_left, bounds), Some(top_left));
+        // Turning clockwise takes the top left corner to the top right, of a 2 wide and 3 tall map
+        assert_eq!(Rotation::Cw90.rotate(top_left, bounds), Some((3, 7, 1)));
+        assert_eq!(Rotation::Cw180.rotate(top_left, bounds), Some((4, 5, 1)));
+        assert_eq!(Rotation::Cw270.rotate(top_left, bounds), Some((2, 5, 1)));
+        // Past the right edge, there's nowhere to turn it to
+        assert_eq!(Rotation::Cw90.rotate((5, 5, 1), bounds), None);
+        assert_eq!(Rotation::Cw0.rotate((5, 5, 1), bounds), Some((5, 5, 1)));
+
+        assert_eq!(Rotation::Cw90.rotate_bounds(bounds), (2, 5, 1, 3, 7, 1));
+        assert_eq!(Rotation::Cw180.rotate_bounds(bounds), bounds);
+
+        assert_eq!(Rotation::from_degrees(270.).unwrap(), Rotation::Cw270);
+        assert!(Rotation::from_degrees(45.).is_err());
+        assert!(Rotation::from_degrees(-90.).is_err());
+    }
+
+    #[test]
+    fn test_rotated_dir_vars() {
+        let mut options = LoadOptions {
+            rotation: Rotation::Cw90,
+            rotate_dirs: true,
+            ..Default::default()
+        };
+        let vars = [
+            ("dir", Literal::Number(1.)),
+            ("pixel_x", Literal::Number(1.)),
+        ];
+        let transform = |options: &LoadOptions| -> Vec<_> {
+            options
+                .transform_vars(&vars)
+                .into_iter()
+                .map(|(key, literal)| (key, literal.into_owned()))
+                .collect()
+        };
+        // NORTH turns to EAST, nothing else changes
+        assert_eq!(
+            transform(&options),
+            vec![
+                ("dir", Literal::Number(4.)),
+                ("pixel_x", Literal::Number(1.))
+            ]
+        );
+
+        // Left alone unless asked for
+        options.rotate_dirs = false;
+        assert_eq!(transform(&options), vars.to_vec());
+    }
+
+    #[test]
+    fn test_rotate_dir() {
+        // NORTH -> EAST -> SOUTH -> WEST -> NORTH
+        assert_eq!(rotate_dir(1., 1), 4.);
+        assert_eq!(rotate_dir(4., 1), 2.);
+        assert_eq!(rotate_dir(2., 1), 8.);
+        assert_eq!(rotate_dir(8., 1), 1.);
+        assert_eq!(rotate_dir(1., 4), 1.);
+        // NORTHEAST -> SOUTHWEST
+        assert_eq!(rotate_dir(5., 2), 10.);
+        // SOUTHWEST -> NORTHWEST
+        assert_eq!(rotate_dir(10., 1), 9.);
+
+        // Not directions, so left alone
+        assert_eq!(rotate_dir(0., 1), 0.);
+        assert_eq!(rotate_dir(16., 1), 16.);
+        assert_eq!(rotate_dir(3., 1), 3.);
+        assert_eq!(rotate_dir(1.5, 1), 1.5);
+    }
+
+    #[test]
+    fn test_check_budget() {
+        let options = LoadOptions {
+            max_tiles: Some(100),
+            max_atoms: Some(10),
+            ..Default::default()
+        };
+        assert!(options.check_budget(100, 10).is_ok());
+        assert_eq!(
+            options
+                .check_budget(100, 11)
+                .unwrap_err()
+                .downcast_ref::<LoadError>(),
+            Some(&LoadError::OverBudget {
+                what: "atoms",
+                count: 11,
+                max: 10
+            })
+        );
+        assert!(options.check_budget(101, 0).is_err());
+        assert!(LoadOptions::default()
+            .check_budget(usize::MAX, usize::MAX)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_clamped_offset() {
+        let options = LoadOptions {
+            clamp_to_world: true,
+            ..Default::default()
+        };
+        let map = (1, 1, 1, 5, 5, 1);
+
+        // Hanging off the top right is moved back in, the z-level stays
+        assert_eq!(
+            options.clamped_offset((98., 10., 3.), map, (100, 100, 1)),
+            Some((96., 10., 3.))
+        );
+        // And the bottom left
+        assert_eq!(
+            options.clamped_offset((-2., 0., 1.), map, (100, 100, 1)),
+            Some((1., 1., 1.))
+        );
+        // Already fits
+        assert_eq!(
+            options.clamped_offset((96., 96., 1.), map, (100, 100, 1)),
+            None
+        );
+        // Too big to fit, so it goes in the corner
+        assert_eq!(
+            options.clamped_offset((3., 3., 1.), map, (4, 4, 1)),
+            Some((1., 1., 1.))
+        );
+
+        assert_eq!(
+            LoadOptions::default().clamped_offset((98., 98., 1.), map, (100, 100, 1)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_centered_offset() {
+        let centered = |center_on, parsed_bounds| {
+            LoadOptions {
+                center_on: Some(center_on),
+                ..Default::default()
+            }
+            .centered_offset(parsed_bounds)
+        };
+
+        // 5x5, the middle is (3, 3)
+        assert_eq!(
+            centered((10, 10, 2), (1, 1, 1, 5, 5, 1)).unwrap(),
+            Some((8., 8., 2.))
+        );
+        // 4x4 rounds down to (2, 2), so it covers 9 to 12
+        assert_eq!(
+            centered((10, 10, 2), (1, 1, 1, 4, 4, 1)).unwrap(),
+            Some((9., 9., 2.))
+        );
+        // Exactly fits against the edge of the world
+        assert_eq!(
+            centered((3, 3, 1), (1, 1, 1, 5, 5, 1)).unwrap(),
+            Some((1., 1., 1.))
+        );
+        assert!(centered((2, 3, 1), (1, 1, 1, 5, 5, 1)).is_err());
+
+        assert_eq!(
+            LoadOptions::default()
+                .centered_offset((1, 1, 1, 5, 5, 1))
+                .unwrap(),
+            None
+        );
+    }
+}
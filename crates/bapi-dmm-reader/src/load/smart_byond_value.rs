@@ -19,6 +19,7 @@ impl From<ByondValue> for SmartByondValue {
     }
 }
 
+/// This also runs while unwinding from a panic, which is how an interrupted load gives its refs back.
 impl Drop for SmartByondValue {
     fn drop(&mut self) {
         self._internal.decrement_ref()
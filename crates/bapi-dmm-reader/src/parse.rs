@@ -1,11 +1,18 @@
 //! All things to do with parsing the map in preparation for loading it
 use byondapi::prelude::*;
-use eyre::eyre;
-use std::{collections::HashMap, path::Path};
+use dmm_lite::{
+    block::{check_key_length, declared_size, intended_size, map_bounds},
+    prefabs::detect_key_length,
+    writer::merge_maps,
+};
+use eyre::{eyre, Context};
+use std::path::Path;
 
 use crate::{
     _compat::setup_panic_handler,
-    arena::{get_arena, ArenaMap, PARSED_MAPS_ARENABASED},
+    arena::{get_parsed_map, ArenaMap, PARSED_MAPS_ARENABASED},
+    error::LoadError,
+    load::helpers::ParsedMapTranslationLayer,
 };
 
 const MAP_TGM: &str = "tgm";
@@ -16,7 +23,17 @@ const MAP_DMM: &str = "dmm";
 /// Any caching must be done in DM
 pub fn _bapidmm_parse_map_blocking(dmm_file: ByondValue, mut map_datum: ByondValue) {
     setup_panic_handler();
+    let result = parse_map_blocking(dmm_file, &mut map_datum);
+    ParsedMapTranslationLayer {
+        parsed_map: map_datum,
+    }
+    .record_error(result)
+}
 
+fn parse_map_blocking(
+    dmm_file: ByondValue,
+    map_datum: &mut ByondValue,
+) -> eyre::Result<ByondValue> {
     if !dmm_file.is_str() {
         return Err(eyre!("dmm_file was not a string: {dmm_file:#?}"));
     }
@@ -25,44 +42,55 @@ pub fn _bapidmm_parse_map_blocking(dmm_file: ByondValue, mut map_datum: ByondVal
 
     let path = Path::new(&dmm_file_str);
     if !path.is_file() {
-        return Err(eyre!("Unable to find {dmm_file_str:#?} on disk"));
+        return Err(LoadError::FileNotFound(dmm_file_str).into());
     }
 
     let string = std::fs::read_to_string(path)
         .map_err(|e| eyre!("Failed to read {dmm_file_str:#?}: {e:#?}"))?;
 
-    // SAFETY: Only called from main thread.
-    let string = unsafe { get_arena() }.alloc(string);
+    let options = dmm_lite::ParseOptions {
+        blank_rows: map_datum
+            .read_var("blank_rows_as_space")
+            .context("Failed to read blank_rows_as_space")?
+            .is_true(),
+    };
 
-    let map = dmm_lite::parse_map_multithreaded(
+    let map = ArenaMap::new(
         path.file_name()
             .map(|s| s.to_string_lossy())
             .unwrap_or(std::borrow::Cow::Owned("<unk filename>".to_owned()))
             .to_string(),
         string,
+        options,
     )
-    .map(|parsed_data| ArenaMap {
-        parsed_data,
-        command_buffers: HashMap::new(),
-    })
-    .map_err(|e| eyre!("Error parsing {dmm_file_str:#?}: {e:#?}"))?;
+    .map_err(|e| LoadError::Parse {
+        path: dmm_file_str.clone(),
+        details: format!("{e:#?}"),
+    })?;
+    // It parses fine, but every tile would come out wrong
+    let (prefabs, blocks) = &map.parsed_data.1;
+    check_key_length(prefabs, blocks).map_err(|e| LoadError::Parse {
+        path: dmm_file_str.clone(),
+        details: e.to_string(),
+    })?;
 
     map_datum.write_var("original_path", &dmm_file)?;
 
+    register_map(map_datum, map)
+}
+
+/// Fills in `map_datum` from `map`, and keeps `map` around for it.
+fn register_map(map_datum: &mut ByondValue, map: ArenaMap<'static>) -> eyre::Result<ByondValue> {
     map_datum.write_var(
         "map_format",
-        &ByondValue::new_str(if map.parsed_data.0.is_tgm {
-            MAP_TGM
-        } else {
-            MAP_DMM
-        })?,
+        &ByondValue::new_str(map_format(map.parsed_data.0.is_tgm))?,
     )?;
 
-    find_metadata(&mut map_datum, &map.parsed_data)?;
+    find_metadata(map_datum, &map.parsed_data)?;
 
     let index = {
-        let mut maps_list = unsafe { PARSED_MAPS_ARENABASED.borrow_mut() };
-        maps_list.push(map);
+        let maps_list = unsafe { PARSED_MAPS_ARENABASED.get_mut() };
+        maps_list.push(Some(map));
         maps_list.len() - 1
     };
 
@@ -71,19 +99,147 @@ pub fn _bapidmm_parse_map_blocking(dmm_file: ByondValue, mut map_datum: ByondVal
     Ok(ByondValue::new_num(1.0))
 }
 
-// Maploader bounds indices
-/// The maploader index for the maps minimum x
-const MAP_MINX: usize = 0;
-/// The maploader index for the maps minimum y
-const MAP_MINY: usize = 1;
-/// The maploader index for the maps minimum z
-const MAP_MINZ: usize = 2;
-/// The maploader index for the maps maximum x
-const MAP_MAXX: usize = 3;
-/// The maploader index for the maps maximum y
-const MAP_MAXY: usize = 4;
-/// The maploader index for the maps maximum z
-const MAP_MAXZ: usize = 5;
+#[byondapi::bind]
+/// Combines several parsed maps into `map_datum`, so that one load places all of them.
+/// `parts` is a list of `list(parsed_map, x_offset, y_offset, z_offset)`, where each offset is
+/// where that map's (1, 1, 1) goes. The merged map is parsed data of its own, so the parts can be
+/// freed afterwards. See [`merge_maps`] for how keys are handed out.
+pub fn _bapidmm_merge_maps(parts: ByondValue, mut map_datum: ByondValue) {
+    setup_panic_handler();
+    let result = merge_parsed_maps(parts, &mut map_datum);
+    ParsedMapTranslationLayer {
+        parsed_map: map_datum,
+    }
+    .record_error(result)
+}
+
+fn merge_parsed_maps(parts: ByondValue, map_datum: &mut ByondValue) -> eyre::Result<ByondValue> {
+    let mut placements = vec![];
+    for part in parts.values().context("parts is not a list")? {
+        let part: Vec<ByondValue> = part
+            .values()
+            .context("Each part must be list(parsed_map, x_offset, y_offset, z_offset)")?
+            .collect();
+        let [parsed_map, x, y, z] = part[..] else {
+            return Err(eyre!(
+                "Each part must be list(parsed_map, x_offset, y_offset, z_offset), found {} items",
+                part.len()
+            ));
+        };
+        let index = ParsedMapTranslationLayer { parsed_map }.get_internal_index()?;
+        let offset = (x.get_number()?, y.get_number()?, z.get_number()?);
+        if offset.0 < 1. || offset.1 < 1. || offset.2 < 1. {
+            return Err(eyre!("Offsets start at 1, found {offset:?}"));
+        }
+        placements.push((
+            index as usize,
+            (offset.0 as usize, offset.1 as usize, offset.2 as usize),
+        ));
+    }
+
+    let text = {
+        let maps = unsafe { &*PARSED_MAPS_ARENABASED.get_mut() };
+        let mut parts = vec![];
+        for (index, offset) in placements {
+            let map = maps
+                .get(index)
+                .and_then(Option::as_ref)
+                .ok_or(LoadError::BadInternalIndex(index))?;
+            parts.push((&map.parsed_data.1, offset));
+        }
+        merge_maps(&parts).to_dmm()
+    };
+
+    // Blank rows of the parts are written out as they are
+    let options = dmm_lite::ParseOptions { blank_rows: true };
+    let map = ArenaMap::new("merged".to_owned(), text, options).map_err(|e| LoadError::Parse {
+        path: "merged".to_owned(),
+        details: format!("{e:#?}"),
+    })?;
+    register_map(map_datum, map)
+}
+
+#[byondapi::bind]
+/// Returns "tgm" or "dmm", whichever the map was detected as when it was parsed
+pub fn _bapidmm_get_map_format(parsed_map: ByondValue) {
+    setup_panic_handler();
+    let parsed_map = ParsedMapTranslationLayer { parsed_map };
+    Ok(ByondValue::new_str(map_format(parsed_map.is_tgm()?))?)
+}
+
+#[byondapi::bind]
+/// Returns what's known about the map without loading it, as
+/// `list("width", "height", "depth", "bounds", "key_len", "blocks", "prefabs", "format", "intended_size", "size_declared")`.
+/// bounds is list(minx, miny, minz, maxx, maxy, maxz), or null (with a 0 size) if the map has no tiles.
+/// intended_size is list(x, y, z), the world size from a `// @size XxYxZ` comment in the map's header
+/// if size_declared, or else the far corner of bounds. Compare the two to find maps that were cropped wrong.
+pub fn _bapidmm_map_info(parsed_map: ByondValue) {
+    setup_panic_handler();
+    let mut parsed_map = ParsedMapTranslationLayer { parsed_map };
+    let result = parsed_map
+        .get_internal_index()
+        .and_then(|id| map_info(id as usize));
+    parsed_map.record_error(result)
+}
+
+fn map_info(id: usize) -> eyre::Result<ByondValue> {
+    let internal_data = unsafe { get_parsed_map(id) }?;
+    let (info, (prefabs, blocks)) = &internal_data.parsed_data;
+    let key_len = detect_key_length(prefabs);
+
+    let declared = declared_size(internal_data.text);
+    let intended_size = match intended_size(internal_data.text, blocks, key_len) {
+        Some((x, y, z)) => {
+            let list = ByondValue::new_list()?;
+            list.write_list(&[x, y, z].map(|coord| ByondValue::new_num(coord as f32)))?;
+            list
+        }
+        None => ByondValue::null(),
+    };
+
+    let (size, bounds) = match map_bounds(blocks, key_len) {
+        Some((min, max)) => {
+            let bounds = ByondValue::new_list()?;
+            bounds.write_list(
+                &[min.0, min.1, min.2, max.0, max.1, max.2]
+                    .map(|coord| ByondValue::new_num(coord as f32)),
+            )?;
+            (
+                (max.0 - min.0 + 1, max.1 - min.1 + 1, max.2 - min.2 + 1),
+                bounds,
+            )
+        }
+        None => ((0, 0, 0), ByondValue::null()),
+    };
+
+    let mut list = ByondValue::new_list()?;
+    for (key, value) in [
+        ("width", ByondValue::new_num(size.0 as f32)),
+        ("height", ByondValue::new_num(size.1 as f32)),
+        ("depth", ByondValue::new_num(size.2 as f32)),
+        ("bounds", bounds),
+        ("key_len", ByondValue::new_num(key_len as f32)),
+        ("blocks", ByondValue::new_num(blocks.len() as f32)),
+        ("prefabs", ByondValue::new_num(prefabs.len() as f32)),
+        ("format", ByondValue::new_str(map_format(info.is_tgm))?),
+        ("intended_size", intended_size),
+        (
+            "size_declared",
+            ByondValue::new_num(if declared.is_some() { 1. } else { 0. }),
+        ),
+    ] {
+        list.write_list_index(ByondValue::new_str(key)?, value)?;
+    }
+    Ok(list)
+}
+
+fn map_format(is_tgm: bool) -> &'static str {
+    if is_tgm {
+        MAP_TGM
+    } else {
+        MAP_DMM
+    }
+}
 
 fn find_metadata(
     metadata: &mut ByondValue,
@@ -104,40 +260,21 @@ fn find_metadata(
 
     metadata.write_var("line_len", &ByondValue::new_num(line_len as f32))?;
 
-    let mut bounds = [
-        f32::INFINITY,
-        f32::INFINITY,
-        f32::INFINITY,
-        f32::NEG_INFINITY,
-        f32::NEG_INFINITY,
-        f32::NEG_INFINITY,
-    ];
-
-    for (coord, lines) in blocks.iter() {
-        // So: maps are defined from top to bottom, left to right
-        // This means that the minimum x and y will always be the minimum coord block we encounter
-        bounds[MAP_MINX] = bounds[MAP_MINX].min(coord.0 as f32);
-        bounds[MAP_MINY] = bounds[MAP_MINY].min(coord.1 as f32);
-        // z-levels must have their own coord block, meaning min/max is simply what we encounter
-        bounds[MAP_MINZ] = bounds[MAP_MINZ].min(coord.2 as f32);
-        bounds[MAP_MAXZ] = bounds[MAP_MAXZ].max(coord.2 as f32);
-        // Now the complicated part: max x, max y
-        // maxx is coord x + line length (in tiles, so divided by key_len), minus one because the left edge is at (x)
-        // Every z-level must be the same size, so we don't have to calculate line length per map
-        bounds[MAP_MAXX] = bounds[MAP_MAXX].max(coord.0 as f32 + (line_len / key_len) as f32 - 1.0);
-        // maxy is slightly more complicated
-        // maxy is coord y + the number of entries in the lines vector (already in tiles), minus one because the top edge is at (y)
-        bounds[MAP_MAXY] = bounds[MAP_MAXY].max(coord.1 as f32 + lines.len() as f32 - 1.0);
-    }
-
-    if bounds.iter().any(|f| f.is_infinite()) {
-        metadata.write_var("parsed_bounds", &ByondValue::null())?;
-        metadata.write_var("bounds", &ByondValue::null())?;
-    } else {
-        let list = ByondValue::new_list()?;
-        list.write_list(&bounds.map(ByondValue::new_num))?;
-        metadata.write_var("parsed_bounds", &list)?;
-        metadata.write_var("bounds", &list)?;
+    // Blocks can start anywhere and be any size, so this is the extent of all of them together
+    match dmm_lite::block::map_bounds(blocks, key_len) {
+        Some((min, max)) => {
+            let list = ByondValue::new_list()?;
+            list.write_list(
+                &[min.0, min.1, min.2, max.0, max.1, max.2]
+                    .map(|coord| ByondValue::new_num(coord as f32)),
+            )?;
+            metadata.write_var("parsed_bounds", &list)?;
+            metadata.write_var("bounds", &list)?;
+        }
+        None => {
+            metadata.write_var("parsed_bounds", &ByondValue::null())?;
+            metadata.write_var("bounds", &ByondValue::null())?;
+        }
     }
 
     Ok(())
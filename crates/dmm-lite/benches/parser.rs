@@ -0,0 +1,73 @@
+//! Benches for the individual parse stages, run single threaded so that changes to the parsers
+//! themselves aren't hidden by rayon.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use dmm_lite::{
+    block::{get_block_locations, parse_block},
+    prefabs::{get_prefab_locations, parse_prefab_line},
+};
+use winnow::Located;
+
+/// (name, path) of each fixture, medium then large
+const FIXTURES: &[(&str, &str)] = &[
+    ("medium", "./tests/maps/MetaStation-64x64.dmm"),
+    ("large", "./tests/maps/nadezhda.dmm"),
+];
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let maps: Vec<(&str, String)> = FIXTURES
+        .iter()
+        .map(|(name, path)| {
+            let map = std::fs::read_to_string(path)
+                .unwrap_or_else(|_| panic!("Failed to load {path} into memory"));
+            (*name, map)
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("parser");
+
+    for (name, map) in &maps {
+        group.throughput(Throughput::Bytes(map.len() as u64));
+
+        group.bench_with_input(
+            BenchmarkId::new("get_prefab_locations", name),
+            map.as_str(),
+            |b, map| b.iter(|| get_prefab_locations(map)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("get_block_locations", name),
+            map.as_str(),
+            |b, map| b.iter(|| get_block_locations(map)),
+        );
+
+        let prefab_locations = get_prefab_locations(map);
+        group.bench_with_input(
+            BenchmarkId::new("parse_prefab_line", name),
+            map.as_str(),
+            |b, map| {
+                b.iter_with_large_drop(|| {
+                    prefab_locations
+                        .iter()
+                        .map(|loc| parse_prefab_line(&mut Located::new(&map[*loc..])).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            },
+        );
+
+        let block_locations = get_block_locations(map);
+        group.bench_with_input(
+            BenchmarkId::new("parse_block", name),
+            map.as_str(),
+            |b, map| {
+                b.iter_with_large_drop(|| {
+                    block_locations
+                        .iter()
+                        .map(|loc| parse_block(&mut Located::new(&map[*loc..])).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            },
+        );
+    }
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,11 @@
+#![no_main]
+//! Malformed maps must produce errors, never panics.
+use dmm_lite::prefabs::parse_prefab_line;
+use libfuzzer_sys::fuzz_target;
+use winnow::Located;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(input) = std::str::from_utf8(data) {
+        let _ = parse_prefab_line(&mut Located::new(input));
+    }
+});
@@ -1,21 +1,41 @@
+use std::ops::Range;
+
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use regex::Regex;
 use winnow::{
-    ascii::{dec_uint, line_ending, space0},
-    combinator::{delimited, opt, repeat, separated_pair, terminated},
+    ascii::{dec_uint, line_ending, space0, space1},
+    combinator::{alt, cut_err, delimited, opt, preceded, repeat, separated_pair, terminated},
+    error::{StrContext, StrContextValue},
     prelude::*,
     stream::Location,
     token::take_while,
     Located,
 };
 
-use crate::LocatedError;
+use crate::{prefabs::Prefabs, LocatedError, ParseOptions};
+
+/// Coordinates start at 1, a 0 can only come from a broken map, and would underflow everything
+/// that turns coordinates into offsets. So it's a hard error here, rather than a guess later.
+fn parse_coord(i: &mut Located<&str>) -> PResult<usize> {
+    cut_err(dec_uint.verify(|coord: &usize| *coord >= 1))
+        .context(StrContext::Label("block coordinate"))
+        .context(StrContext::Expected(StrContextValue::Description(
+            "a number starting at 1",
+        )))
+        .parse_next(i)
+}
 
+/// Hand-edited maps sometimes have spaces in the header, `(1, 1, 1)`, so those are allowed.
 pub fn parse_coords(i: &mut Located<&str>) -> PResult<(usize, usize, usize)> {
+    let comma = (space0, ',', space0);
     delimited(
-        '(',
-        separated_pair(dec_uint, ',', separated_pair(dec_uint, ',', dec_uint)),
-        ')',
+        ('(', space0),
+        separated_pair(
+            parse_coord,
+            comma,
+            separated_pair(parse_coord, comma, parse_coord),
+        ),
+        (space0, ')'),
     )
     .map(|(a, (b, c))| (a, b, c))
     .parse_next(i)
@@ -34,19 +54,369 @@ pub fn parse_map_lines<'s>(i: &mut Located<&'s str>) -> PResult<Vec<&'s str>> {
     .parse_next(i)
 }
 
+/// Like [`parse_map_lines`], but rows of only whitespace are kept as empty rows instead of failing.
+/// See [`ParseOptions::blank_rows`].
+pub fn parse_map_lines_with_blanks<'s>(i: &mut Located<&'s str>) -> PResult<Vec<&'s str>> {
+    let row = alt((
+        terminated(map_characters, opt(line_ending)),
+        terminated(space0, line_ending).map(|_| ""),
+    ));
+    let mut lines: Vec<&str> = delimited(
+        ("{\"", opt(line_ending)),
+        repeat(1.., row),
+        (opt(line_ending), "\"}"),
+    )
+    .parse_next(i)?;
+    // The strict parser allows a blank line before the end too, that one isn't a row
+    if lines.last() == Some(&"") {
+        lines.pop();
+    }
+    Ok(lines)
+}
+
 pub type Block<'s> = ((usize, usize, usize), Vec<&'s str>);
 pub fn parse_block<'s>(i: &mut Located<&'s str>) -> PResult<Block<'s>> {
-    separated_pair(
-        parse_coords,
-        delimited(space0, '=', space0),
-        parse_map_lines,
+    parse_block_with(i, ParseOptions::default())
+}
+
+fn parse_block_with<'s>(i: &mut Located<&'s str>, options: ParseOptions) -> PResult<Block<'s>> {
+    let coords = terminated(parse_coords, delimited(space0, '=', space0)).parse_next(i)?;
+    let lines = if options.blank_rows {
+        parse_map_lines_with_blanks(i)?
+    } else {
+        parse_map_lines(i)?
+    };
+    Ok((coords, lines))
+}
+
+/// The prefab key at `coord`, in the same (1-based) coordinates the blocks were declared with.
+///
+/// Returns None if no block covers `coord`. Where blocks overlap the later one wins, same as when
+/// loading.
+pub fn key_at<'s>(
+    blocks: &[Block<'s>],
+    key_len: usize,
+    coord: (usize, usize, usize),
+) -> Option<&'s str> {
+    if key_len == 0 {
+        return None;
+    }
+
+    blocks.iter().rev().find_map(|(bottom_left, lines)| {
+        if coord.2 != bottom_left.2 {
+            return None;
+        }
+        let map_x_offset = coord.0.checked_sub(bottom_left.0)?;
+        let map_y_offset = coord.1.checked_sub(bottom_left.1)?;
+        // Rows are listed top to bottom, so the last row is at the block's y
+        let line = lines
+            .len()
+            .checked_sub(map_y_offset + 1)
+            .map(|row| lines[row])?;
+        line.get(map_x_offset * key_len..(map_x_offset + 1) * key_len)
+    })
+}
+
+/// Every tile of every block with its key. Blocks are walked in the order they're declared, each
+/// from bottom to top and left to right, so where blocks overlap a coordinate comes up once per
+/// block, and the last time is the key that gets loaded. Only whole keys count.
+pub fn tiles_iter<'a, 's>(
+    blocks: &'a [Block<'s>],
+    key_len: usize,
+) -> impl Iterator<Item = ((usize, usize, usize), &'s str)> + 'a {
+    blocks
+        .iter()
+        .flat_map(move |block| BlockGrid::new(block, key_len).into_tiles())
+}
+
+/// A block split into its keys, so that tiles can be looked up by where they are rather than by
+/// slicing rows. Positions are relative to the block's bottom left corner, starting at (0, 0), with
+/// y counting up the same as the map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockGrid<'s> {
+    /// The bottom left corner, in the same (1-based) coordinates the block was declared with
+    pub origin: (usize, usize, usize),
+    /// Rows from the bottom up, each split into whole keys. A short key at the end of a row is
+    /// left off, the same as when loading.
+    rows: Vec<Vec<&'s str>>,
+    /// The rows as parsed, top to bottom, for [`BlockGrid::into_block`]
+    lines: Vec<&'s str>,
+    /// How many characters each key is
+    key_len: usize,
+}
+
+impl<'s> BlockGrid<'s> {
+    /// Splits `block` into keys of `key_len` characters. With a `key_len` of 0 every row is empty.
+    pub fn new((origin, lines): &Block<'s>, key_len: usize) -> Self {
+        // Rows are listed top to bottom, so the last row is at the block's y
+        let rows = lines
+            .iter()
+            .rev()
+            .map(|line| {
+                if key_len == 0 {
+                    return vec![];
+                }
+                (0..line.len() / key_len)
+                    .map(|x| &line[x * key_len..(x + 1) * key_len])
+                    .collect()
+            })
+            .collect();
+        BlockGrid {
+            origin: *origin,
+            rows,
+            lines: lines.clone(),
+            key_len,
+        }
+    }
+
+    /// How many keys the widest row has
+    pub fn width(&self) -> usize {
+        if self.key_len == 0 {
+            return 0;
+        }
+        self.lines
+            .iter()
+            .map(|line| line.len() / self.key_len)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// How many rows there are, counting empty ones
+    pub fn height(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// The key at (`x`, `y`), or None if that row doesn't reach that far.
+    pub fn get(&self, x: usize, y: usize) -> Option<&'s str> {
+        self.rows.get(y)?.get(x).copied()
+    }
+
+    /// The region the block covers, see [`block_bounds`].
+    pub fn bounds(&self) -> Option<Bounds> {
+        let width = self.width();
+        if width == 0 {
+            return None;
+        }
+        let top_right = (
+            self.origin.0 + width - 1,
+            self.origin.1 + self.height() - 1,
+            self.origin.2,
+        );
+        Some((self.origin, top_right))
+    }
+
+    /// Every key with its coordinate in the map, from bottom to top and left to right.
+    pub fn into_tiles(self) -> impl Iterator<Item = ((usize, usize, usize), &'s str)> {
+        let origin = self.origin;
+        self.rows.into_iter().enumerate().flat_map(move |(y, row)| {
+            row.into_iter()
+                .enumerate()
+                .map(move |(x, key)| ((origin.0 + x, origin.1 + y, origin.2), key))
+        })
+    }
+
+    /// The block this was made from, as [`parse_block`] returns it.
+    pub fn into_block(self) -> Block<'s> {
+        (self.origin, self.lines)
+    }
+}
+
+/// Signs of a map written with more than one key length, see [`check_key_length`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum KeyLengthError {
+    #[error("Prefab keys are different lengths, {0} and {1} characters")]
+    MixedPrefabKeys(usize, usize),
+    #[error("Prefab keys are {key_len} characters long, but the block at {block:?} has rows {row_len} characters long, which can't be split into whole keys")]
+    BlockRows {
+        key_len: usize,
+        row_len: usize,
+        block: (usize, usize, usize),
+    },
+    #[error("Prefab keys are {key_len} characters long, but most rows of the block at {block:?} aren't made of the map's keys when split that way")]
+    UndefinedKeys {
+        key_len: usize,
+        block: (usize, usize, usize),
+    },
+}
+
+/// Checks that every prefab key is the same length, and that every block is made of keys that long.
+/// Returns the key length, as [`crate::prefabs::detect_key_length`] does.
+///
+/// A row is made of keys when it splits evenly and at least half of what it splits into are keys
+/// the map defines, so an undefined key here and there is left for the loader to warn about. A
+/// single row that isn't is allowed too, that's a typo rather than a map using the wrong key
+/// length. It's an error when more than one row isn't and they're at least half of the block, or
+/// when none of its rows are.
+pub fn check_key_length(prefabs: &Prefabs, blocks: &[Block]) -> Result<usize, KeyLengthError> {
+    let mut key_lengths = prefabs.keys().map(|key| key.len());
+    let Some(key_len) = key_lengths.next() else {
+        return Ok(0);
+    };
+    if let Some(other) = key_lengths.find(|&other| other != key_len) {
+        return Err(KeyLengthError::MixedPrefabKeys(
+            key_len.min(other),
+            key_len.max(other),
+        ));
+    }
+
+    let made_of_keys = |line: &str| {
+        if !line.len().is_multiple_of(key_len) {
+            return false;
+        }
+        let keys = line.len() / key_len;
+        let defined = (0..keys)
+            .filter(|x| prefabs.contains_key(&line[x * key_len..(x + 1) * key_len]))
+            .count();
+        defined * 2 >= keys
+    };
+    for (bottom_left, lines) in blocks {
+        let not_keys: Vec<&str> = lines
+            .iter()
+            .copied()
+            .filter(|line| !made_of_keys(line))
+            .collect();
+        let keys = lines.len() - not_keys.len();
+        if not_keys.is_empty() || (keys > 0 && (not_keys.len() == 1 || not_keys.len() < keys)) {
+            continue;
+        }
+        // A row that can't be split at all says the most about what went wrong
+        return Err(
+            match not_keys
+                .iter()
+                .find(|line| !line.len().is_multiple_of(key_len))
+            {
+                Some(line) => KeyLengthError::BlockRows {
+                    key_len,
+                    row_len: line.len(),
+                    block: *bottom_left,
+                },
+                None => KeyLengthError::UndefinedKeys {
+                    key_len,
+                    block: *bottom_left,
+                },
+            },
+        );
+    }
+    Ok(key_len)
+}
+
+/// A region of the map, as inclusive (min, max) corners.
+pub type Bounds = ((usize, usize, usize), (usize, usize, usize));
+
+/// The bounding box of every block combined, as inclusive (min, max) corners in the same (1-based)
+/// coordinates the blocks were declared with.
+///
+/// Blocks don't have to start at (1, 1, 1) or be the same size, so this is the whole area the map
+/// covers. Only whole keys count towards a block's width. Returns None if no block covers anything.
+pub fn map_bounds(blocks: &[Block], key_len: usize) -> Option<Bounds> {
+    if key_len == 0 {
+        return None;
+    }
+
+    blocks
+        .iter()
+        .filter_map(|block| block_bounds(block, key_len))
+        .reduce(|(min, max), (bottom_left, top_right)| {
+            (
+                (
+                    min.0.min(bottom_left.0),
+                    min.1.min(bottom_left.1),
+                    min.2.min(bottom_left.2),
+                ),
+                (
+                    max.0.max(top_right.0),
+                    max.1.max(top_right.1),
+                    max.2.max(top_right.2),
+                ),
+            )
+        })
+}
+
+/// The region a single block covers, or None if it doesn't have a single whole key.
+pub fn block_bounds((bottom_left, lines): &Block, key_len: usize) -> Option<Bounds> {
+    if key_len == 0 {
+        return None;
+    }
+    let width = lines.iter().map(|line| line.len() / key_len).max()?;
+    if width == 0 {
+        return None;
+    }
+    let top_right = (
+        bottom_left.0 + width - 1,
+        bottom_left.1 + lines.len() - 1,
+        bottom_left.2,
+    );
+    Some((*bottom_left, top_right))
+}
+
+/// A `// @size 255x255x1` comment, where a map says how big a world it was made for.
+pub fn parse_size_comment(i: &mut Located<&str>) -> PResult<(usize, usize, usize)> {
+    let size = || dec_uint.verify(|size: &usize| *size >= 1);
+    preceded(
+        (space0, "//", space0, "@size", space1),
+        (size(), 'x', size(), 'x', size()),
     )
+    .map(|(x, _, y, _, z)| (x, y, z))
     .parse_next(i)
 }
 
+/// The size from a [`parse_size_comment`] in the comments before the map's first prefab, if it has one.
+pub fn declared_size(i: &str) -> Option<(usize, usize, usize)> {
+    i.lines()
+        .map(str::trim)
+        .take_while(|line| line.is_empty() || line.starts_with("//"))
+        .find_map(|line| parse_size_comment.parse(Located::new(line)).ok())
+}
+
+/// How big a world the map was made for: its [`declared_size`], or else just big enough for every
+/// block. Comparing this with [`map_bounds`] catches maps that were cropped wrong. Returns None if
+/// neither is known.
+pub fn intended_size(i: &str, blocks: &[Block], key_len: usize) -> Option<(usize, usize, usize)> {
+    declared_size(i).or_else(|| map_bounds(blocks, key_len).map(|(_min, max)| max))
+}
+
+/// Every pair of blocks that cover some of the same tiles, as the indices of both blocks and the
+/// region they overlap in. When loading the later block wins, so this is usually a duplicated block
+/// left behind by a bad merge.
+pub fn block_overlaps(blocks: &[Block], key_len: usize) -> Vec<(usize, usize, Bounds)> {
+    let bounds: Vec<_> = blocks
+        .iter()
+        .map(|block| block_bounds(block, key_len))
+        .collect();
+
+    let mut overlaps = vec![];
+    for (first, first_bounds) in bounds.iter().enumerate() {
+        let Some((first_min, first_max)) = first_bounds else {
+            continue;
+        };
+        for (second, second_bounds) in bounds.iter().enumerate().skip(first + 1) {
+            let Some((second_min, second_max)) = second_bounds else {
+                continue;
+            };
+            let min = (
+                first_min.0.max(second_min.0),
+                first_min.1.max(second_min.1),
+                first_min.2.max(second_min.2),
+            );
+            let max = (
+                first_max.0.min(second_max.0),
+                first_max.1.min(second_max.1),
+                first_max.2.min(second_max.2),
+            );
+            if min.0 <= max.0 && min.1 <= max.1 && min.2 <= max.2 {
+                overlaps.push((first, second, (min, max)));
+            }
+        }
+    }
+    overlaps
+}
+
 /// Used for multithreading: Uses a fast regex to get the starting location of every map block
 pub fn get_block_locations(i: &str) -> Vec<usize> {
-    let re = Regex::new(r#"\((\d+),(\d+),(\d+)\) = \{"#).unwrap();
+    // Allows the same spacing as parse_block
+    let re =
+        Regex::new(r#"\([ \t]*(\d+)[ \t]*,[ \t]*(\d+)[ \t]*,[ \t]*(\d+)[ \t]*\)[ \t]*=[ \t]*\{"#)
+            .unwrap();
 
     let mut results = vec![];
     for offset in re
@@ -59,32 +429,200 @@ pub fn get_block_locations(i: &str) -> Vec<usize> {
     results
 }
 
-pub fn multithreaded_parse_map_locations(i: Located<&str>) -> Result<Vec<Block>, LocatedError> {
-    let locations = get_block_locations(&i);
+pub fn multithreaded_parse_map_locations(i: Located<&str>) -> Result<Vec<Block<'_>>, LocatedError> {
+    parse_blocks_at(i, &get_block_locations(&i), ParseOptions::default()).map(|(blocks, _)| blocks)
+}
 
-    locations
+/// Parses the blocks starting at each of `locations`, the offsets from [`get_block_locations`].
+/// Also returns where the last block ends, for [`trailing_content`], or None without any blocks.
+pub(crate) fn parse_blocks_at<'s>(
+    i: Located<&'s str>,
+    locations: &[usize],
+    options: ParseOptions,
+) -> Result<(Vec<Block<'s>>, Option<usize>), LocatedError> {
+    let parsed: Vec<_> = locations
         .par_iter()
-        .map(|loc| {
-            let mut substring = Located::new(&i[*loc..]);
-            parse_block(&mut substring).map_err(|e| {
-                if let Some(e) = e.into_inner() {
-                    LocatedError {
-                        key_offset: i.location() + *loc,
-                        main_offset: substring.location() + i.location() + *loc,
-                        underlying: e,
-                    }
-                } else {
-                    panic!("Parser produced Incomplete")
-                }
-            })
-        })
-        .collect()
+        .map(|loc| parse_block_at(i, *loc, options))
+        .collect::<Result<_, _>>()?;
+    let end = parsed.last().map(|(_, end)| *end);
+    Ok((parsed.into_iter().map(|(block, _)| block).collect(), end))
+}
+
+/// Parses the block starting at `loc`, one of the offsets from [`get_block_locations`], along
+/// with the offset in `i` just after it.
+fn parse_block_at(
+    i: Located<&str>,
+    loc: usize,
+    options: ParseOptions,
+) -> Result<(Block<'_>, usize), LocatedError> {
+    let mut substring = Located::new(&i[loc..]);
+    match parse_block_with(&mut substring, options) {
+        Ok(block) => Ok((block, loc + substring.location())),
+        Err(e) => Err(if let Some(e) = e.into_inner() {
+            LocatedError {
+                key_offset: i.location() + loc,
+                main_offset: substring.location() + i.location() + loc,
+                underlying: e,
+            }
+        } else {
+            panic!("Parser produced Incomplete")
+        }),
+    }
+}
+
+/// Whatever comes after the last block, which ends at `end` (see [`parse_blocks_at`]), such as a
+/// footer comment added by a tool. The line ending closing the block isn't part of it, and a
+/// trailer of only whitespace is none at all.
+pub fn trailing_content(i: &str, end: usize) -> Option<&str> {
+    let rest = &i[end..];
+    let rest = rest
+        .strip_prefix("\r\n")
+        .or_else(|| rest.strip_prefix('\n'))
+        .unwrap_or(rest);
+    (!rest.trim().is_empty()).then_some(rest)
+}
+
+/// For tooling: Parses only the part of the block starting at `loc` (one of the offsets from
+/// [`get_block_locations`]) that's inside `rect`, without building every row of the block.
+///
+/// Rows outside of `rect` are counted but not checked, so a broken row elsewhere in the block isn't
+/// noticed. Returns None if none of the block is inside `rect`.
+pub fn parse_block_region(
+    i: &str,
+    loc: usize,
+    rect: Bounds,
+    key_len: usize,
+) -> Result<Option<Block<'_>>, LocatedError> {
+    let Some(region) = block_region(&i[loc..], rect, key_len) else {
+        // Couldn't find its way through the block, so let the full parser explain why
+        let ((bottom_left, lines), _) =
+            parse_block_at(Located::new(i), loc, ParseOptions::default())?;
+        return Ok(region_of(bottom_left, lines, rect, key_len));
+    };
+    Ok(region)
+}
+
+/// [`parse_block_region`] without any error reporting. The outer None is a block that doesn't parse.
+fn block_region(i: &str, rect: Bounds, key_len: usize) -> Option<Option<Block<'_>>> {
+    let mut i = Located::new(i);
+    let bottom_left = terminated(parse_coords, delimited(space0, '=', space0))
+        .parse_next(&mut i)
+        .ok()?;
+    let opening: PResult<_> = ("{\"", opt(line_ending)).parse_next(&mut i);
+    opening.ok()?;
+    let body = &i[..i.find("\"}")?];
+    let lines: Vec<&str> = body
+        .split_terminator('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .collect();
+    if lines.is_empty() {
+        return None;
+    }
+
+    let region = region_of(bottom_left, lines, rect, key_len);
+    if let Some((_, lines)) = &region {
+        if lines
+            .iter()
+            .any(|line| !line.chars().all(|c| c.is_ascii_alphabetic()))
+        {
+            return None;
+        }
+    }
+    Some(region)
+}
+
+/// The rows of a block, cut down to what's inside `rect`.
+fn region_of<'s>(
+    bottom_left: (usize, usize, usize),
+    lines: Vec<&'s str>,
+    (min, max): Bounds,
+    key_len: usize,
+) -> Option<Block<'s>> {
+    if key_len == 0 || !(min.2..=max.2).contains(&bottom_left.2) {
+        return None;
+    }
+    let top = bottom_left.1 + lines.len() - 1;
+    let (bottom_y, top_y) = (min.1.max(bottom_left.1), max.1.min(top));
+    let (left_x, right_x) = (min.0.max(bottom_left.0), max.0);
+    if bottom_y > top_y || left_x > right_x {
+        return None;
+    }
+
+    let start = (left_x - bottom_left.0) * key_len;
+    let end = (right_x - bottom_left.0 + 1).saturating_mul(key_len);
+    // Rows are listed top to bottom, so the last row is at the block's y
+    let rows: Vec<&str> = lines[top - top_y..=top - bottom_y]
+        .iter()
+        .map(|line| line.get(start..end.min(line.len())).unwrap_or(""))
+        .collect();
+    if rows.iter().all(|row| row.is_empty()) {
+        return None;
+    }
+    Some(((left_x, bottom_y, bottom_left.2), rows))
+}
+
+/// For editors: Re-parses only the blocks of `i` that overlap `edit`, a byte range of `i` that
+/// was just changed. Each block comes with its index, for replacing the old one.
+///
+/// Returns None if `i` no longer has `block_count` blocks, as then the indices don't line up with
+/// the old blocks anymore, and everything has to be parsed again.
+pub fn reparse_blocks(
+    i: &str,
+    edit: Range<usize>,
+    block_count: usize,
+) -> Result<Option<Vec<(usize, Block<'_>)>>, LocatedError> {
+    let locations = get_block_locations(i);
+    if locations.len() != block_count {
+        return Ok(None);
+    }
+
+    let mut blocks = vec![];
+    for (index, &start) in locations.iter().enumerate() {
+        let end = locations.get(index + 1).copied().unwrap_or(i.len());
+        // An empty edit is an insertion, which still touches the block it's in
+        if start < edit.end.max(edit.start + 1) && edit.start < end {
+            blocks.push((
+                index,
+                parse_block_at(Located::new(i), start, ParseOptions::default())?.0,
+            ));
+        }
+    }
+    Ok(Some(blocks))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_reparse_blocks() {
+        let map = "(1,1,1) = {\"\naa\n\"}\n(3,1,1) = {\"\nab\n\"}\n(5,1,1) = {\"\naa\n\"}\n";
+        let second = map.find("ab").unwrap();
+        let edited = map.replacen("ab", "bb", 1);
+
+        assert_eq!(
+            reparse_blocks(&edited, second..second + 1, 3).unwrap(),
+            Some(vec![(1, ((3, 1, 1), vec!["bb"]))])
+        );
+        // Inserting right at the start of a block
+        let third = map.find("(5").unwrap();
+        assert_eq!(
+            reparse_blocks(map, third..third, 3)
+                .unwrap()
+                .map(|blocks| blocks.into_iter().map(|(index, _)| index).collect()),
+            Some(vec![2])
+        );
+        // Spanning two blocks
+        assert_eq!(
+            reparse_blocks(map, 0..second, 3)
+                .unwrap()
+                .map(|blocks| blocks.len()),
+            Some(2)
+        );
+        // A block was added, so the old ones can't be patched
+        assert_eq!(reparse_blocks(map, 0..1, 2).unwrap(), None);
+    }
+
     #[test]
     fn test_parse_coords() {
         let coords = "(1,2,3)";
@@ -98,6 +636,79 @@ mod tests {
             parse_coords.parse_next(&mut Located::new(bigger_coords)),
             Ok((100, 241, 2))
         );
+        assert_eq!(
+            parse_coords.parse_next(&mut Located::new("( 1, 2 ,\t3 )")),
+            Ok((1, 2, 3))
+        );
+    }
+
+    #[test]
+    fn test_check_key_length() {
+        let prefabs = Prefabs::from([("aa", vec![]), ("ab", vec![])]);
+        let blocks = vec![((1, 1, 1), vec!["aaab", "abaa"])];
+        assert_eq!(check_key_length(&prefabs, &blocks), Ok(2));
+
+        // One short row is left for the loader to warn about
+        let blocks = vec![((1, 1, 1), vec!["aaab", "aba"])];
+        assert_eq!(check_key_length(&prefabs, &blocks), Ok(2));
+
+        let blocks = vec![((1, 1, 1), vec!["aaabaaa", "abaaaab"])];
+        assert_eq!(
+            check_key_length(&prefabs, &blocks),
+            Err(KeyLengthError::BlockRows {
+                key_len: 2,
+                row_len: 7,
+                block: (1, 1, 1)
+            })
+        );
+
+        // Having one row that splits evenly isn't enough when the rest don't
+        let blocks = vec![((1, 1, 1), vec!["aaab", "aaabaaa", "abaaaab"])];
+        assert_eq!(
+            check_key_length(&prefabs, &blocks),
+            Err(KeyLengthError::BlockRows {
+                key_len: 2,
+                row_len: 7,
+                block: (1, 1, 1)
+            })
+        );
+        // Rows of 3 character keys split into 2 characters evenly, but not into the map's keys
+        let blocks = vec![((1, 1, 1), vec!["bbabbb", "bbbbba", "bbbbbb"])];
+        assert_eq!(
+            check_key_length(&prefabs, &blocks),
+            Err(KeyLengthError::UndefinedKeys {
+                key_len: 2,
+                block: (1, 1, 1)
+            })
+        );
+        // An undefined key now and then is still the right key length
+        let blocks = vec![((1, 1, 1), vec!["aaxx", "abaa", "xxab"])];
+        assert_eq!(check_key_length(&prefabs, &blocks), Ok(2));
+
+        let prefabs = Prefabs::from([("aa", vec![]), ("abc", vec![])]);
+        assert_eq!(
+            check_key_length(&prefabs, &[]),
+            Err(KeyLengthError::MixedPrefabKeys(2, 3))
+        );
+        assert_eq!(check_key_length(&Prefabs::new(), &blocks), Ok(0));
+    }
+
+    #[test]
+    fn test_zero_coords() {
+        let error = parse_coords
+            .parse_next(&mut Located::new("(0,1,1)"))
+            .unwrap_err();
+        assert!(matches!(error, winnow::error::ErrMode::Cut(_)));
+        assert!(error
+            .into_inner()
+            .unwrap()
+            .to_string()
+            .contains("a number starting at 1"));
+
+        // Found when parsing a whole map too, at the coordinate
+        let map = "\"a\" = (/turf,/area)\n(1,0,1) = {\"\na\n\"}\n";
+        let error = multithreaded_parse_map_locations(Located::new(map)).unwrap_err();
+        assert_eq!(&map[error.main_offset..error.main_offset + 1], "0");
     }
 
     #[test]
@@ -110,6 +721,198 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_map_lines_with_blanks() {
+        let map_lines = "{\"\naaa\n  \n\t\naaa\n\"}";
+        // Strict parsing still rejects them
+        assert!(parse_map_lines
+            .parse_next(&mut Located::new(map_lines))
+            .is_err());
+        assert_eq!(
+            parse_map_lines_with_blanks.parse_next(&mut Located::new(map_lines)),
+            Ok(vec!["aaa", "", "", "aaa"])
+        );
+        // The usual blank line before the end isn't a row
+        assert_eq!(
+            parse_map_lines_with_blanks.parse_next(&mut Located::new("{\"\naaa\n\n\"}")),
+            Ok(vec!["aaa"])
+        );
+    }
+
+    #[test]
+    fn test_key_at() {
+        let blocks = vec![
+            ((1, 1, 1), vec!["aaab", "acad"]),
+            ((3, 1, 1), vec!["ae"]),
+            ((1, 1, 2), vec!["af"]),
+        ];
+
+        assert_eq!(key_at(&blocks, 2, (1, 1, 1)), Some("ac"));
+        assert_eq!(key_at(&blocks, 2, (2, 2, 1)), Some("ab"));
+        // Overlapped by the later block
+        assert_eq!(key_at(&blocks, 2, (3, 1, 1)), Some("ae"));
+        assert_eq!(key_at(&blocks, 2, (1, 1, 2)), Some("af"));
+
+        assert_eq!(key_at(&blocks, 2, (3, 2, 1)), None);
+        assert_eq!(key_at(&blocks, 2, (1, 3, 1)), None);
+        assert_eq!(key_at(&blocks, 2, (0, 1, 1)), None);
+        assert_eq!(key_at(&blocks, 2, (1, 1, 3)), None);
+        assert_eq!(key_at(&blocks, 0, (1, 1, 1)), None);
+    }
+
+    #[test]
+    fn test_tiles_iter() {
+        let blocks = vec![
+            ((1, 1, 1), vec!["aaab", "acad"]),
+            // Overlaps the first block, and has a half key at the end
+            ((2, 1, 1), vec!["aea"]),
+            ((1, 1, 2), vec!["af"]),
+        ];
+
+        assert_eq!(
+            tiles_iter(&blocks, 2).collect::<Vec<_>>(),
+            vec![
+                ((1, 1, 1), "ac"),
+                ((2, 1, 1), "ad"),
+                ((1, 2, 1), "aa"),
+                ((2, 2, 1), "ab"),
+                ((2, 1, 1), "ae"),
+                ((1, 1, 2), "af"),
+            ]
+        );
+        assert_eq!(tiles_iter(&blocks, 0).count(), 0);
+    }
+
+    #[test]
+    fn test_block_grid() {
+        // A short key left at the end of the top row, and an empty row
+        let block = ((3, 2, 1), vec!["aaaba", "", "acad"]);
+        let grid = BlockGrid::new(&block, 2);
+
+        assert_eq!((grid.width(), grid.height()), (2, 3));
+        assert_eq!(grid.get(0, 0), Some("ac"));
+        assert_eq!(grid.get(1, 2), Some("ab"));
+        assert_eq!(grid.get(0, 1), None);
+        assert_eq!(grid.get(2, 2), None);
+        assert_eq!(grid.get(0, 3), None);
+        assert_eq!(grid.bounds(), Some(((3, 2, 1), (4, 4, 1))));
+        assert_eq!(
+            grid.clone().into_tiles().collect::<Vec<_>>(),
+            vec![
+                ((3, 2, 1), "ac"),
+                ((4, 2, 1), "ad"),
+                ((3, 4, 1), "aa"),
+                ((4, 4, 1), "ab"),
+            ]
+        );
+        assert_eq!(grid.into_block(), block);
+
+        let empty = BlockGrid::new(&block, 0);
+        assert_eq!((empty.width(), empty.bounds()), (0, None));
+    }
+
+    #[test]
+    fn test_map_bounds() {
+        let blocks = vec![
+            ((3, 2, 1), vec!["aaab", "acad"]),
+            ((10, 5, 1), vec!["ae"]),
+            ((2, 4, 2), vec!["af"]),
+        ];
+        assert_eq!(map_bounds(&blocks, 2), Some(((2, 2, 1), (10, 5, 2))));
+        // Single block, starting away from the origin
+        assert_eq!(map_bounds(&blocks[..1], 2), Some(((3, 2, 1), (4, 3, 1))));
+
+        assert_eq!(map_bounds(&[], 2), None);
+        assert_eq!(map_bounds(&blocks, 0), None);
+        // Not even one whole key
+        assert_eq!(map_bounds(&[((1, 1, 1), vec!["a"])], 2), None);
+    }
+
+    #[test]
+    fn test_declared_size() {
+        let header = "//MAP CONVERTED BY dmm2tgm.py THIS HEADER COMMENT PREVENTS RECONVERSION, DO NOT REMOVE\n// @size 255x128x2\n\"a\" = (/turf,/area)\n";
+        assert_eq!(declared_size(header), Some((255, 128, 2)));
+        let blocks = vec![((1, 1, 1), vec!["aa"])];
+        assert_eq!(intended_size(header, &blocks, 1), Some((255, 128, 2)));
+
+        // Only the header counts
+        let late = "\"a\" = (/turf,/area)\n// @size 255x128x2\n";
+        assert_eq!(declared_size(late), None);
+        assert_eq!(intended_size(late, &blocks, 1), Some((2, 1, 1)));
+
+        assert_eq!(declared_size("// @size 0x1x1\n"), None);
+        assert_eq!(declared_size("// @size 10x10\n"), None);
+        assert_eq!(intended_size("", &[], 1), None);
+    }
+
+    #[test]
+    fn test_block_overlaps() {
+        let blocks = vec![
+            ((1, 1, 1), vec!["aaab", "acad"]),
+            // Overlaps the right column of the first block
+            ((2, 2, 1), vec!["aeaf"]),
+            // Same origin on another z-level doesn't count
+            ((1, 1, 2), vec!["aaab", "acad"]),
+            // Touching, but not overlapping
+            ((3, 1, 1), vec!["ag"]),
+            // A duplicate of the first
+            ((1, 1, 1), vec!["aaab", "acad"]),
+        ];
+        assert_eq!(
+            block_overlaps(&blocks, 2),
+            vec![
+                (0, 1, ((2, 2, 1), (2, 2, 1))),
+                (0, 4, ((1, 1, 1), (2, 2, 1))),
+                (1, 4, ((2, 2, 1), (2, 2, 1))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_block_region() {
+        let map = "(1,1,1) = {\"\naaabac\nadaeaf\nagahai\n\"}\n(1,1,2) = {\"\naj\n\"}\n";
+        let locations = get_block_locations(map);
+
+        // The middle column of the bottom two rows
+        assert_eq!(
+            parse_block_region(map, locations[0], ((2, 1, 1), (2, 2, 1)), 2).unwrap(),
+            Some(((2, 1, 1), vec!["ae", "ah"]))
+        );
+        // Hanging off of the top right, so it's clipped to the block
+        assert_eq!(
+            parse_block_region(map, locations[0], ((3, 3, 1), (10, 10, 1)), 2).unwrap(),
+            Some(((3, 3, 1), vec!["ac"]))
+        );
+        // Whole block
+        assert_eq!(
+            parse_block_region(map, locations[1], ((1, 1, 1), (5, 5, 5)), 2).unwrap(),
+            Some(((1, 1, 2), vec!["aj"]))
+        );
+        // Outside of it
+        assert_eq!(
+            parse_block_region(map, locations[0], ((4, 1, 1), (5, 3, 1)), 2).unwrap(),
+            None
+        );
+        assert_eq!(
+            parse_block_region(map, locations[1], ((1, 1, 1), (5, 5, 1)), 2).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_block_region_errors() {
+        // Broken inside the region
+        let map = "(1,1,1) = {\"\naa\na1\n\"}";
+        assert!(parse_block_region(map, 0, ((1, 1, 1), (1, 1, 1)), 2).is_err());
+        // Broken outside of it, which isn't checked
+        assert_eq!(
+            parse_block_region(map, 0, ((1, 2, 1), (1, 2, 1)), 2).unwrap(),
+            Some(((1, 2, 1), vec!["aa"]))
+        );
+        // Never closed
+        assert!(parse_block_region("(1,1,1) = {\"\naa\n", 0, ((1, 1, 1), (1, 1, 1)), 2).is_err());
+    }
+
     #[test]
     fn test_parse_block() {
         let block = "(1,1,1) = {\"aaaaabaac\naabaacaaa\"}";
@@ -124,4 +927,18 @@ mod tests {
             Ok(((1, 1, 1), vec!["aaa", "aab", "aac", "aab", "aac", "aaa"]))
         );
     }
+
+    #[test]
+    fn test_trailing_content() {
+        let trailer = |map| {
+            let (_, end) =
+                parse_blocks_at(Located::new(map), &[0], ParseOptions::default()).unwrap();
+            trailing_content(map, end.unwrap())
+        };
+        let map = "(1,1,1) = {\"\nab\n\"}\r\n// footer\r\n";
+        assert_eq!(trailer(map), Some("// footer\r\n"));
+        // Only the end of the file
+        assert_eq!(trailer("(1,1,1) = {\"\nab\n\"}\n\n"), None);
+        assert_eq!(trailer("(1,1,1) = {\"\nab\n\"}"), None);
+    }
 }
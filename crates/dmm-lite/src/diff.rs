@@ -0,0 +1,131 @@
+//! Post-processing: Which tiles differ between two versions of a map, for only loading what changed
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    block::{tiles_iter, Block},
+    prefabs::{detect_key_length, Prefab, Prefabs},
+    MapData,
+};
+
+/// How one tile differs between the old and new versions of a map.
+/// Each carries the tile's prefab list, the same as a prefab definition.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TileChange<'a, 's> {
+    /// Only in the new map, with what's there now
+    Added(&'a [Prefab<'s>]),
+    /// Only in the old map, with what used to be there
+    Removed(&'a [Prefab<'s>]),
+    /// In both, with what's there now
+    Changed(&'a [Prefab<'s>]),
+}
+
+/// Every tile of the map and its prefab list, by coordinate. Where blocks overlap the later one wins,
+/// the same as loading. A key with no prefab definition has an empty list.
+fn tile_prefabs<'a, 's>(
+    prefabs: &'a Prefabs<'s>,
+    blocks: &[Block<'s>],
+) -> HashMap<(usize, usize, usize), &'a [Prefab<'s>]> {
+    let mut tiles = HashMap::new();
+
+    let key_len = detect_key_length(prefabs);
+    if key_len == 0 {
+        return tiles;
+    }
+
+    for (coord, key) in tiles_iter(blocks, key_len) {
+        tiles.insert(coord, prefabs.get(key).map_or(&[][..], Vec::as_slice));
+    }
+    tiles
+}
+
+/// Every tile that isn't the same in `old` and `new`, ordered by z, then y, then x.
+///
+/// Tiles are compared by what's on them, not by key, so re-keying a map (as map merging tools
+/// do) doesn't count as a change. Coordinates are relative to the map, starting at (1, 1, 1).
+pub fn map_diff<'a, 's>(
+    old: &'a MapData<'s>,
+    new: &'a MapData<'s>,
+) -> Vec<((usize, usize, usize), TileChange<'a, 's>)> {
+    let old_tiles = tile_prefabs(&old.0, &old.1);
+    let new_tiles = tile_prefabs(&new.0, &new.1);
+
+    // (z, y, x) so that it sorts in the order we want
+    let mut changes = BTreeMap::new();
+    for (&coord, &tile) in &new_tiles {
+        let change = match old_tiles.get(&coord) {
+            None => TileChange::Added(tile),
+            Some(&old_tile) if old_tile != tile => TileChange::Changed(tile),
+            Some(_) => continue,
+        };
+        changes.insert((coord.2, coord.1, coord.0), change);
+    }
+    for (&coord, &old_tile) in &old_tiles {
+        if !new_tiles.contains_key(&coord) {
+            changes.insert((coord.2, coord.1, coord.0), TileChange::Removed(old_tile));
+        }
+    }
+
+    changes
+        .into_iter()
+        .map(|((z, y, x), change)| ((x, y, z), change))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse_map_multithreaded;
+
+    use super::*;
+
+    const OLD: &str = r#""a" = (/turf/open/floor,/area/station)
+"b" = (/obj/item/crowbar,/turf/open/floor,/area/station)
+
+(1,1,1) = {"
+aba
+aab
+"}
+"#;
+
+    #[test]
+    fn test_map_diff() {
+        // Keys swapped, one tile changed and one row made longer
+        let new = r#""b" = (/turf/open/floor,/area/station)
+"a" = (/obj/item/crowbar,/turf/open/floor,/area/station)
+"c" = (/obj/item/wrench,/turf/open/floor,/area/station)
+
+(1,1,1) = {"
+bab
+bcaa
+"}
+"#;
+        let (_info, old) = parse_map_multithreaded("old".to_owned(), OLD).unwrap();
+        let (_info, new) = parse_map_multithreaded("new".to_owned(), new).unwrap();
+
+        let wrench = &new.0["c"];
+        let crowbar = &new.0["a"];
+        assert_eq!(
+            map_diff(&old, &new),
+            vec![
+                ((2, 1, 1), TileChange::Changed(wrench.as_slice())),
+                ((4, 1, 1), TileChange::Added(crowbar.as_slice())),
+            ]
+        );
+
+        // The other way around
+        let floor = &old.0["a"];
+        assert_eq!(
+            map_diff(&new, &old),
+            vec![
+                ((2, 1, 1), TileChange::Changed(floor.as_slice())),
+                ((4, 1, 1), TileChange::Removed(crowbar.as_slice())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_map_diff_identical() {
+        let (_info, old) = parse_map_multithreaded("old".to_owned(), OLD).unwrap();
+        let (_info, new) = parse_map_multithreaded("new".to_owned(), OLD).unwrap();
+        assert!(map_diff(&old, &new).is_empty());
+    }
+}
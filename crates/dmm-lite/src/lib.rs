@@ -1,13 +1,20 @@
+use std::time::{Duration, Instant};
+
 use miette::{miette, LabeledSpan};
-use winnow::{combinator::opt, error::ContextError, Located, Parser};
+use winnow::{error::ContextError, Located};
 
 pub mod block;
+pub mod diff;
 pub mod prefabs;
+pub mod stats;
+pub mod writer;
 
 #[derive(Debug)]
 pub struct MapInfo {
     pub is_tgm: bool,
     pub name: String,
+    /// Anything after the last block, see [`block::trailing_content`]
+    pub trailer: Option<String>,
 }
 
 #[derive(Debug)]
@@ -33,15 +40,49 @@ impl LocatedError {
     }
 }
 
+/// Leniency for hand-made maps. Everything is off by default, which is as strict as BYOND is.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Rows of only whitespace inside a block are kept as empty rows, instead of failing to parse.
+    /// It's up to whatever loads the map what goes there.
+    pub blank_rows: bool,
+}
+
 pub type MapData<'s> = (prefabs::Prefabs<'s>, Vec<block::Block<'s>>);
-pub fn parse_map_multithreaded(name: String, i: &str) -> Result<(MapInfo, MapData), LocatedError> {
+pub fn parse_map_multithreaded(
+    name: String,
+    i: &str,
+) -> Result<(MapInfo, MapData<'_>), LocatedError> {
+    parse_map_with_options(name, i, ParseOptions::default())
+}
+
+pub fn parse_map_with_options(
+    name: String,
+    i: &str,
+    options: ParseOptions,
+) -> Result<(MapInfo, MapData<'_>), LocatedError> {
+    parse_map_timed(name, i, options).map(|(info, data, _timing)| (info, data))
+}
+
+/// How long each phase of [`parse_map_timed`] took, for profiling without a tracy build.
+/// Scanning finds where each prefab or block starts, and parsing (which is multithreaded) does the rest.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ParseTiming {
+    pub prefab_scan: Duration,
+    pub prefab_parse: Duration,
+    pub block_scan: Duration,
+    pub block_parse: Duration,
+}
+
+/// [`parse_map_with_options`], timing each phase as it goes.
+pub fn parse_map_timed(
+    name: String,
+    i: &str,
+    options: ParseOptions,
+) -> Result<(MapInfo, MapData<'_>, ParseTiming), LocatedError> {
     let mut i = Located::new(i);
-    // just merk the dmm2tgm header
-    let _ = opt(
-        "//MAP CONVERTED BY dmm2tgm.py THIS HEADER COMMENT PREVENTS RECONVERSION, DO NOT REMOVE",
-    )
-    .parse_next(&mut i)
-    .map_err(|e| {
+    // just merk the dmm2tgm header, and any other comments before the first prefab
+    prefabs::parse_filler(&mut i).map_err(|e| {
         if let Some(e) = e.into_inner() {
             LocatedError {
                 key_offset: 0,
@@ -55,8 +96,33 @@ pub fn parse_map_multithreaded(name: String, i: &str) -> Result<(MapInfo, MapDat
 
     let is_tgm = prefabs::detect_tgm(&i);
 
-    let prefab_map = prefabs::multithreaded_parse_map_prefabs(i)?;
-    let block_list = block::multithreaded_parse_map_locations(i)?;
+    let mut timing = ParseTiming::default();
+    let mut started = Instant::now();
+    let mut lap = |phase: &mut Duration| {
+        let now = Instant::now();
+        *phase = now - started;
+        started = now;
+    };
+
+    let prefab_locations = prefabs::get_prefab_locations(&i);
+    lap(&mut timing.prefab_scan);
+    let prefab_map = prefabs::parse_prefabs_at(i, &prefab_locations)?;
+    lap(&mut timing.prefab_parse);
+    let block_locations = block::get_block_locations(&i);
+    lap(&mut timing.block_scan);
+    let (block_list, blocks_end) = block::parse_blocks_at(i, &block_locations, options)?;
+    lap(&mut timing.block_parse);
+    let trailer = blocks_end
+        .and_then(|end| block::trailing_content(&i, end))
+        .map(str::to_owned);
 
-    Ok((MapInfo { name, is_tgm }, (prefab_map, block_list)))
+    Ok((
+        MapInfo {
+            name,
+            is_tgm,
+            trailer,
+        },
+        (prefab_map, block_list),
+        timing,
+    ))
 }
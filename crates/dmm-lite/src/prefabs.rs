@@ -1,10 +1,11 @@
 use miette::{miette, LabeledSpan, Severity};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use winnow::{
     ascii::{
         alpha0, alpha1, alphanumeric0, dec_int, float, line_ending, multispace0, space0, space1,
+        till_line_ending,
     },
     combinator::{
         alt, cut_err, delimited, fail, opt, peek, preceded, repeat, separated_pair, terminated,
@@ -26,8 +27,9 @@ pub fn parse_key<'s>(i: &mut Located<&'s str>) -> PResult<&'s str> {
     .parse_next(i)
 }
 
+/// Comment lines before the first prefab, like the `//MAP CONVERTED BY dmm2tgm.py` header, are skipped.
 pub fn detect_tgm(i: &str) -> bool {
-    (parse_key, line_ending)
+    (parse_filler, parse_key, line_ending)
         .parse_next(&mut Located::new(i))
         .is_ok()
 }
@@ -51,10 +53,10 @@ pub fn parse_prefab_data<'s>(i: &mut &'s str) -> PResult<&'s str> {
     }
 
     loop {
-        match alt((r#"\""#, take(1usize))).parse_next(i) {
+        match alt((r"\\", r#"\""#, take(1usize))).parse_next(i) {
             Err(e) => return Err(e),
-            // Ignore escaped quotes
-            Ok(r#"\""#) => {
+            // Ignore escaped quotes, and escaped backslashes so that one before a quote doesn't escape it
+            Ok(r"\\" | r#"\""#) => {
                 count += 2;
             }
             // Switch str state to avoid ending early
@@ -91,6 +93,16 @@ pub fn parse_prefab<'s>(i: &mut Located<&'s str>) -> PResult<Prefab<'s>> {
     .parse_next(i)
 }
 
+/// Whitespace and `// comments`, which hand-edited maps sometimes have around prefabs
+pub fn parse_filler(i: &mut Located<&str>) -> PResult<()> {
+    loop {
+        multispace0.parse_next(i)?;
+        if opt(("//", till_line_ending)).parse_next(i)?.is_none() {
+            return Ok(());
+        }
+    }
+}
+
 pub type PrefabLine<'s> = (&'s str, Vec<Prefab<'s>>);
 pub fn parse_prefab_line<'s>(i: &mut Located<&'s str>) -> PResult<PrefabLine<'s>> {
     terminated(
@@ -99,10 +111,10 @@ pub fn parse_prefab_line<'s>(i: &mut Located<&'s str>) -> PResult<PrefabLine<'s>
             multispace0,
             repeat(
                 1..,
-                terminated(terminated(parse_prefab, opt(',')), opt(line_ending)),
+                delimited(parse_filler, parse_prefab, (parse_filler, opt(','))),
             ),
         ),
-        ")",
+        (parse_filler, ")"),
     )
     .parse_next(i)
 }
@@ -122,10 +134,44 @@ pub fn get_prefab_locations(i: &str) -> Vec<usize> {
     results
 }
 
+/// A `// @note ...` on the same line as the end of a prefab, for mappers to leave notes on tiles.
+/// Returns the trimmed text of the note.
+pub fn parse_prefab_note<'s>(i: &mut Located<&'s str>) -> PResult<&'s str> {
+    preceded((space0, "//", space0, "@note", space1), till_line_ending)
+        .map(str::trim)
+        .parse_next(i)
+}
+
+/// Prefab key -> note, for every prefab with a [`parse_prefab_note`] after it.
+/// This is separate from parsing the prefabs, since only callers that want notes need to pay for it.
+pub fn prefab_notes(i: &str) -> HashMap<&str, &str> {
+    get_prefab_locations(i)
+        .par_iter()
+        .filter_map(|loc| {
+            let mut substring = Located::new(&i[*loc..]);
+            let (key, _) = parse_prefab_line(&mut substring).ok()?;
+            let note = parse_prefab_note(&mut substring).ok()?;
+            Some((key, note))
+        })
+        .collect()
+}
+
 pub type Prefabs<'s> = HashMap<&'s str, Vec<(&'s str, Option<Vec<(&'s str, Literal<'s>)>>)>>;
-pub fn multithreaded_parse_map_prefabs(i: Located<&str>) -> Result<Prefabs, LocatedError> {
-    let locations = get_prefab_locations(&i);
 
+/// Every key in a map is the same length, so we only need to look at one. Returns 0 if there are no prefabs.
+pub fn detect_key_length(prefabs: &Prefabs) -> usize {
+    prefabs.keys().next().map(|key| key.len()).unwrap_or(0)
+}
+
+pub fn multithreaded_parse_map_prefabs(i: Located<&str>) -> Result<Prefabs<'_>, LocatedError> {
+    parse_prefabs_at(i, &get_prefab_locations(&i))
+}
+
+/// Parses the prefab lines starting at each of `locations`, the offsets from [`get_prefab_locations`].
+pub(crate) fn parse_prefabs_at<'s>(
+    i: Located<&'s str>,
+    locations: &[usize],
+) -> Result<Prefabs<'s>, LocatedError> {
     locations
         .par_iter()
         .map(|loc| {
@@ -147,9 +193,19 @@ pub fn multithreaded_parse_map_prefabs(i: Located<&str>) -> Result<Prefabs, Loca
 
 /// Post-processing: Separate each variable kv pair in the list
 /// {var1="derp"; var2; var3=7} -> ["var1=\"derp\"", "var2", "var3=7"]
+///
+/// Vars can be separated by `;` (DMM), or by line breaks that aren't inside a list (TGM, or maps
+/// that left the `;` off). Empty entries from trailing or doubled separators are skipped.
+/// Indentation is trimmed off each entry, so TGM files whose tabs were expanded to spaces still work.
 pub fn separate_var_list<'s>(i: &mut Located<&'s str>) -> PResult<Vec<Located<&'s str>>> {
     let mut count: usize = 0;
     let mut in_str = false;
+    // Same as in_str, but for 'file' literals
+    let mut in_file = false;
+    // How many parentheses deep we are, outside of strings. Line breaks inside lists don't separate vars
+    let mut depth: usize = 0;
+    // How many braces deep we are, outside of strings, so that only the `}` matching our `{` ends the list
+    let mut braces: usize = 0;
 
     // Eat the starting "{"
     '{'.context(StrContext::Expected(
@@ -167,7 +223,9 @@ pub fn separate_var_list<'s>(i: &mut Located<&'s str>) -> PResult<Vec<Located<&'
     loop {
         match cut_err(
             alt((
+                r"\\",
                 r#"\""#,
+                r#"\'"#,
                 take(1usize),
                 fail.context(StrContext::Label("var list"))
                     .context(StrContext::Expected(
@@ -182,37 +240,57 @@ pub fn separate_var_list<'s>(i: &mut Located<&'s str>) -> PResult<Vec<Located<&'
         .parse_next(i)
         {
             Err(e) => return Err(e),
-            // Ignore escaped quotes
-            Ok(r#"\""#) => {
+            // Ignore escaped quotes, and escaped backslashes so that one before a quote doesn't escape it
+            Ok(r"\\" | r#"\""# | r#"\'"#) => {
                 count += 2;
             }
             // Switch str state to avoid ending early
-            Ok("\"") => {
+            Ok("\"") if !in_file => {
                 count += 1;
                 in_str = !in_str;
                 last_quote = i.offset_from(&first_checkpoint) - 1;
             }
-            Ok(";") => {
-                if !in_str {
-                    // We hit the end of a var decl: we now need to push it into our vars lost
-                    i.reset(&checkpoint);
-                    let key_and_val = take(count).parse_next(i)?;
-                    // Eat all the whitespace
-                    vars.push(Located::new(key_and_val.trim()));
-                    // Eat the semicolon
-                    let _ = take(1usize).parse_next(i)?;
-                    // Eat any space
-                    let _ = multispace0.parse_next(i)?;
-                    // Continue with a reset count and a new checkpoint
-                    count = 0;
-                    checkpoint = i.checkpoint();
-                } else {
-                    count += 1;
+            Ok("'") if !in_str => {
+                count += 1;
+                in_file = !in_file;
+                last_quote = i.offset_from(&first_checkpoint) - 1;
+            }
+            Ok("(") if !in_str && !in_file => {
+                count += 1;
+                depth += 1;
+            }
+            Ok(")") if !in_str && !in_file => {
+                count += 1;
+                depth = depth.saturating_sub(1);
+            }
+            Ok("{") if !in_str && !in_file => {
+                count += 1;
+                braces += 1;
+            }
+            Ok(separator @ (";" | "\n"))
+                if !in_str && !in_file && braces == 0 && (separator == ";" || depth == 0) =>
+            {
+                // We hit the end of a var decl: we now need to push it into our vars lost
+                i.reset(&checkpoint);
+                // Eat all the whitespace
+                let key_and_val = take(count).parse_next(i)?.trim();
+                if !key_and_val.is_empty() {
+                    vars.push(Located::new(key_and_val));
                 }
+                // Eat the separator
+                let _ = take(1usize).parse_next(i)?;
+                // Eat any space
+                let _ = multispace0.parse_next(i)?;
+                // Continue with a reset count and a new checkpoint
+                count = 0;
+                checkpoint = i.checkpoint();
+            }
+            Ok(";") => {
+                count += 1;
             }
             Ok("\n") => {
                 count += 1;
-                if in_str {
+                if in_str || in_file {
                     let report = miette!(
                         severity = Severity::Warning,
                         labels = vec![LabeledSpan::at_offset(
@@ -237,17 +315,24 @@ pub fn separate_var_list<'s>(i: &mut Located<&'s str>) -> PResult<Vec<Located<&'
                     checkpoint = i.checkpoint();
 
                     in_str = false;
+                    in_file = false;
                 }
             }
+            Ok("}") if !in_str && !in_file && braces > 0 => {
+                count += 1;
+                braces -= 1;
+            }
             Ok("}") => {
                 // Only dip out if not in a string
-                if !in_str {
+                if !in_str && !in_file {
                     // If we have something left in our buffer, we add it
                     if count > 0 {
                         i.reset(&checkpoint);
-                        let key_and_val = Located::new(take(count).parse_next(i)?.trim());
                         // Eat all the whitespace
-                        vars.push(key_and_val);
+                        let key_and_val = take(count).parse_next(i)?.trim();
+                        if !key_and_val.is_empty() {
+                            vars.push(Located::new(key_and_val));
+                        }
                         // Eat the }
                         let _ = '}'.parse_next(i)?;
                     }
@@ -266,14 +351,36 @@ pub fn separate_var_list<'s>(i: &mut Located<&'s str>) -> PResult<Vec<Located<&'
 
 /// Post-processing: Separate each variable into k and v
 /// {var1="derp"; var2; var3=7} -> {"var1": Some("derp"), "var2": None, "var3": Some(7f32)}
+///
+/// Values longer than [`MAX_LITERAL_LEN`] are kept as a [`Literal::Fallback`].
 pub fn parse_var_list<'s>(i: &mut Located<&'s str>) -> PResult<Vec<(&'s str, Literal<'s>)>> {
     let vars = separate_var_list(i)?;
 
     vars.into_iter()
-        .map(|mut kv| (parse_var_list_key, parse_literal).parse_next(&mut kv))
+        .map(|mut kv| {
+            let key = parse_var_list_key.parse_next(&mut kv)?;
+            if kv.len() > MAX_LITERAL_LEN {
+                return Ok((key, Literal::Fallback(*kv)));
+            }
+            Ok((key, parse_literal.parse_next(&mut kv)?))
+        })
         .collect()
 }
 
+/// Vars that are set more than once in a var list, each listed once in the order they're first
+/// repeated. They're kept as they are in the list, so the last one wins when it's loaded, the same
+/// as BYOND.
+pub fn duplicate_vars<'s>(vars: &[(&'s str, Literal<'s>)]) -> Vec<&'s str> {
+    let mut seen = HashSet::new();
+    let mut duplicates = vec![];
+    for (var, _) in vars {
+        if !seen.insert(*var) && !duplicates.contains(var) {
+            duplicates.push(*var);
+        }
+    }
+    duplicates
+}
+
 pub fn parse_var_list_key<'s>(i: &mut Located<&'s str>) -> PResult<&'s str> {
     terminated(parse_identifier, " = ").parse_next(i)
 }
@@ -297,17 +404,24 @@ pub enum Literal<'s> {
 }
 
 pub fn parse_literal<'s>(i: &mut Located<&'s str>) -> PResult<Literal<'s>> {
+    parse_literal_nested(i, 0)
+}
+
+/// [`parse_literal`], for a literal inside of `depth` lists
+fn parse_literal_nested<'s>(i: &mut Located<&'s str>, depth: usize) -> PResult<Literal<'s>> {
     match alt((
         parse_literal_number.map(Literal::Number),
         parse_literal_string.map(Literal::String),
-        parse_literal_list,
+        |i: &mut Located<&'s str>| parse_literal_list_nested(i, depth),
         parse_path.map(Literal::Path),
         parse_literal_file.map(Literal::File),
         "null".map(|_| Literal::Null),
     ))
     .parse_next(i)
     {
-        Err(ErrMode::Backtrack(_)) => Ok(Literal::Fallback(i)),
+        // A list entry that doesn't parse fails the whole list, otherwise the
+        // empty fallback would eat the closing paren of the list it's in
+        Err(ErrMode::Backtrack(_)) if depth == 0 => Ok(Literal::Fallback(i)),
         Err(e) => Err(e),
         Ok(t) => Ok(t),
     }
@@ -325,10 +439,10 @@ pub fn parse_literal_string<'s>(i: &mut Located<&'s str>) -> PResult<&'s str> {
     let checkpoint = i.checkpoint();
 
     loop {
-        match alt((r#"\""#, take(1usize))).parse_next(i) {
+        match alt((r"\\", r#"\""#, take(1usize))).parse_next(i) {
             Err(e) => return Err(e),
-            // Ignore escaped quotes
-            Ok(r#"\""#) => {
+            // Ignore escaped quotes, and escaped backslashes so that one before a quote doesn't escape it
+            Ok(r"\\" | r#"\""#) => {
                 count += 2;
             }
             // Switch str state to avoid ending early
@@ -353,37 +467,77 @@ pub fn parse_bare_list_key<'s>(i: &mut Located<&'s str>) -> PResult<Literal<'s>>
         .parse_next(i)
 }
 
+/// Whether `text` is a bare identifier that [`parse_bare_list_key`] keeps as a [`Literal::Fallback`],
+/// like `aaa` in `list(aaa = 2)`. These are valid DM, so they're not a parse failure.
+pub fn is_bare_list_key(text: &str) -> bool {
+    text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Lists nested deeper than this aren't parsed, so that machine-generated maps can't run us
+/// out of stack. The whole value becomes a [`Literal::Fallback`] instead.
+pub const MAX_LIST_DEPTH: usize = 8;
+
+/// Values longer than this aren't parsed either, and are kept as a [`Literal::Fallback`].
+/// Only machine-generated maps come anywhere near it, with a huge list all on one line.
+pub const MAX_LITERAL_LEN: usize = 1 << 20;
+
 pub fn parse_literal_list<'s>(i: &mut Located<&'s str>) -> PResult<Literal<'s>> {
+    parse_literal_list_nested(i, 0)
+}
+
+/// [`parse_literal_list`], for a list inside of `depth` others
+fn parse_literal_list_nested<'s>(i: &mut Located<&'s str>, depth: usize) -> PResult<Literal<'s>> {
     // Must start with "list("
     "list(".parse_next(i)?;
 
     // Special case: Empty lists
-    if **i == ")" {
+    if opt(')').parse_next(i)?.is_some() {
         return Ok(Literal::List(vec![]));
     }
 
-    alt((
-        // Lists are either associative
-        repeat(
-            1..,
+    if depth >= MAX_LIST_DEPTH {
+        return fail.parse_next(i);
+    }
+    let item = move |i: &mut Located<&'s str>| parse_literal_nested(i, depth + 1);
+    let equals = || delimited(space0, '=', space0);
+    let separator = || delimited(space0, alt((',', ')')), space0);
+
+    // Lists are either associative or not, which the first item says. It's only parsed once, so
+    // that nested lists aren't tried both ways at every level.
+    let start = i.checkpoint();
+    let (first, is_assoc) = match opt(parse_bare_list_key).parse_next(i)? {
+        Some(key) if opt(equals()).parse_next(i)?.is_some() => (key, true),
+        // Something like `null` that's a value after all
+        Some(_) => {
+            i.reset(&start);
+            (item(i)?, false)
+        }
+        None => {
+            let first = item(i)?;
+            (first, opt(equals()).parse_next(i)?.is_some())
+        }
+    };
+
+    if is_assoc {
+        let value = terminated(item, separator()).parse_next(i)?;
+        let rest: Vec<(Literal, Literal)> = repeat(
+            0..,
             terminated(
-                separated_pair(
-                    alt((parse_bare_list_key, parse_literal)),
-                    delimited(space0, '=', space0),
-                    parse_literal,
-                ),
-                delimited(space0, alt((',', ')')), space0),
+                separated_pair(alt((parse_bare_list_key, item)), equals(), item),
+                separator(),
             ),
         )
-        .map(|pairs: Vec<(Literal, Literal)>| Literal::AssocList(pairs)),
-        // Or not
-        repeat(
-            1..,
-            terminated(parse_literal, delimited(space0, alt((',', ')')), space0)),
-        )
-        .map(|literals: Vec<Literal>| Literal::List(literals)),
-    ))
-    .parse_next(i)
+        .parse_next(i)?;
+        let mut pairs = vec![(first, value)];
+        pairs.extend(rest);
+        Ok(Literal::AssocList(pairs))
+    } else {
+        separator().parse_next(i)?;
+        let rest: Vec<Literal> = repeat(0.., terminated(item, separator())).parse_next(i)?;
+        let mut literals = vec![first];
+        literals.extend(rest);
+        Ok(Literal::List(literals))
+    }
 }
 
 pub fn parse_literal_file<'s>(i: &mut Located<&'s str>) -> PResult<&'s str> {
@@ -394,10 +548,10 @@ pub fn parse_literal_file<'s>(i: &mut Located<&'s str>) -> PResult<&'s str> {
     let checkpoint = i.checkpoint();
 
     loop {
-        match alt((r#"\'"#, take(1usize))).parse_next(i) {
+        match alt((r"\\", r#"\'"#, take(1usize))).parse_next(i) {
             Err(e) => return Err(e),
-            // Ignore escaped single quote
-            Ok(r#"\'"#) => {
+            // Ignore escaped single quote, and escaped backslashes
+            Ok(r"\\" | r#"\'"#) => {
                 count += 2;
             }
             // Switch str state to avoid ending early
@@ -420,6 +574,26 @@ pub fn parse_literal_file<'s>(i: &mut Located<&'s str>) -> PResult<&'s str> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_prefab_notes() {
+        let map = r#""a" = (/obj/effect/landmark,/turf/space,/area/space) // @note  Keep this clear
+"b" = (/turf/space,/area/space) // just a comment
+"c" = (
+/obj/effect/landmark,
+/turf/space,
+/area/space) //@note Notes go after the closing parenthesis
+"d" = (/turf/space,/area/space)
+
+(1,1,1) = {"
+abcd
+"}
+"#;
+        let notes = prefab_notes(map);
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes["a"], "Keep this clear");
+        assert_eq!(notes["c"], "Notes go after the closing parenthesis");
+    }
+
     #[test]
     fn test_parse_key() {
         let mut key = Located::new(r#""abc" = ("#);
@@ -529,6 +703,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_prefab_line_filler() {
+        let mut line = Located::new(
+            "\"a\" = ( /obj/item  , // a comment, with a comma\n/turf/open/floor  ,  \n/area/space // more\n)",
+        );
+        assert_eq!(
+            parse_prefab_line.parse_next(&mut line),
+            Ok((
+                "a",
+                vec![
+                    ("/obj/item", None),
+                    ("/turf/open/floor", None),
+                    ("/area/space", None)
+                ]
+            ))
+        );
+    }
+
     #[test]
     fn test_prefab_var_separation() {
         let mut variables_dmm =
@@ -567,6 +759,36 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_var_separator_variants() {
+        fn separate(input: &str) -> PResult<Vec<&str>> {
+            separate_var_list
+                .parse_next(&mut Located::new(input))
+                .map(|s| s.iter().map(|s| **s).collect())
+        }
+        let expected = Ok(vec!["dir = 10", "name = \"a;b\""]);
+
+        // Trailing and doubled semicolons
+        assert_eq!(separate(r#"{dir = 10;; name = "a;b";}"#), expected);
+        // Newlines without semicolons
+        assert_eq!(separate("{\n\tdir = 10\n\tname = \"a;b\"\n\t}"), expected);
+        // Indented with spaces, or a mix, as editors like to do to TGM files
+        assert_eq!(
+            separate("{\n    dir = 10\n  \tname = \"a;b\"\n    }"),
+            expected
+        );
+        // Both, with windows line endings
+        assert_eq!(
+            separate("{\r\n\tdir = 10;\r\n\tname = \"a;b\"\r\n\t}"),
+            expected
+        );
+        // Line breaks inside a list don't split it
+        assert_eq!(
+            separate("{dir = 10\nlist = list(1,\n2)}"),
+            Ok(vec!["dir = 10", "list = list(1,\n2)"])
+        );
+    }
+
     #[test]
     fn test_identifier() {
         let mut valid_identifier = Located::new("abc1 = ");
@@ -692,6 +914,99 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_duplicate_vars() {
+        let mut list = Located::new(r#"{name = "a"; dir = 4; name = "b"; dir = 8; name = "c"}"#);
+        let vars = parse_var_list.parse_next(&mut list).unwrap();
+
+        // Nothing is dropped, the last name is the one loaded
+        assert_eq!(vars.len(), 5);
+        assert_eq!(vars[4], ("name", Literal::String("c")));
+        assert_eq!(duplicate_vars(&vars), vec!["name", "dir"]);
+        assert!(duplicate_vars(&vars[..2]).is_empty());
+    }
+
+    #[test]
+    fn test_long_values() {
+        // A long list all on one line is still parsed, without recursing per item
+        let items = vec!["1"; 100_000].join(",");
+        let text = format!("{{long = list({items}); name = \"after\"}}");
+        let vars = parse_var_list
+            .parse_next(&mut Located::new(text.as_str()))
+            .unwrap();
+        assert!(matches!(&vars[0].1, Literal::List(items) if items.len() == 100_000));
+        assert_eq!(vars[1], ("name", Literal::String("after")));
+
+        // Past the limit it's kept as it's written
+        let items = "1,".repeat(MAX_LITERAL_LEN / 2);
+        let text = format!("{{long = list({items}1); name = \"after\"}}");
+        let vars = parse_var_list
+            .parse_next(&mut Located::new(text.as_str()))
+            .unwrap();
+        assert!(matches!(vars[0].1, Literal::Fallback(text) if text.len() > MAX_LITERAL_LEN));
+        assert_eq!(vars[1], ("name", Literal::String("after")));
+    }
+
+    #[test]
+    fn test_list_depth() {
+        let deep = format!(
+            "{}1{}",
+            "list(".repeat(MAX_LIST_DEPTH + 1),
+            ")".repeat(MAX_LIST_DEPTH + 1)
+        );
+        assert!(matches!(
+            parse_literal.parse_next(&mut Located::new(deep.as_str())),
+            Ok(Literal::Fallback(_))
+        ));
+
+        // As deep as it goes
+        let deepest = format!(
+            "{}1{}",
+            "list(".repeat(MAX_LIST_DEPTH),
+            ")".repeat(MAX_LIST_DEPTH)
+        );
+        let mut literal = parse_literal
+            .parse_next(&mut Located::new(deepest.as_str()))
+            .unwrap();
+        for _ in 0..MAX_LIST_DEPTH {
+            let Literal::List(mut items) = literal else {
+                panic!("Expected a list, found {literal:?}");
+            };
+            literal = items.remove(0);
+        }
+        assert_eq!(literal, Literal::Number(1.));
+
+        assert_eq!(
+            parse_literal.parse_next(&mut Located::new("list(1, list(2))")),
+            Ok(Literal::List(vec![
+                Literal::Number(1.),
+                Literal::List(vec![Literal::Number(2.)])
+            ]))
+        );
+        assert_eq!(
+            parse_literal.parse_next(&mut Located::new("list(list(), 1)")),
+            Ok(Literal::List(vec![
+                Literal::List(vec![]),
+                Literal::Number(1.)
+            ]))
+        );
+        // Whether it's associative only depends on what follows the first item
+        assert_eq!(
+            parse_literal.parse_next(&mut Located::new("list(list(1) = 2, null = 3)")),
+            Ok(Literal::AssocList(vec![
+                (
+                    Literal::List(vec![Literal::Number(1.)]),
+                    Literal::Number(2.)
+                ),
+                (Literal::Fallback("null"), Literal::Number(3.)),
+            ]))
+        );
+        assert_eq!(
+            parse_literal.parse_next(&mut Located::new("list(null , 1)")),
+            Ok(Literal::List(vec![Literal::Null, Literal::Number(1.)]))
+        );
+    }
+
     #[test]
     fn test_weird_error_from_virgo() {
         let mut list = Located::new(
@@ -0,0 +1,311 @@
+//! Post-processing: Statistics about what a parsed map contains, for auditing maps without loading them
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{
+    block::{tiles_iter, Block},
+    prefabs::{detect_key_length, is_bare_list_key, Literal, Prefab, Prefabs},
+};
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MapStatistics<'s> {
+    /// Number of tiles across every block
+    pub tiles: usize,
+    /// Number of turfs placed (one per tile with a defined prefab)
+    pub turfs: usize,
+    /// Number of areas placed (one per tile with a defined prefab)
+    pub areas: usize,
+    /// Number of movables placed, not counting markers
+    pub atoms: usize,
+    /// How many times each path is placed, not counting markers
+    pub path_counts: HashMap<&'s str, usize>,
+    /// Every coordinate each marker path is placed at, in BYOND (1-based) coordinates
+    pub markers: HashMap<&'s str, Vec<(usize, usize, usize)>>,
+    /// Keys used in blocks that have no prefab definition
+    pub undefined_keys: HashMap<&'s str, usize>,
+    /// Keys used in blocks whose prefab doesn't end in an area with a turf just before it, so
+    /// the loader can't place them. Their tiles are still counted.
+    pub malformed_prefabs: BTreeSet<&'s str>,
+}
+
+/// Whether `prefab` is shaped the way the loader expects: movables, then a turf, then an area
+fn is_well_formed(prefab: &[Prefab]) -> bool {
+    let mut paths = prefab.iter().rev().map(|(path, _vars)| *path);
+    paths.next().is_some_and(|area| area.starts_with("/area"))
+        && paths.next().is_some_and(|turf| turf.starts_with("/turf"))
+}
+
+/// Returns true if `path` is `marker`, or a subtype of it
+fn is_marker(path: &str, markers: &[&str]) -> bool {
+    markers.iter().any(|marker| {
+        path.strip_prefix(marker)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+/// Walks every tile of the map and counts what would be placed, noting keys that are undefined
+/// or malformed along the way.
+///
+/// Anything matching `markers` (or a subtype of one) is reported with its coordinates in
+/// [`MapStatistics::markers`] instead of being counted as an atom. This is for annotation tiles
+/// such as spawn points and landmarks.
+pub fn map_statistics<'s>(
+    prefabs: &Prefabs<'s>,
+    blocks: &[Block<'s>],
+    markers: &[&str],
+) -> MapStatistics<'s> {
+    let mut stats = MapStatistics::default();
+
+    let key_len = detect_key_length(prefabs);
+    if key_len == 0 {
+        return stats;
+    }
+
+    for (coord, key) in tiles_iter(blocks, key_len) {
+        stats.tiles += 1;
+
+        let Some(prefab) = prefabs.get(key) else {
+            *stats.undefined_keys.entry(key).or_default() += 1;
+            continue;
+        };
+        if !is_well_formed(prefab) {
+            stats.malformed_prefabs.insert(key);
+        }
+
+        for (path, _vars) in prefab {
+            if is_marker(path, markers) {
+                stats.markers.entry(path).or_default().push(coord);
+                continue;
+            }
+
+            if path.starts_with("/turf") {
+                stats.turfs += 1;
+            } else if path.starts_with("/area") {
+                stats.areas += 1;
+            } else {
+                stats.atoms += 1;
+            }
+            *stats.path_counts.entry(path).or_default() += 1;
+        }
+    }
+
+    stats
+}
+
+/// Every path the map references, whether placed or used as a var value, with no coordinates.
+/// This is for checking that a map only uses paths which exist.
+pub fn distinct_paths<'s>(prefabs: &Prefabs<'s>) -> BTreeSet<&'s str> {
+    fn add_literal_paths<'s>(literal: &Literal<'s>, paths: &mut BTreeSet<&'s str>) {
+        match literal {
+            Literal::Path(path) => {
+                paths.insert(path);
+            }
+            Literal::List(list) => list
+                .iter()
+                .for_each(|literal| add_literal_paths(literal, paths)),
+            Literal::AssocList(list) => list.iter().for_each(|(key, value)| {
+                add_literal_paths(key, paths);
+                add_literal_paths(value, paths);
+            }),
+            _ => {}
+        }
+    }
+
+    let mut paths = BTreeSet::new();
+    for (path, vars) in prefabs.values().flatten() {
+        paths.insert(*path);
+        for (_var, literal) in vars.iter().flatten() {
+            add_literal_paths(literal, &mut paths);
+        }
+    }
+    paths
+}
+
+/// Somewhere the parser couldn't make sense of a var's value and kept the raw text instead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fallback<'s> {
+    /// The prefab key the var is on
+    pub key: &'s str,
+    /// The path the var is on
+    pub path: &'s str,
+    pub var: &'s str,
+    /// What was kept, as written in the map
+    pub text: &'s str,
+    /// The 1-based line of the map `text` is on, and that whole line
+    pub line: Option<(usize, &'s str)>,
+}
+
+/// Finds the line of `input` that `text` was sliced from, if it was
+fn line_of<'s>(input: &'s str, text: &str) -> Option<(usize, &'s str)> {
+    let offset = (text.as_ptr() as usize).checked_sub(input.as_ptr() as usize)?;
+    if offset > input.len() {
+        return None;
+    }
+    let start = input[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let end = input[offset..]
+        .find('\n')
+        .map_or(input.len(), |i| offset + i);
+    let number = input[..offset].matches('\n').count() + 1;
+    Some((number, input[start..end].trim_end_matches('\r')))
+}
+
+/// Every [`Literal::Fallback`] in the map, including inside lists, ordered by key.
+/// `input` is the text the map was parsed from, and is only used to find each fallback's line.
+///
+/// Bare identifiers used as assoc list keys (`list(a = 1)`) are kept as fallbacks on purpose,
+/// so they're not reported.
+pub fn fallbacks<'s>(prefabs: &Prefabs<'s>, input: &'s str) -> Vec<Fallback<'s>> {
+    fn find_fallbacks<'s>(literal: &Literal<'s>, found: &mut Vec<&'s str>) {
+        match literal {
+            Literal::Fallback(text) => found.push(text),
+            Literal::List(list) => list
+                .iter()
+                .for_each(|literal| find_fallbacks(literal, found)),
+            Literal::AssocList(list) => list.iter().for_each(|(key, value)| {
+                if !matches!(key, Literal::Fallback(text) if is_bare_list_key(text)) {
+                    find_fallbacks(key, found);
+                }
+                find_fallbacks(value, found);
+            }),
+            _ => {}
+        }
+    }
+
+    let mut keys: Vec<_> = prefabs.keys().collect();
+    keys.sort_unstable();
+
+    let mut fallbacks = vec![];
+    let mut found = vec![];
+    for key in keys {
+        for (path, vars) in &prefabs[key] {
+            for (var, literal) in vars.iter().flatten() {
+                find_fallbacks(literal, &mut found);
+                fallbacks.extend(found.drain(..).map(|text| Fallback {
+                    key,
+                    path,
+                    var,
+                    text,
+                    line: line_of(input, text),
+                }));
+            }
+        }
+    }
+    fallbacks
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse_map_multithreaded;
+
+    use super::*;
+
+    const MAP: &str = r#""a" = (/obj/effect/landmark/start{name = "Assistant"},/turf/open/floor,/area/station)
+"b" = (/obj/item/crowbar,/turf/open/floor,/area/station)
+"c" = (/obj/effect/landmarkish,/turf/open/floor,/area/station)
+
+(1,1,1) = {"
+abc
+bba
+"}
+"#;
+
+    #[test]
+    fn test_map_statistics() {
+        let (_info, (prefabs, blocks)) = parse_map_multithreaded("test".to_owned(), MAP).unwrap();
+        let stats = map_statistics(&prefabs, &blocks, &[]);
+
+        assert_eq!(stats.tiles, 6);
+        assert_eq!(stats.turfs, 6);
+        assert_eq!(stats.areas, 6);
+        assert_eq!(stats.atoms, 6);
+        assert_eq!(
+            stats.path_counts.get("/obj/effect/landmark/start"),
+            Some(&2)
+        );
+        assert!(stats.markers.is_empty());
+        assert!(stats.malformed_prefabs.is_empty());
+    }
+
+    #[test]
+    fn test_map_statistics_validation() {
+        let map = r#""a" = (/turf/open/floor,/area/station)
+"b" = (/area/station,/turf/open/floor)
+"c" = (/obj/item/crowbar,/area/station)
+
+(1,1,1) = {"
+abcx
+"}
+"#;
+        let (_info, (prefabs, blocks)) = parse_map_multithreaded("test".to_owned(), map).unwrap();
+        let stats = map_statistics(&prefabs, &blocks, &[]);
+
+        assert_eq!(stats.tiles, 4);
+        assert_eq!(stats.undefined_keys, HashMap::from([("x", 1)]));
+        assert_eq!(stats.malformed_prefabs, BTreeSet::from(["b", "c"]));
+    }
+
+    #[test]
+    fn test_distinct_paths() {
+        let map = r#""a" = (/obj/item/crowbar{spawns = list(/obj/item/wrench = 1)},/turf/open/floor,/area/station)
+"b" = (/obj/item/crowbar,/turf/open/floor,/area/station)
+
+(1,1,1) = {"
+ab
+"}
+"#;
+        let (_info, (prefabs, _blocks)) = parse_map_multithreaded("test".to_owned(), map).unwrap();
+        assert_eq!(
+            distinct_paths(&prefabs).into_iter().collect::<Vec<_>>(),
+            vec![
+                "/area/station",
+                "/obj/item/crowbar",
+                "/obj/item/wrench",
+                "/turf/open/floor"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_map_statistics_markers() {
+        let (_info, (prefabs, blocks)) = parse_map_multithreaded("test".to_owned(), MAP).unwrap();
+        let stats = map_statistics(&prefabs, &blocks, &["/obj/effect/landmark"]);
+
+        assert_eq!(stats.atoms, 4);
+        assert_eq!(stats.path_counts.get("/obj/effect/landmark/start"), None);
+        // Prefix matching must respect path boundaries
+        assert_eq!(stats.path_counts.get("/obj/effect/landmarkish"), Some(&1));
+        assert_eq!(
+            stats.markers.get("/obj/effect/landmark/start"),
+            Some(&vec![(3, 1, 1), (1, 2, 1)])
+        );
+    }
+
+    #[test]
+    fn test_fallbacks() {
+        let map = r#""a" = (/obj/item/crowbar{name = gmddmf; req_access = list(aaa = 2)},/turf/open/floor,/area/station)
+"b" = (/obj/item/crowbar,/turf/open/floor{icon = foo},/area/station)
+
+(1,1,1) = {"
+ab
+"}
+"#;
+        let (_info, (prefabs, _blocks)) = parse_map_multithreaded("test".to_owned(), map).unwrap();
+        let found = fallbacks(&prefabs, map);
+
+        let summary: Vec<_> = found
+            .iter()
+            .map(|fallback| (fallback.key, fallback.path, fallback.var, fallback.text))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("a", "/obj/item/crowbar", "name", "gmddmf"),
+                ("b", "/turf/open/floor", "icon", "foo"),
+            ]
+        );
+        assert_eq!(found[1].line.map(|(number, _)| number), Some(2));
+        assert_eq!(found[0].line.unwrap().1, map.lines().next().unwrap());
+
+        // Not sliced from the input
+        assert_eq!(line_of(map, "foo"), None);
+    }
+}
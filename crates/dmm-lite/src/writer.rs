@@ -0,0 +1,1188 @@
+//! Writing maps back out in DMM format, for maps that are generated programmatically
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use crate::{
+    block::{block_overlaps, Block},
+    prefabs::{detect_key_length, Literal, Prefab, Prefabs},
+    MapData, MapInfo,
+};
+
+/// Characters keys are made of, in the order they're handed out
+pub const KEY_CHARACTERS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// The first line of a TGM map, which stops dmm2tgm.py from converting it again
+pub const TGM_HEADER: &str =
+    "//MAP CONVERTED BY dmm2tgm.py THIS HEADER COMMENT PREVENTS RECONVERSION, DO NOT REMOVE";
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum WriteError {
+    #[error("Keys must be at least one character long")]
+    ZeroKeyLength,
+    #[error("{prefabs} distinct prefabs don't fit in keys of length {key_len}, which can only encode {capacity}")]
+    TooManyPrefabs {
+        prefabs: usize,
+        key_len: usize,
+        capacity: usize,
+    },
+}
+
+/// One z-level, as rows of tiles from the bottom (y = 1) up.
+/// Each tile is its prefab list, in the same order as a prefab definition: the area last, the turf before it.
+pub type Level<'s> = Vec<Vec<Vec<Prefab<'s>>>>;
+
+/// How many distinct keys of `key_len` characters there are
+pub fn key_capacity(key_len: usize) -> usize {
+    u32::try_from(key_len)
+        .ok()
+        .and_then(|key_len| KEY_CHARACTERS.len().checked_pow(key_len))
+        .unwrap_or(usize::MAX)
+}
+
+/// The shortest key length that can encode `prefabs` distinct prefabs
+pub fn min_key_length(prefabs: usize) -> usize {
+    let mut key_len = 1;
+    while key_capacity(key_len) < prefabs {
+        key_len += 1;
+    }
+    key_len
+}
+
+/// The `index`th key of length `key_len`: "aa", "ab", ... "aZ", "ba", ...
+/// Only meaningful if `index` is below [`key_capacity`].
+pub fn key_for_index(mut index: usize, key_len: usize) -> String {
+    let mut key = vec![KEY_CHARACTERS[0]; key_len];
+    for character in key.iter_mut().rev() {
+        *character = KEY_CHARACTERS[index % KEY_CHARACTERS.len()];
+        index /= KEY_CHARACTERS.len();
+    }
+    // Only ever ASCII
+    String::from_utf8(key).unwrap()
+}
+
+/// Sorts `key` by where it comes in the order keys are handed out ([`key_for_index`]), rather
+/// than by byte, so "aZ" comes before "ba". Characters that aren't in [`KEY_CHARACTERS`] come
+/// after the ones that are.
+pub fn key_order(key: &str) -> Vec<usize> {
+    key.bytes()
+        .map(|byte| {
+            KEY_CHARACTERS
+                .iter()
+                .position(|&character| character == byte)
+                .unwrap_or(KEY_CHARACTERS.len() + byte as usize)
+        })
+        .collect()
+}
+
+/// Assigns a key to each of `prefabs` distinct prefabs, in order.
+pub fn allocate_keys(prefabs: usize, key_len: usize) -> Result<Vec<String>, WriteError> {
+    if key_len == 0 {
+        return Err(WriteError::ZeroKeyLength);
+    }
+    let capacity = key_capacity(key_len);
+    if prefabs > capacity {
+        return Err(WriteError::TooManyPrefabs {
+            prefabs,
+            key_len,
+            capacity,
+        });
+    }
+    Ok((0..prefabs)
+        .map(|index| key_for_index(index, key_len))
+        .collect())
+}
+
+/// Formats a number for writing into a map: integers without a decimal point, everything else
+/// in as few digits as read back to the same value. Unlike BYOND's own 6 significant digits,
+/// nothing is rounded off. Very large and very small numbers switch to an exponent in the same
+/// style as BYOND's, as `1e+07` or `1.5e-05`.
+pub fn format_number(n: f32) -> String {
+    if n.is_nan() {
+        return "nan".to_owned();
+    }
+    if n.is_infinite() {
+        return if n > 0. { "inf" } else { "-inf" }.to_owned();
+    }
+    if n == 0. {
+        // Including -0
+        return "0".to_owned();
+    }
+    let exponent = n.abs().log10().floor() as i32;
+    if (-4..7).contains(&exponent) {
+        return format!("{n}");
+    }
+    let scientific = format!("{n:e}");
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let (sign, digits) = match exponent.strip_prefix('-') {
+        Some(digits) => ('-', digits),
+        None => ('+', exponent),
+    };
+    format!("{mantissa}e{sign}{digits:0>2}")
+}
+
+impl fmt::Display for Literal<'_> {
+    /// Formats the literal the same way it would appear in a map.
+    /// Strings and files are kept escaped by the parser, so they're written back verbatim.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Literal::Number(n) => write!(f, "{}", format_number(*n)),
+            Literal::String(s) => write!(f, "\"{s}\""),
+            Literal::Path(p) => write!(f, "{p}"),
+            Literal::File(file) => write!(f, "'{file}'"),
+            Literal::Null => write!(f, "null"),
+            Literal::Fallback(s) => write!(f, "{s}"),
+            Literal::List(list) => {
+                write!(f, "list(")?;
+                for (index, item) in list.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, ")")
+            }
+            Literal::AssocList(list) => {
+                write!(f, "list(")?;
+                for (index, (key, value)) in list.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{key} = {value}")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Formats a tile's prefab list the way it goes between the parentheses of a prefab definition
+pub fn format_prefab_list(prefabs: &[Prefab]) -> String {
+    let mut out = String::new();
+    for (index, (path, vars)) in prefabs.iter().enumerate() {
+        if index > 0 {
+            out.push(',');
+        }
+        out.push_str(path);
+        if let Some(vars) = vars {
+            out.push('{');
+            for (var_index, (var, value)) in vars.iter().enumerate() {
+                if var_index > 0 {
+                    out.push_str("; ");
+                }
+                out.push_str(&format!("{var} = {value}"));
+            }
+            out.push('}');
+        }
+    }
+    out
+}
+
+/// [`format_prefab_list`] as TGM writes it, with every path on a line of its own and every var
+/// on one of its own within that.
+pub fn format_prefab_list_tgm(prefabs: &[Prefab]) -> String {
+    let mut out = String::new();
+    for (index, (path, vars)) in prefabs.iter().enumerate() {
+        if index > 0 {
+            out.push_str(",\n");
+        }
+        out.push_str(path);
+        if let Some(vars) = vars {
+            out.push('{');
+            for (var_index, (var, value)) in vars.iter().enumerate() {
+                if var_index > 0 {
+                    out.push(';');
+                }
+                out.push_str(&format!("\n\t{var} = {value}"));
+            }
+            out.push_str("\n\t}");
+        }
+    }
+    out
+}
+
+/// Writes only the prefab definitions of a map, in key order and formatted the same way as
+/// [`write_dmm`], so that two versions of a map can be diffed without their layout getting in the way.
+/// Run it on the output of [`canonicalize`] to fold identical prefabs together first.
+pub fn write_prefabs<K: AsRef<str>>(prefabs: &HashMap<K, Vec<Prefab>>) -> String {
+    write_prefabs_as(prefabs, false)
+}
+
+/// [`write_prefabs`], or as TGM with `tgm`
+fn write_prefabs_as<K: AsRef<str>>(prefabs: &HashMap<K, Vec<Prefab>>, tgm: bool) -> String {
+    let mut definitions: Vec<(&str, &[Prefab])> = prefabs
+        .iter()
+        .map(|(key, prefab)| (key.as_ref(), prefab.as_slice()))
+        .collect();
+    definitions.sort_by_cached_key(|(key, _)| key_order(key));
+
+    let mut out = String::new();
+    for (key, prefab) in definitions {
+        if tgm {
+            out.push_str(&format!(
+                "\"{key}\" = (\n{})\n",
+                format_prefab_list_tgm(prefab)
+            ));
+        } else {
+            out.push_str(&format!("\"{key}\" = ({})\n", format_prefab_list(prefab)));
+        }
+    }
+    out
+}
+
+/// Writes a parsed map back out with the keys it already has, so that re-writing a map only
+/// changes what was changed. Prefabs are in key order and blocks as they were parsed.
+///
+/// A map that was already written this way (by [`write_dmm`] or an earlier `write_map`) comes
+/// out byte for byte the same. Anything else only has its prefabs and numbers reformatted, and
+/// its prefabs sorted by [`key_order`].
+/// Always writes DMM, see [`write_map_with_info`] for keeping a TGM map as TGM.
+pub fn write_map(prefabs: &Prefabs, blocks: &[Block]) -> String {
+    write_map_with_trailer(prefabs, blocks, None)
+}
+
+/// [`write_map`], with the map's [`crate::MapInfo::trailer`] after the last block as it was.
+pub fn write_map_with_trailer(
+    prefabs: &Prefabs,
+    blocks: &[Block],
+    trailer: Option<&str>,
+) -> String {
+    write_map_as(prefabs, blocks, trailer, false)
+}
+
+/// [`write_map`], in the format the map was read in: TGM (under [`TGM_HEADER`]) if
+/// [`MapInfo::is_tgm`], and with its trailer. A TGM map that was already written this way, by
+/// dmm2tgm.py or an earlier `write_map_with_info`, comes out byte for byte the same, except for
+/// numbers that weren't written the way [`format_number`] writes them.
+pub fn write_map_with_info(info: &MapInfo, prefabs: &Prefabs, blocks: &[Block]) -> String {
+    write_map_as(prefabs, blocks, info.trailer.as_deref(), info.is_tgm)
+}
+
+fn write_map_as(prefabs: &Prefabs, blocks: &[Block], trailer: Option<&str>, tgm: bool) -> String {
+    let mut out = String::new();
+    if tgm {
+        out.push_str(TGM_HEADER);
+        out.push('\n');
+    }
+    out.push_str(&write_prefabs_as(prefabs, tgm));
+    out.push('\n');
+    for (coord, lines) in blocks {
+        write_block(&mut out, *coord, lines);
+    }
+    if let Some(trailer) = trailer {
+        out.push_str(trailer);
+    }
+    out
+}
+
+/// Writes one block, with its rows as they are
+fn write_block<S: AsRef<str>>(out: &mut String, (x, y, z): (usize, usize, usize), lines: &[S]) {
+    out.push_str(&format!("({x},{y},{z}) = {{\"\n"));
+    for line in lines {
+        out.push_str(line.as_ref());
+        out.push('\n');
+    }
+    out.push_str("\"}\n");
+}
+
+/// Writes `levels` out as a DMM, with one block per z-level starting at (1, 1, 1).
+///
+/// Identical tiles share a key. Errors if there are more distinct tiles than keys of
+/// `key_len` characters can encode, see [`min_key_length`].
+pub fn write_dmm(levels: &[Level], key_len: usize) -> Result<String, WriteError> {
+    // Formatted prefab list -> index of its key, in order of first use
+    let mut indices: HashMap<String, usize> = HashMap::new();
+    let mut definitions = vec![];
+    let levels: Vec<Vec<Vec<usize>>> = levels
+        .iter()
+        .map(|rows| {
+            rows.iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|tile| {
+                            let definition = format_prefab_list(tile);
+                            *indices.entry(definition.clone()).or_insert_with(|| {
+                                definitions.push(definition);
+                                definitions.len() - 1
+                            })
+                        })
+                        .collect()
+                })
+                .collect()
+        })
+        .collect();
+
+    let keys = allocate_keys(definitions.len(), key_len)?;
+
+    let mut out = String::new();
+    for (key, definition) in keys.iter().zip(&definitions) {
+        out.push_str(&format!("\"{key}\" = ({definition})\n"));
+    }
+    out.push('\n');
+
+    for (z, rows) in levels.iter().enumerate() {
+        out.push_str(&format!("(1,1,{}) = {{\"\n", z + 1));
+        // Rows are listed top to bottom
+        for row in rows.iter().rev() {
+            for index in row {
+                out.push_str(&keys[*index]);
+            }
+            out.push('\n');
+        }
+        out.push_str("\"}\n");
+    }
+
+    Ok(out)
+}
+
+/// A block whose rows were rewritten, so it owns its keys
+pub type OwnedBlock = ((usize, usize, usize), Vec<String>);
+
+/// Merges keys whose prefab lists are identical, vars and nested lists included, into the first
+/// of them (alphabetically) and rewrites the blocks to use it.
+pub fn canonicalize<'s>(
+    prefabs: &Prefabs<'s>,
+    blocks: &[Block<'s>],
+) -> (Prefabs<'s>, Vec<OwnedBlock>) {
+    let mut keys: Vec<&'s str> = prefabs.keys().copied().collect();
+    keys.sort_unstable();
+
+    // Identical prefab lists always format the same, so only those sharing a bucket need comparing
+    let mut buckets: HashMap<String, Vec<&'s str>> = HashMap::new();
+    let mut canonical_keys: HashMap<&'s str, &'s str> = HashMap::new();
+    for key in keys {
+        let bucket = buckets
+            .entry(format_prefab_list(&prefabs[key]))
+            .or_default();
+        let canonical = match bucket.iter().find(|other| prefabs[**other] == prefabs[key]) {
+            Some(other) => *other,
+            None => {
+                bucket.push(key);
+                key
+            }
+        };
+        canonical_keys.insert(key, canonical);
+    }
+
+    let canonical_prefabs = prefabs
+        .iter()
+        .filter(|(key, _)| canonical_keys[*key] == **key)
+        .map(|(key, prefab)| (*key, prefab.clone()))
+        .collect();
+
+    let key_len = detect_key_length(prefabs);
+    let blocks = blocks
+        .iter()
+        .map(|(coord, lines)| {
+            let lines = lines
+                .iter()
+                .map(|line| {
+                    if key_len == 0 {
+                        return line.to_string();
+                    }
+                    (0..line.len() / key_len)
+                        .map(|index| {
+                            let key = &line[index * key_len..(index + 1) * key_len];
+                            // Undefined keys are left alone
+                            canonical_keys.get(key).copied().unwrap_or(key)
+                        })
+                        .collect()
+                })
+                .collect();
+            (*coord, lines)
+        })
+        .collect();
+
+    (canonical_prefabs, blocks)
+}
+
+/// Several maps combined into one, with keys of its own. See [`merge_maps`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergedMap<'s> {
+    pub key_len: usize,
+    pub prefabs: HashMap<String, Vec<Prefab<'s>>>,
+    pub blocks: Vec<OwnedBlock>,
+}
+
+impl MergedMap<'_> {
+    /// Writes the merged map out as a DMM, prefabs in key order and blocks in the order they were merged.
+    pub fn to_dmm(&self) -> String {
+        write_owned_map(&self.prefabs, &self.blocks)
+    }
+}
+
+/// Writes prefabs in key order, then blocks in order
+fn write_owned_map(prefabs: &HashMap<String, Vec<Prefab>>, blocks: &[OwnedBlock]) -> String {
+    let mut out = write_prefabs(prefabs);
+    out.push('\n');
+
+    for (coord, lines) in blocks {
+        write_block(&mut out, *coord, lines);
+    }
+    out
+}
+
+/// A map that owns its keys, so that its tiles can be changed with [`EditableMap::set_tile`].
+/// Unlike [`merge_maps`], a parsed map keeps the keys it already has, so writing it back out only
+/// changes what was changed.
+#[derive(Debug, Clone)]
+pub struct EditableMap<'s> {
+    key_len: usize,
+    prefabs: HashMap<String, Vec<Prefab<'s>>>,
+    blocks: Vec<OwnedBlock>,
+    /// Every key that's defined or used by a block. Undefined keys are still on the map, and are
+    /// reported when loading, so they're never handed out.
+    used_keys: HashSet<String>,
+    /// Formatted prefab list -> every key defined as it, the same as in [`canonicalize`]
+    buckets: HashMap<String, Vec<String>>,
+    /// Keys are never given back, so every key before this index is already used
+    next_key: usize,
+}
+
+impl<'s> EditableMap<'s> {
+    /// A map with nothing on it, to build up a tile at a time
+    pub fn new(key_len: usize) -> Self {
+        EditableMap {
+            key_len,
+            prefabs: HashMap::new(),
+            blocks: vec![],
+            used_keys: HashSet::new(),
+            buckets: HashMap::new(),
+            next_key: 0,
+        }
+    }
+
+    /// A parsed map, with the keys and blocks it already has
+    pub fn from_map((prefabs, blocks): &MapData<'s>) -> Self {
+        let mut map = EditableMap::new(detect_key_length(prefabs));
+        for (key, prefab_list) in prefabs {
+            map.define(key.to_string(), prefab_list.clone());
+        }
+        for (coord, lines) in blocks {
+            for line in lines {
+                map.used_keys
+                    .extend(keys_in_line(line, map.key_len).map(str::to_owned));
+            }
+            let lines = lines.iter().map(|line| line.to_string()).collect();
+            map.blocks.push((*coord, lines));
+        }
+        map
+    }
+
+    pub fn key_len(&self) -> usize {
+        self.key_len
+    }
+
+    pub fn prefabs(&self) -> &HashMap<String, Vec<Prefab<'s>>> {
+        &self.prefabs
+    }
+
+    pub fn blocks(&self) -> &[OwnedBlock] {
+        &self.blocks
+    }
+
+    /// Writes the map out as a DMM, prefabs in key order and blocks in order.
+    pub fn to_dmm(&self) -> String {
+        write_owned_map(&self.prefabs, &self.blocks)
+    }
+
+    /// Sets the tile at `coord` to `prefab_list`, returning its key. A prefab list that's already
+    /// defined reuses its key (the first alphabetically, if there's more than one), anything else
+    /// gets the first key that isn't defined or used. Keys that were used before are kept, even
+    /// if nothing uses them any more.
+    ///
+    /// Where blocks overlap the later one is rewritten, as that's the one that gets loaded. A
+    /// `coord` outside of every block is added to a block it's next to where that keeps the block
+    /// a rectangle, or gets a block of its own otherwise, so a map can be built up a tile at a
+    /// time. Errors if every key of [`EditableMap::key_len`] characters is taken.
+    pub fn set_tile(
+        &mut self,
+        coord: (usize, usize, usize),
+        prefab_list: Vec<Prefab<'s>>,
+    ) -> Result<String, WriteError> {
+        if self.key_len == 0 {
+            return Err(WriteError::ZeroKeyLength);
+        }
+        let key = match self.defined_key(&prefab_list) {
+            Some(key) => key,
+            None => {
+                let key = self.free_key()?;
+                self.define(key.clone(), prefab_list);
+                key
+            }
+        };
+
+        let key_len = self.key_len;
+        let cell = self
+            .blocks
+            .iter_mut()
+            .rev()
+            .find_map(|(bottom_left, lines)| {
+                if coord.2 != bottom_left.2 {
+                    return None;
+                }
+                let map_x_offset = coord.0.checked_sub(bottom_left.0)?;
+                let map_y_offset = coord.1.checked_sub(bottom_left.1)?;
+                // Rows are listed top to bottom, so the last row is at the block's y
+                let row = lines.len().checked_sub(map_y_offset + 1)?;
+                let columns = map_x_offset * key_len..(map_x_offset + 1) * key_len;
+                (columns.end <= lines[row].len()).then(|| (&mut lines[row], columns))
+            });
+        match cell {
+            Some((line, columns)) => line.replace_range(columns, &key),
+            None => self.add_tile(coord, &key),
+        }
+        Ok(key)
+    }
+
+    fn define(&mut self, key: String, prefab_list: Vec<Prefab<'s>>) {
+        self.used_keys.insert(key.clone());
+        self.buckets
+            .entry(format_prefab_list(&prefab_list))
+            .or_default()
+            .push(key.clone());
+        self.prefabs.insert(key, prefab_list);
+    }
+
+    /// The first key (alphabetically) that's defined as `prefab_list`
+    fn defined_key(&self, prefab_list: &[Prefab<'s>]) -> Option<String> {
+        self.buckets
+            .get(&format_prefab_list(prefab_list))?
+            .iter()
+            .filter(|key| self.prefabs[*key] == prefab_list)
+            .min()
+            .cloned()
+    }
+
+    /// The first key that's neither defined nor used by a block
+    fn free_key(&mut self) -> Result<String, WriteError> {
+        let capacity = key_capacity(self.key_len);
+        while self.next_key < capacity {
+            let key = key_for_index(self.next_key, self.key_len);
+            self.next_key += 1;
+            if !self.used_keys.contains(&key) {
+                return Ok(key);
+            }
+        }
+        Err(WriteError::TooManyPrefabs {
+            prefabs: self.used_keys.len() + 1,
+            key_len: self.key_len,
+            capacity,
+        })
+    }
+
+    /// Puts `key` at `coord`, which isn't in any block yet
+    fn add_tile(&mut self, coord: (usize, usize, usize), key: &str) {
+        let key_len = self.key_len;
+        let grown = self
+            .blocks
+            .iter_mut()
+            .rposition(|block| grow_block(block, coord, key, key_len));
+        let index = grown.unwrap_or_else(|| {
+            self.blocks.push((coord, vec![key.to_owned()]));
+            self.blocks.len() - 1
+        });
+        self.merge_neighbour(index);
+    }
+
+    /// Merges the block at `index` with one it lines up with exactly, unless a block in between
+    /// them overlaps either, as that would change which block wins there.
+    fn merge_neighbour(&mut self, index: usize) {
+        let key_len = self.key_len;
+        let Some(rect) = block_rect(&self.blocks[index], key_len) else {
+            return;
+        };
+        let neighbour = (0..self.blocks.len())
+            .filter(|other| *other != index)
+            .find_map(|other| {
+                let side = adjoining_side(rect, block_rect(&self.blocks[other], key_len)?)?;
+                let (first, last) = (index.min(other), index.max(other));
+                let in_between_overlaps = self.blocks[first + 1..last].iter().any(|between| {
+                    blocks_overlap(between, &self.blocks[index], key_len)
+                        || blocks_overlap(between, &self.blocks[other], key_len)
+                });
+                (!in_between_overlaps).then_some((other, side))
+            });
+        let Some((other, side)) = neighbour else {
+            return;
+        };
+
+        // Where the earlier of the two was, since nothing between them covers either
+        let (first, last) = (index.min(other), index.max(other));
+        let last_block = self.blocks.remove(last);
+        let first_block = std::mem::take(&mut self.blocks[first]);
+        self.blocks[first] = if index == first {
+            join_blocks(first_block, last_block, side)
+        } else {
+            join_blocks(last_block, first_block, side)
+        };
+    }
+}
+
+/// `other` put on the `side` of `block`, see [`adjoining_side`]
+fn join_blocks(
+    (mut coord, mut lines): OwnedBlock,
+    (other_coord, other_lines): OwnedBlock,
+    side: Side,
+) -> OwnedBlock {
+    match side {
+        // Rows are listed top to bottom
+        Side::Above => {
+            lines.splice(0..0, other_lines);
+        }
+        Side::Below => {
+            lines.extend(other_lines);
+            coord = other_coord;
+        }
+        Side::Right => {
+            for (line, other_line) in lines.iter_mut().zip(other_lines) {
+                line.push_str(&other_line);
+            }
+        }
+        Side::Left => {
+            for (line, other_line) in lines.iter_mut().zip(other_lines) {
+                line.insert_str(0, &other_line);
+            }
+            coord = other_coord;
+        }
+    }
+    (coord, lines)
+}
+
+/// Where a block is next to another, see [`adjoining_side`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Above,
+    Below,
+    Left,
+    Right,
+}
+
+/// The bottom left and (width, height) of a block, if every row is the same whole number of keys
+type Rect = ((usize, usize, usize), (usize, usize));
+
+fn block_rect((coord, lines): &OwnedBlock, key_len: usize) -> Option<Rect> {
+    let width = lines.first()?.len() / key_len;
+    let rectangular = lines.iter().all(|line| line.len() == width * key_len);
+    (width > 0 && rectangular).then_some((*coord, (width, lines.len())))
+}
+
+/// Which side of `rect` `other` is on, if they're the same size along it and touch all the way
+fn adjoining_side(rect: Rect, other: Rect) -> Option<Side> {
+    let (((x, y, z), (width, height)), ((other_x, other_y, other_z), (other_width, other_height))) =
+        (rect, other);
+    if z != other_z {
+        return None;
+    }
+    if x == other_x && width == other_width {
+        if other_y == y + height {
+            return Some(Side::Above);
+        }
+        if y == other_y + other_height {
+            return Some(Side::Below);
+        }
+    }
+    if y == other_y && height == other_height {
+        if other_x == x + width {
+            return Some(Side::Right);
+        }
+        if x == other_x + other_width {
+            return Some(Side::Left);
+        }
+    }
+    None
+}
+
+fn blocks_overlap(first: &OwnedBlock, second: &OwnedBlock, key_len: usize) -> bool {
+    fn as_block((coord, lines): &OwnedBlock) -> Block<'_> {
+        (*coord, lines.iter().map(String::as_str).collect())
+    }
+    !block_overlaps(&[as_block(first), as_block(second)], key_len).is_empty()
+}
+
+/// Adds `key` at `coord` to a block that's a single row or column, if it's just past either end of it
+fn grow_block(
+    ((x, y, z), lines): &mut OwnedBlock,
+    coord: (usize, usize, usize),
+    key: &str,
+    key_len: usize,
+) -> bool {
+    if *z != coord.2 {
+        return false;
+    }
+    if let [line] = lines.as_mut_slice() {
+        if coord.1 == *y && line.len() % key_len == 0 {
+            if coord.0 == *x + line.len() / key_len {
+                line.push_str(key);
+                return true;
+            }
+            if coord.0 + 1 == *x {
+                line.insert_str(0, key);
+                *x -= 1;
+                return true;
+            }
+        }
+    }
+    if coord.0 == *x && lines.iter().all(|line| line.len() == key_len) {
+        // Rows are listed top to bottom
+        if coord.1 == *y + lines.len() {
+            lines.insert(0, key.to_owned());
+            return true;
+        }
+        if coord.1 + 1 == *y {
+            lines.push(key.to_owned());
+            *y -= 1;
+            return true;
+        }
+    }
+    false
+}
+
+/// Each key of a row of a block
+fn keys_in_line(line: &str, key_len: usize) -> impl Iterator<Item = &str> {
+    let columns = line.len().checked_div(key_len).unwrap_or(0);
+    (0..columns).map(move |column| &line[column * key_len..(column + 1) * key_len])
+}
+
+/// Combines `maps` into one map, each moved so that its (1, 1, 1) is at its offset, the same as
+/// the offsets used when loading. Offsets start at 1, so (1, 1, 1) leaves a map where it is.
+///
+/// Keys are handed out again, as different maps use the same key for different things. Identical
+/// prefab lists share a key, even across maps. Keys a map uses without defining them each get a
+/// key of their own, which stays undefined so it's still reported when loading. Blocks are kept
+/// in order, so where maps overlap the later one wins.
+pub fn merge_maps<'s>(maps: &[(&MapData<'s>, (usize, usize, usize))]) -> MergedMap<'s> {
+    // What's on a tile: a prefab list, or an undefined key of one of the maps
+    #[derive(Clone, Copy, PartialEq)]
+    enum Tile<'a, 's> {
+        Defined(&'a [Prefab<'s>]),
+        Undefined(usize, &'s str),
+    }
+
+    // Every distinct tile, in order of first use. Its index is the index of its key.
+    let mut tiles: Vec<Tile> = vec![];
+    // Identical prefab lists always format the same, so only those sharing a bucket need comparing
+    let mut buckets: HashMap<String, Vec<usize>> = HashMap::new();
+
+    // Each block, as the index of every tile in every row
+    let mut blocks = vec![];
+    for (map, ((prefabs, map_blocks), offset)) in maps.iter().enumerate() {
+        let key_len = detect_key_length(prefabs);
+        for ((x, y, z), lines) in map_blocks {
+            let rows: Vec<Vec<usize>> = lines
+                .iter()
+                .map(|line| {
+                    if key_len == 0 {
+                        return vec![];
+                    }
+                    (0..line.len() / key_len)
+                        .map(|column| {
+                            let key = &line[column * key_len..(column + 1) * key_len];
+                            let (tile, bucket) = match prefabs.get(key) {
+                                Some(prefab_list) => {
+                                    (Tile::Defined(prefab_list), format_prefab_list(prefab_list))
+                                }
+                                None => (Tile::Undefined(map, key), format!("{map}:{key}")),
+                            };
+                            let bucket = buckets.entry(bucket).or_default();
+                            if let Some(index) = bucket.iter().find(|index| tiles[**index] == tile)
+                            {
+                                return *index;
+                            }
+                            tiles.push(tile);
+                            bucket.push(tiles.len() - 1);
+                            tiles.len() - 1
+                        })
+                        .collect()
+                })
+                .collect();
+            blocks.push(((x + offset.0 - 1, y + offset.1 - 1, z + offset.2 - 1), rows));
+        }
+    }
+
+    let key_len = min_key_length(tiles.len());
+    let keys =
+        allocate_keys(tiles.len(), key_len).expect("min_key_length always has room for every tile");
+
+    let prefabs = tiles
+        .iter()
+        .zip(&keys)
+        .filter_map(|(tile, key)| match tile {
+            Tile::Defined(prefab_list) => Some((key.clone(), prefab_list.to_vec())),
+            Tile::Undefined(..) => None,
+        })
+        .collect();
+    let blocks = blocks
+        .into_iter()
+        .map(|(coord, rows)| {
+            let lines = rows
+                .iter()
+                .map(|row| row.iter().map(|index| keys[*index].as_str()).collect())
+                .collect();
+            (coord, lines)
+        })
+        .collect();
+
+    MergedMap {
+        key_len,
+        prefabs,
+        blocks,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{block::key_at, parse_map_multithreaded};
+
+    use super::*;
+
+    #[test]
+    fn test_key_allocation() {
+        assert_eq!(key_for_index(0, 2), "aa");
+        assert_eq!(key_for_index(51, 2), "aZ");
+        assert_eq!(key_for_index(52, 2), "ba");
+        assert_eq!(key_for_index(52 * 52 - 1, 2), "ZZ");
+        assert_eq!(allocate_keys(3, 3).unwrap(), vec!["aaa", "aab", "aac"]);
+
+        assert_eq!(min_key_length(52), 1);
+        assert_eq!(min_key_length(53), 2);
+        assert_eq!(
+            allocate_keys(53, 1),
+            Err(WriteError::TooManyPrefabs {
+                prefabs: 53,
+                key_len: 1,
+                capacity: 52
+            })
+        );
+        assert_eq!(allocate_keys(1, 0), Err(WriteError::ZeroKeyLength));
+    }
+
+    #[test]
+    fn test_write_round_trip() {
+        let floor = vec![("/turf/open/floor", None), ("/area/station", None)];
+        let crowbar = vec![
+            (
+                "/obj/item/crowbar",
+                Some(vec![
+                    ("name", Literal::String("a \\\"crowbar\\\"")),
+                    ("pixel_x", Literal::Number(-4.5)),
+                    (
+                        "contents",
+                        Literal::AssocList(vec![(
+                            Literal::Path("/obj/item/wrench"),
+                            Literal::Number(1.),
+                        )]),
+                    ),
+                    (
+                        "spawns",
+                        Literal::List(vec![Literal::Number(1.), Literal::Null]),
+                    ),
+                ]),
+            ),
+            (
+                "/turf/open/floor",
+                Some(vec![("icon", Literal::File("floor.dmi"))]),
+            ),
+            ("/area/station", None),
+        ];
+        let levels = vec![
+            vec![
+                vec![floor.clone(), crowbar.clone()],
+                vec![crowbar.clone(), floor.clone()],
+            ],
+            vec![vec![floor.clone()]],
+        ];
+
+        let written = write_dmm(&levels, 3).unwrap();
+        let (_info, (prefabs, blocks)) =
+            parse_map_multithreaded("written".to_owned(), &written).unwrap();
+
+        assert_eq!(prefabs.len(), 2);
+        assert_eq!(prefabs["aaa"], floor);
+        assert_eq!(prefabs["aab"], crowbar);
+        assert_eq!(
+            blocks,
+            vec![
+                ((1, 1, 1), vec!["aabaaa", "aaaaab"]),
+                ((1, 1, 2), vec!["aaa"])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_number() {
+        assert_eq!(format_number(1.), "1");
+        assert_eq!(format_number(-0.), "0");
+        assert_eq!(format_number(-4.5), "-4.5");
+        assert_eq!(format_number(0.1), "0.1");
+        assert_eq!(format_number(1000.), "1000");
+        assert_eq!(format_number(1_000_000.), "1000000");
+        assert_eq!(format_number(10_000_000.), "1e+07");
+        // BYOND would round this to 1.67772e+07
+        assert_eq!(format_number(16_777_216.), "1.6777216e+07");
+        assert_eq!(format_number(16_777_216.), "1.6777216e+07");
+        assert_eq!(format_number(0.00015), "0.00015");
+        assert_eq!(format_number(0.000015), "1.5e-05");
+        assert_eq!(format_number(1e-30), "1e-30");
+        assert_eq!(format_number(f32::INFINITY), "inf");
+    }
+
+    #[test]
+    fn test_number_round_trip() {
+        let numbers = [
+            "1", "-1", "0", "-4.5", "0.1", "0.25", "1000", "16", "3.14159", "1e+07", "-2.5e+10",
+            "1.5e-05", "1e-30",
+        ];
+        let vars: Vec<String> = numbers
+            .iter()
+            .enumerate()
+            .map(|(index, n)| format!("var{index} = {n}"))
+            .collect();
+        let map = format!(
+            "\"a\" = (/obj{{{}}},/turf,/area)\n\n(1,1,1) = {{\"\na\n\"}}\n",
+            vars.join("; ")
+        );
+
+        let (_info, (prefabs, _blocks)) =
+            parse_map_multithreaded("numbers".to_owned(), &map).unwrap();
+        let written = format_prefab_list(&prefabs["a"]);
+        assert_eq!(written, format!("/obj{{{}}},/turf,/area", vars.join("; ")));
+
+        // Noisy spellings settle on one
+        let map = "\"a\" = (/obj{a = 1.0; b = 1.50; c = 10000000; d = 0.0000150},/turf,/area)\n\n(1,1,1) = {\"\na\n\"}\n";
+        let (_info, (prefabs, _blocks)) = parse_map_multithreaded("noisy".to_owned(), map).unwrap();
+        assert_eq!(
+            format_prefab_list(&prefabs["a"]),
+            "/obj{a = 1; b = 1.5; c = 1e+07; d = 1.5e-05},/turf,/area"
+        );
+    }
+
+    #[test]
+    fn test_merge_maps() {
+        let left = "\"a\" = (/turf/open/floor,/area/station)\n\"b\" = (/obj/item,/turf/open/floor,/area/station)\n\n(1,1,1) = {\"\nab\nba\n\"}\n";
+        // Same keys, but "a" is something else here
+        let right = "\"a\" = (/turf/closed/wall,/area/station)\n\"b\" = (/obj/item,/turf/open/floor,/area/station)\n\n(1,1,1) = {\"\nabx\n\"}\n";
+        let (_info, left) = parse_map_multithreaded("left".to_owned(), left).unwrap();
+        let (_info, right) = parse_map_multithreaded("right".to_owned(), right).unwrap();
+
+        let merged = merge_maps(&[(&left, (1, 1, 1)), (&right, (3, 2, 1))]);
+        // Floor, item, wall and the undefined "x"
+        assert_eq!(merged.key_len, 1);
+        assert_eq!(merged.prefabs.len(), 3);
+        assert_eq!(
+            merged.blocks,
+            vec![
+                ((1, 1, 1), vec!["ab".to_owned(), "ba".to_owned()]),
+                ((3, 2, 1), vec!["cbd".to_owned()]),
+            ]
+        );
+
+        let text = merged.to_dmm();
+        let (_info, (prefabs, blocks)) =
+            parse_map_multithreaded("merged".to_owned(), &text).unwrap();
+        let tile_at = |coord| key_at(&blocks, 1, coord).and_then(|key| prefabs.get(key));
+        assert_eq!(tile_at((1, 2, 1)), Some(&left.0["a"]));
+        assert_eq!(tile_at((2, 2, 1)), Some(&left.0["b"]));
+        assert_eq!(tile_at((3, 2, 1)), Some(&right.0["a"]));
+        // Shared between both maps
+        assert_eq!(tile_at((4, 2, 1)), Some(&left.0["b"]));
+        assert_eq!(key_at(&blocks, 1, (5, 2, 1)), Some("d"));
+        assert_eq!(tile_at((5, 2, 1)), None);
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        fn with_contents(inner: f32) -> Vec<Prefab<'static>> {
+            vec![
+                (
+                    "/obj/item",
+                    Some(vec![(
+                        "contents",
+                        Literal::AssocList(vec![(
+                            Literal::Path("/obj/item"),
+                            Literal::List(vec![Literal::Number(1.), Literal::Number(inner)]),
+                        )]),
+                    )]),
+                ),
+                ("/turf", None),
+                ("/area", None),
+            ]
+        }
+        let floor = vec![("/turf", None), ("/area", None)];
+        let prefabs: Prefabs = [
+            ("a", with_contents(2.)),
+            ("b", floor.clone()),
+            ("c", with_contents(2.)),
+            // Only differs deep inside the nested list
+            ("d", with_contents(3.)),
+            ("e", floor),
+        ]
+        .into_iter()
+        .collect();
+        let blocks = vec![((1, 1, 1), vec!["abcde", "edcbx"])];
+
+        let (prefabs, blocks) = canonicalize(&prefabs, &blocks);
+
+        let mut keys: Vec<_> = prefabs.keys().copied().collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec!["a", "b", "d"]);
+        assert_eq!(prefabs["d"], with_contents(3.));
+        assert_eq!(
+            blocks,
+            vec![((1, 1, 1), vec!["abadb".to_owned(), "bdabx".to_owned()])]
+        );
+    }
+
+    #[test]
+    fn test_write_prefabs() {
+        // Out of order, with messy spacing and numbers
+        let map = r#""b" = (/obj/item{name = "crowbar";pixel_x = 1.50},/turf/open/floor,/area/station)
+"a" = (/turf/open/floor,/area/station)
+
+(1,1,1) = {"
+ab
+"}
+"#;
+        let (_info, (prefabs, _blocks)) = parse_map_multithreaded("map".to_owned(), map).unwrap();
+        assert_eq!(
+            write_prefabs(&prefabs),
+            r#""a" = (/turf/open/floor,/area/station)
+"b" = (/obj/item{name = "crowbar"; pixel_x = 1.5},/turf/open/floor,/area/station)
+"#
+        );
+
+        // In the order keys are handed out, not by byte
+        let prefabs = Prefabs::from([("aA", vec![]), ("az", vec![]), ("ba", vec![])]);
+        assert_eq!(
+            write_prefabs(&prefabs),
+            "\"az\" = ()\n\"aA\" = ()\n\"ba\" = ()\n"
+        );
+    }
+
+    #[test]
+    fn test_set_tile() {
+        let map = "\"a\" = (/turf/open/floor,/area/station)\n\"b\" = (/obj/item,/turf/open/floor,/area/station)\n\n(1,1,1) = {\"\nab\nbx\n\"}\n";
+        let (_info, map) = parse_map_multithreaded("map".to_owned(), map).unwrap();
+        let mut edited = EditableMap::from_map(&map);
+        let wall = vec![("/turf/closed/wall", None), ("/area/station", None)];
+
+        // The undefined "x" is still taken
+        assert_eq!(edited.set_tile((2, 1, 1), wall.clone()), Ok("c".to_owned()));
+        // Already defined
+        assert_eq!(edited.set_tile((2, 2, 1), wall.clone()), Ok("c".to_owned()));
+        assert_eq!(
+            edited.set_tile((1, 1, 1), map.0["a"].clone()),
+            Ok("a".to_owned())
+        );
+        // A column of its own, until it's as tall as the block next to it
+        edited.set_tile((3, 1, 1), wall.clone()).unwrap();
+        assert_eq!(edited.blocks().len(), 2);
+        edited.set_tile((3, 2, 1), wall.clone()).unwrap();
+        // Nothing was there yet
+        let window = vec![("/obj/window", None), ("/area/station", None)];
+        assert_eq!(edited.set_tile((5, 5, 2), window), Ok("d".to_owned()));
+        assert_eq!(
+            edited.blocks(),
+            [
+                ((1, 1, 1), vec!["acc".to_owned(), "acc".to_owned()]),
+                ((5, 5, 2), vec!["d".to_owned()]),
+            ]
+        );
+        assert_eq!(edited.prefabs().len(), 4);
+        // Keys that weren't touched are as they were
+        assert_eq!(edited.prefabs()["b"], map.0["b"]);
+
+        let mut empty = EditableMap::new(0);
+        assert_eq!(
+            empty.set_tile((1, 1, 1), map.0["a"].clone()),
+            Err(WriteError::ZeroKeyLength)
+        );
+    }
+
+    #[test]
+    fn test_set_tile_builds_blocks() {
+        let floor = vec![("/turf/open/floor", None), ("/area/station", None)];
+        let wall = vec![("/turf/closed/wall", None), ("/area/station", None)];
+        let mut built = EditableMap::new(1);
+        for y in 1..=2 {
+            for x in 1..=3 {
+                let tile = if x == 2 { &wall } else { &floor };
+                built.set_tile((x, y, 1), tile.clone()).unwrap();
+            }
+        }
+        assert_eq!(
+            built.to_dmm(),
+            "\"a\" = (/turf/open/floor,/area/station)\n\"b\" = (/turf/closed/wall,/area/station)\n\n(1,1,1) = {\"\naba\naba\n\"}\n"
+        );
+
+        // The later block wins where it overlaps the first, so the two on either side of it can't
+        // be merged without changing that
+        let map = "\"a\" = (/turf/open/floor,/area/station)\n\"b\" = (/obj/item,/turf/open/floor,/area/station)\n\n(1,1,1) = {\"\naa\n\"}\n(1,1,1) = {\"\nb\n\"}\n";
+        let (_info, map) = parse_map_multithreaded("map".to_owned(), map).unwrap();
+        let mut edited = EditableMap::from_map(&map);
+        edited.set_tile((2, 2, 1), floor.clone()).unwrap();
+        edited.set_tile((1, 2, 1), floor).unwrap();
+        assert_eq!(
+            edited.blocks(),
+            [
+                ((1, 1, 1), vec!["aa".to_owned()]),
+                ((1, 1, 1), vec!["b".to_owned()]),
+                ((1, 2, 1), vec!["aa".to_owned()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_map_keeps_keys() {
+        let map = r#""ab" = (/obj/item{name = "crowbar"; pixel_x = 1.5},/turf/open/floor,/area/station)
+"zz" = (/turf/open/floor,/area/station)
+
+(1,1,1) = {"
+zzab
+abzz
+"}
+(3,1,1) = {"
+zz
+"}
+"#;
+        let (_info, (prefabs, blocks)) = parse_map_multithreaded("map".to_owned(), map).unwrap();
+        assert_eq!(write_map(&prefabs, &blocks), map);
+
+        // Messy input keeps its keys, only the formatting changes
+        let messy = "\"zz\" = (/turf/open/floor,/area/station)\n\"ab\" = (/obj/item{name = \"crowbar\";pixel_x = 1.50},/turf/open/floor,/area/station)\n\n(1,1,1) = {\"\nzzab\nabzz\n\"}\n(3,1,1) = {\"\nzz\n\"}\n";
+        let (_info, (prefabs, blocks)) =
+            parse_map_multithreaded("messy".to_owned(), messy).unwrap();
+        assert_eq!(write_map(&prefabs, &blocks), map);
+    }
+
+    #[test]
+    fn test_write_map_key_order() {
+        // Enough prefabs to get past "az", where byte order and key order part ways
+        let paths: Vec<String> = (0..60).map(|index| format!("/turf/t{index}")).collect();
+        let row = paths
+            .iter()
+            .map(|path| vec![(path.as_str(), None), ("/area", None)])
+            .collect();
+        let written = write_dmm(&[vec![row]], 2).unwrap();
+        let (_info, (prefabs, blocks)) =
+            parse_map_multithreaded("written".to_owned(), &written).unwrap();
+        assert_eq!(write_map(&prefabs, &blocks), written);
+    }
+
+    #[test]
+    fn test_write_too_many_prefabs() {
+        let paths: Vec<String> = (0..53).map(|index| format!("/turf/t{index}")).collect();
+        let row = paths
+            .iter()
+            .map(|path| vec![(path.as_str(), None), ("/area", None)])
+            .collect();
+        let levels = vec![vec![row]];
+
+        assert!(matches!(
+            write_dmm(&levels, 1),
+            Err(WriteError::TooManyPrefabs { prefabs: 53, .. })
+        ));
+        assert!(write_dmm(&levels, 2).is_ok());
+    }
+}
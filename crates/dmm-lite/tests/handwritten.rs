@@ -1,7 +1,8 @@
 use dmm_lite::{
     block::{get_block_locations, parse_block},
-    parse_map_multithreaded,
-    prefabs::{detect_tgm, get_prefab_locations, parse_prefab_line, Literal},
+    parse_map_multithreaded, parse_map_timed,
+    prefabs::{detect_tgm, duplicate_vars, get_prefab_locations, parse_prefab_line, Literal},
+    writer::{format_prefab_list, write_map_with_trailer},
 };
 use winnow::{Located, Parser};
 
@@ -21,6 +22,21 @@ fn test_tgm_detection() {
     assert!(detect_tgm(&meow_tgm));
 }
 
+#[test]
+fn test_tgm_detection_with_header() {
+    let meow_tgm = std::fs::read_to_string("./tests/maps/handwritten-tgm.dmm").unwrap();
+    assert!(meow_tgm.starts_with("//MAP CONVERTED BY dmm2tgm.py"));
+    assert!(detect_tgm(&meow_tgm));
+    // Any comments, not just the dmm2tgm one
+    assert!(detect_tgm(&format!("// Made by hand\n\n{meow_tgm}")));
+
+    let commented = format!("// Made by hand\n{meow_tgm}");
+    let (meta, (prefabs, _blocks)) =
+        parse_map_multithreaded("Meow".to_owned(), &commented).unwrap();
+    assert!(meta.is_tgm);
+    assert_eq!(prefabs.len(), 3);
+}
+
 #[test]
 fn test_prefab_detection() {
     let meow = std::fs::read_to_string("./tests/maps/handwritten.dmm").unwrap();
@@ -179,3 +195,241 @@ fn full_parse() {
     assert_eq!(tgm_prefabs.len(), 3);
     assert_eq!(tgm_blocks.len(), 3);
 }
+
+#[test]
+fn test_mixed_var_separators() {
+    let map = std::fs::read_to_string("./tests/maps/mixed-separators.dmm").unwrap();
+    let (_meta, (prefabs, blocks)) = parse_map_multithreaded("Mixed".to_owned(), &map).unwrap();
+    assert_eq!(blocks.len(), 1);
+
+    assert_eq!(
+        prefabs["a"][0],
+        (
+            "/obj/item",
+            Some(vec![
+                ("name", Literal::String("semicolons")),
+                ("desc", Literal::String("dmm style")),
+            ])
+        )
+    );
+    assert_eq!(
+        prefabs["b"][0],
+        (
+            "/obj/item",
+            Some(vec![
+                ("name", Literal::String("newlines")),
+                ("desc", Literal::String("no semicolons")),
+            ])
+        )
+    );
+    assert_eq!(
+        prefabs["b"][1],
+        (
+            "/turf/space",
+            Some(vec![
+                ("name", Literal::String("mixed")),
+                ("desc", Literal::String("tgm style")),
+                ("icon_state", Literal::String("and;not")),
+            ])
+        )
+    );
+}
+
+#[test]
+fn test_tgm_space_indentation() {
+    let meow_tgm = std::fs::read_to_string("./tests/maps/handwritten-tgm.dmm").unwrap();
+    let spaces_tgm = std::fs::read_to_string("./tests/maps/handwritten-tgm-spaces.dmm").unwrap();
+
+    let (_meta, (prefabs, blocks)) = parse_map_multithreaded("Meow".to_owned(), &meow_tgm).unwrap();
+    let (spaces_meta, (spaces_prefabs, spaces_blocks)) =
+        parse_map_multithreaded("Spaces".to_owned(), &spaces_tgm).unwrap();
+    assert!(spaces_meta.is_tgm);
+    assert_eq!(spaces_blocks, blocks);
+
+    // Indented with spaces instead of tabs, but otherwise identical
+    assert_eq!(spaces_prefabs["aaa"], prefabs["aaa"]);
+    assert_eq!(
+        spaces_prefabs["aab"][0],
+        (
+            "/obj/item",
+            Some(vec![
+                ("dir", Literal::Number(4.)),
+                (
+                    "list_var",
+                    Literal::AssocList(vec![
+                        (Literal::String("a"), Literal::Number(1.)),
+                        (Literal::String("b"), Literal::Number(2.)),
+                    ])
+                ),
+            ])
+        )
+    );
+    // Mixed tabs and spaces
+    assert_eq!(
+        spaces_prefabs["aac"][0],
+        ("/obj/item", Some(vec![("name", Literal::String("mixed"))]))
+    );
+}
+
+#[test]
+fn test_spaced_block_headers() {
+    let meow = std::fs::read_to_string("./tests/maps/handwritten.dmm").unwrap();
+    let spaced = meow.replace("(1,1,1) = {", "(1, 1, 1)={");
+    assert_ne!(spaced, meow);
+
+    let (_meta, (_prefabs, blocks)) = parse_map_multithreaded("Meow".to_owned(), &meow).unwrap();
+    let (_meta, (_prefabs, spaced_blocks)) =
+        parse_map_multithreaded("Spaced".to_owned(), &spaced).unwrap();
+    assert_eq!(get_block_locations(&spaced).len(), 1);
+    assert_eq!(spaced_blocks, blocks);
+}
+
+#[test]
+fn test_braces_in_strings() {
+    let map = std::fs::read_to_string("./tests/maps/string-braces.dmm").unwrap();
+    let (_meta, (prefabs, blocks)) = parse_map_multithreaded("Braces".to_owned(), &map).unwrap();
+    assert_eq!(blocks.len(), 1);
+
+    assert_eq!(
+        prefabs["a"],
+        vec![
+            (
+                "/obj/item",
+                Some(vec![
+                    ("name", Literal::String("}closing")),
+                    ("desc", Literal::String("{opening} and {nested {braces}}")),
+                ])
+            ),
+            ("/turf/space", None),
+            ("/area/space", None),
+        ]
+    );
+    assert_eq!(
+        prefabs["b"],
+        vec![
+            (
+                "/obj/item",
+                Some(vec![
+                    ("name", Literal::String("a { b")),
+                    ("desc", Literal::String(r#"\"{quoted}\""#)),
+                ])
+            ),
+            (
+                "/turf/space",
+                Some(vec![
+                    ("name", Literal::String("}")),
+                    ("icon", Literal::File("icons/{odd}.dmi")),
+                ])
+            ),
+            ("/area/space", None),
+        ]
+    );
+    // Braces outside of strings nest, rather than ending the var list early
+    assert_eq!(
+        prefabs["c"][0],
+        (
+            "/obj/item",
+            Some(vec![
+                ("desc", Literal::Fallback(r#"{"text; block"}"#)),
+                ("name", Literal::String("after")),
+            ])
+        )
+    );
+}
+
+#[test]
+fn test_string_escapes() {
+    let map = std::fs::read_to_string("./tests/maps/string-escapes.dmm").unwrap();
+    let (_meta, (prefabs, blocks)) = parse_map_multithreaded("Escapes".to_owned(), &map).unwrap();
+    assert_eq!(blocks.len(), 1);
+
+    // Kept exactly as written, escapes and all
+    assert_eq!(
+        prefabs["a"][0].1,
+        Some(vec![
+            ("desc", Literal::String(r"ends with a backslash \\")),
+            ("name", Literal::String(r"\[not embedded\]")),
+        ])
+    );
+    assert_eq!(
+        prefabs["b"][0].1,
+        Some(vec![
+            ("desc", Literal::String(r"\improper [src] \\[one]\\")),
+            ("name", Literal::String(r#"\"quoted\" \\\"both\\\""#)),
+        ])
+    );
+    assert_eq!(
+        prefabs["b"][1].1,
+        Some(vec![("name", Literal::String(r"\\"))])
+    );
+    assert_eq!(
+        prefabs["c"][0].1,
+        Some(vec![
+            ("desc", Literal::String(r"C:\\maps\\")),
+            (
+                "list",
+                Literal::AssocList(vec![(Literal::String(r"\\"), Literal::String(r"\[\\\]"))])
+            ),
+        ])
+    );
+
+    // And written back out the same way
+    for line in map.lines().take(3) {
+        let (key, prefab_list) = line.split_once(" = (").unwrap();
+        let key = key.trim_matches('"');
+        assert_eq!(
+            format!("{})", format_prefab_list(&prefabs[key])),
+            prefab_list
+        );
+    }
+}
+
+#[test]
+fn test_deep_lists() {
+    let map = std::fs::read_to_string("./tests/maps/deep-lists.dmm").unwrap();
+    let (_meta, (prefabs, _blocks)) = parse_map_multithreaded("Deep".to_owned(), &map).unwrap();
+    let vars = prefabs["a"][0].1.as_ref().unwrap();
+
+    // Far past MAX_LIST_DEPTH, so it's kept as text rather than recursing all the way down
+    assert_eq!(vars[0].0, "deep");
+    assert!(matches!(vars[0].1, Literal::Fallback(text) if text.starts_with("list(list(")));
+    assert_eq!(vars[1], ("name", Literal::String("still parsed")));
+}
+
+#[test]
+fn test_parse_map_timed() {
+    let meow = std::fs::read_to_string("./tests/maps/handwritten.dmm").unwrap();
+    let (_meta, untimed) = parse_map_multithreaded("Meow".to_owned(), &meow).unwrap();
+    let (_meta, timed, timing) =
+        parse_map_timed("Meow".to_owned(), &meow, Default::default()).unwrap();
+    assert_eq!(timed, untimed);
+
+    let total = timing.prefab_scan + timing.prefab_parse + timing.block_scan + timing.block_parse;
+    assert!(total > std::time::Duration::ZERO);
+}
+
+#[test]
+fn test_duplicate_vars() {
+    let map = std::fs::read_to_string("./tests/maps/duplicate-vars.dmm").unwrap();
+    let (_meta, (prefabs, _blocks)) = parse_map_multithreaded("Dupes".to_owned(), &map).unwrap();
+    let vars = prefabs["a"][0].1.as_ref().unwrap();
+
+    assert_eq!(vars.len(), 3);
+    assert_eq!(vars[2], ("name", Literal::String("last")));
+    assert_eq!(duplicate_vars(vars), vec!["name"]);
+}
+
+#[test]
+fn test_footer() {
+    let map = std::fs::read_to_string("./tests/maps/footer.dmm").unwrap();
+    let (meta, (prefabs, blocks)) = parse_map_multithreaded("Footer".to_owned(), &map).unwrap();
+
+    assert_eq!(
+        meta.trailer.as_deref(),
+        Some("// Edited with the map tool, do not remove\n// key_len = 1\n")
+    );
+    assert_eq!(
+        write_map_with_trailer(&prefabs, &blocks, meta.trailer.as_deref()),
+        map
+    );
+}
@@ -2,6 +2,7 @@ use dmm_lite::{
     block::{get_block_locations, parse_block},
     parse_map_multithreaded,
     prefabs::{detect_tgm, get_prefab_locations, parse_prefab_line, Literal},
+    writer::{write_map, write_map_with_info},
 };
 use winnow::{Located, Parser as _};
 
@@ -214,3 +215,32 @@ fn full_parse() {
         ])
     );
 }
+
+#[test]
+fn test_cropped_fixture() {
+    // Medium benchmark fixture, cut out of the middle of MetaStation.dmm
+    let metastation = std::fs::read_to_string("./tests/maps/MetaStation-64x64.dmm").unwrap();
+    let (_info, (prefabs, blocks)) =
+        parse_map_multithreaded("MetaStation-64x64".to_owned(), &metastation).unwrap();
+
+    assert_eq!(prefabs.len(), 2493);
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0].1.len(), 64);
+    assert!(blocks[0].1.iter().all(|line| line.len() == 64 * 3));
+}
+
+#[test]
+fn test_write_round_trip() {
+    let map_tgm = std::fs::read_to_string("./tests/maps/MetaStation-tgm.dmm").unwrap();
+    let (meta, (prefabs, blocks)) = parse_map_multithreaded("Meta".to_owned(), &map_tgm).unwrap();
+    // Other than two numbers in an older BYOND's format, which are written the current way
+    let expected = map_tgm
+        .replace("capacity = 1.8e+008", "capacity = 1.8e+08")
+        .replace("charge = 2e+005", "charge = 200000");
+    assert_eq!(write_map_with_info(&meta, &prefabs, &blocks), expected);
+
+    let map = std::fs::read_to_string("./tests/maps/MetaStation.dmm").unwrap();
+    let (meta, (prefabs, blocks)) = parse_map_multithreaded("Meta".to_owned(), &map).unwrap();
+    assert_eq!(write_map_with_info(&meta, &prefabs, &blocks), map);
+    assert_eq!(write_map(&prefabs, &blocks), map);
+}
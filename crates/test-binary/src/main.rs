@@ -1,12 +1,15 @@
 use std::path::PathBuf;
 
 use clap::Parser;
-use dmm_lite::parse_map_multithreaded;
+use dmm_lite::{parse_map_multithreaded, stats::distinct_paths};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     files: Vec<PathBuf>,
+    /// Print every path each map references
+    #[arg(long)]
+    paths: bool,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -36,6 +39,11 @@ fn main() -> anyhow::Result<()> {
                     prefabs.len(),
                     blocks.len()
                 );
+                if args.paths {
+                    for path in distinct_paths(&prefabs) {
+                        println!("{path}");
+                    }
+                }
             }
             Err(e) => {
                 eprintln!("\x1b[31mFAILED Parsing {file:#?}\x1b[0m");